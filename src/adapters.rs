@@ -1,60 +1,1455 @@
 use crate::flash;
+use crate::ondisk::PspSoftFuseChain32MiBSpiDecoding;
+use core::cell::{Cell, RefCell};
 use flash::ErasableLocation;
+use flash::ErasableRange;
 use flash::FlashAlign;
 use flash::FlashRead;
 use flash::FlashWrite;
-use flash::Result;
+use flash::Location;
+use flash::{Error, IoError, Result};
 
-const UPPER_HALF_OFFSET: u32 = 0x100_0000; // 16 MiB
-const MODULUS: u32 = 0x200_0000; // 32 MiB
+const HALF_32MIB: u32 = 0x100_0000; // 16 MiB
+const SIZE_32MIB: u32 = 0x200_0000; // 32 MiB
 
-/// This is a flash adapter that allows you to simulate what AMD does when it's using the upper half of a 32 MiB flash chip.
-/// Especially, it is the case that if locations are big enough (i.e. bit 24 set), then they refer to the lower half again.
-pub struct Upper16MiBFlashAdapter<'a> {
-    underlying_reader: &'a dyn FlashRead,
-    underlying_writer: &'a dyn FlashWrite,
+/// This is a flash adapter that makes the window of SIZE Bytes starting at
+/// BASE of the flash UNDERLYING available as if it started at location 0.
+/// Underlying locations wrap around at MODULUS (this is what AMD does when
+/// it's using the upper half of a 32 MiB flash chip: if locations are big
+/// enough (i.e. bit 24 set), then they refer to the lower half again).
+/// Accesses outside of the window fail with Error::Size.
+pub struct WindowFlashAdapter<'a, T> {
+    underlying: &'a T,
+    base: Location,
+    size: u32,
+    modulus: u32,
 }
 
-impl FlashRead for Upper16MiBFlashAdapter<'_> {
-    fn read_exact(&self, offset: u32, buf: &mut [u8]) -> Result<()> {
-        let offset = (offset + UPPER_HALF_OFFSET) % MODULUS;
-        self.underlying_reader.read_exact(offset, buf)
+impl<'a, T: FlashAlign> WindowFlashAdapter<'a, T> {
+    /// BASE, SIZE and MODULUS all need to be multiples of the erasable block
+    /// size of UNDERLYING, and BASE < MODULUS, SIZE <= MODULUS.
+    pub fn new(
+        underlying: &'a T,
+        base: Location,
+        size: u32,
+        modulus: u32,
+    ) -> Result<Self> {
+        underlying.erasable_location(base)?;
+        underlying.erasable_location(size)?;
+        underlying.erasable_location(modulus)?;
+        if base >= modulus || size > modulus {
+            return Err(Error::Size);
+        }
+        Ok(Self { underlying, base, size, modulus })
+    }
+
+    /// Makes the half of a 32 MiB flash chip that DECODING maps to MMIO
+    /// 0xff00_0000 available at location 0.  Locations from 16 MiB on
+    /// refer to the other half (for UpperHalf, wrapping around to the lower
+    /// half).
+    pub fn for_32mib_spi_decoding(
+        underlying: &'a T,
+        decoding: PspSoftFuseChain32MiBSpiDecoding,
+    ) -> Result<Self> {
+        let base = match decoding {
+            PspSoftFuseChain32MiBSpiDecoding::LowerHalf => 0,
+            PspSoftFuseChain32MiBSpiDecoding::UpperHalf => HALF_32MIB,
+        };
+        Self::new(underlying, base, SIZE_32MIB, SIZE_32MIB)
+    }
+
+    /// Returns the size of the window, in Byte.
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// Checks that SIZE Bytes starting at LOCATION are inside the window
+    /// and returns the underlying location of LOCATION, and how many Bytes
+    /// can be accessed there before wrapping around.
+    fn translate(
+        &self,
+        location: Location,
+        size: usize,
+    ) -> Result<(Location, usize)> {
+        let end = u64::from(location) + size as u64;
+        if end > u64::from(self.size) {
+            return Err(Error::Size);
+        }
+        let underlying_location = ((u64::from(self.base) + u64::from(location))
+            % u64::from(self.modulus))
+            as Location;
+        let contiguous = (self.modulus - underlying_location) as usize;
+        Ok((underlying_location, contiguous.min(size)))
     }
 }
 
-impl FlashAlign for Upper16MiBFlashAdapter<'_> {
+impl<T: FlashRead + FlashAlign> FlashRead for WindowFlashAdapter<'_, T> {
+    fn read_exact(&self, location: Location, buf: &mut [u8]) -> Result<()> {
+        let (underlying_location, contiguous) =
+            self.translate(location, buf.len())?;
+        let (head, tail) = buf.split_at_mut(contiguous);
+        self.underlying.read_exact(underlying_location, head)?;
+        if !tail.is_empty() {
+            // Wrapped around.
+            self.underlying.read_exact(0, tail)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: FlashAlign> FlashAlign for WindowFlashAdapter<'_, T> {
     fn erasable_block_size(&self) -> usize {
-        self.underlying_writer.erasable_block_size()
+        self.underlying.erasable_block_size()
     }
 }
 
-impl FlashWrite for Upper16MiBFlashAdapter<'_> {
-    fn erase_block(
-        &self,
-        offset: ErasableLocation,
-    ) -> core::result::Result<(), flash::Error> {
-        let offset = self.location(offset)?;
-        let offset = (offset + UPPER_HALF_OFFSET) % MODULUS;
-        self.underlying_writer.erase_block(self.erasable_location(offset)?)
+impl<T: FlashWrite> FlashWrite for WindowFlashAdapter<'_, T> {
+    fn erase_block(&self, location: ErasableLocation) -> Result<()> {
+        let location = self.location(location)?;
+        // Since BASE and MODULUS are aligned, blocks never wrap around.
+        let (underlying_location, _) =
+            self.translate(location, self.erasable_block_size())?;
+        self.underlying.erase_block(
+            self.underlying.erasable_location(underlying_location)?,
+        )
     }
     fn erase_and_write_block(
         &self,
-        offset: ErasableLocation,
+        location: ErasableLocation,
         buf: &[u8],
-    ) -> core::result::Result<(), flash::Error> {
-        let offset = self.location(offset)?;
-        let offset = (offset + UPPER_HALF_OFFSET) % MODULUS;
-        self.underlying_writer
-            .erase_and_write_block(self.erasable_location(offset)?, buf)
+    ) -> Result<()> {
+        let location = self.location(location)?;
+        let (underlying_location, _) =
+            self.translate(location, self.erasable_block_size())?;
+        self.underlying.erase_and_write_block(
+            self.underlying.erasable_location(underlying_location)?,
+            buf,
+        )
+    }
+    fn erase_sizes(&self) -> &[usize] {
+        self.underlying.erase_sizes()
+    }
+    fn erase(&self, location: ErasableLocation, size: usize) -> Result<()> {
+        let erasable_block_size = self.erasable_block_size();
+        if !size.is_multiple_of(erasable_block_size) {
+            return Err(Error::Size);
+        }
+        let (underlying_location, contiguous) =
+            self.translate(self.location(location)?, size)?;
+        if contiguous == size
+            && (underlying_location as usize).is_multiple_of(size)
+        {
+            return self.underlying.erase(
+                self.underlying.erasable_location(underlying_location)?,
+                size,
+            );
+        }
+        // On UNDERLYING, the range is not aligned to SIZE (or it wraps
+        // around), so erase it block by block.
+        let mut location = location;
+        for _ in 0..size / erasable_block_size {
+            self.erase_block(location)?;
+            location = location.advance(erasable_block_size)?;
+        }
+        Ok(())
+    }
+    fn program(&self, location: Location, buffer: &[u8]) -> Result<()> {
+        let (underlying_location, contiguous) =
+            self.translate(location, buffer.len())?;
+        let (head, tail) = buffer.split_at(contiguous);
+        self.underlying.program(underlying_location, head)?;
+        if !tail.is_empty() {
+            // Wrapped around.
+            self.underlying.program(0, tail)?;
+        }
+        Ok(())
     }
 }
 
-impl<'a> Upper16MiBFlashAdapter<'a> {
-    #[allow(dead_code)]
+#[derive(Clone, Copy, Default)]
+struct CacheSlot {
+    location: Option<Location>,
+    dirty: bool,
+    last_used: u64,
+}
+
+struct CacheState<'a, const N: usize> {
+    slots: [CacheSlot; N],
+    buffer: &'a mut [u8], // N blocks of erasable_block_size
+    clock: u64,
+}
+
+impl<const N: usize> CacheState<'_, N> {
+    fn lookup(&self, location: Location) -> Option<usize> {
+        self.slots.iter().position(|slot| slot.location == Some(location))
+    }
+    fn block(&self, index: usize, erasable_block_size: usize) -> &[u8] {
+        let beginning = index * erasable_block_size;
+        &self.buffer[beginning..beginning + erasable_block_size]
+    }
+    fn block_mut(
+        &mut self,
+        index: usize,
+        erasable_block_size: usize,
+    ) -> &mut [u8] {
+        let beginning = index * erasable_block_size;
+        &mut self.buffer[beginning..beginning + erasable_block_size]
+    }
+    fn touch(&mut self, index: usize) {
+        self.clock += 1;
+        self.slots[index].last_used = self.clock;
+    }
+}
+
+/// This is a write-back cache of N erasable blocks in front of the flash
+/// UNDERLYING.
+/// Erasing or writing a block only changes the cache; the block is written
+/// to UNDERLYING (with one erase_and_write_block) once it is evicted (least
+/// recently used first) or once flush is called.
+/// Reads are served from the cache where possible and are passed through
+/// to UNDERLYING otherwise (without populating the cache).
+/// Erases of one of UNDERLYING's erase_sizes() go directly to UNDERLYING
+/// (and drop the blocks they cover from the cache), and so does
+/// programming blocks that are not cached.
+///
+/// Note: Dirty blocks are NOT written back on drop.  Call flush before
+/// dropping, otherwise the changes are lost.
+pub struct CachedFlash<'a, T: FlashWrite, const N: usize> {
+    underlying: &'a T,
+    state: RefCell<CacheState<'a, N>>,
+}
+
+impl<'a, T: FlashWrite, const N: usize> CachedFlash<'a, T, N> {
+    /// BUFFER is used to hold the cached blocks and needs to have room for
+    /// at least N erasable blocks of UNDERLYING.
+    pub fn new(underlying: &'a T, buffer: &'a mut [u8]) -> Result<Self> {
+        let size = N
+            .checked_mul(underlying.erasable_block_size())
+            .ok_or(Error::Size)?;
+        if N == 0 || buffer.len() < size {
+            return Err(Error::Size);
+        }
+        Ok(Self {
+            underlying,
+            state: RefCell::new(CacheState {
+                slots: [CacheSlot::default(); N],
+                buffer: &mut buffer[..size],
+                clock: 0,
+            }),
+        })
+    }
+
+    /// Returns whether the cache currently has changes that are not on
+    /// the underlying flash yet.
+    pub fn is_dirty(&self) -> bool {
+        self.state.borrow().slots.iter().any(|slot| slot.dirty)
+    }
+
+    /// Writes all dirty blocks to the underlying flash.
+    pub fn flush(&self) -> Result<()> {
+        let mut state = self.state.borrow_mut();
+        for index in 0..N {
+            self.write_back(&mut state, index)?;
+        }
+        Ok(())
+    }
+
+    fn write_back(
+        &self,
+        state: &mut CacheState<'a, N>,
+        index: usize,
+    ) -> Result<()> {
+        let slot = state.slots[index];
+        if let Some(location) = slot.location
+            && slot.dirty
+        {
+            let erasable_block_size = self.erasable_block_size();
+            self.underlying.erase_and_write_block(
+                self.underlying.erasable_location(location)?,
+                state.block(index, erasable_block_size),
+            )?;
+            state.slots[index].dirty = false;
+        }
+        Ok(())
+    }
+
+    /// Finds the slot for LOCATION, evicting the least recently used block
+    /// if necessary.  The slot contents are unspecified unless the block
+    /// was already cached.
+    fn slot(
+        &self,
+        state: &mut CacheState<'a, N>,
+        location: Location,
+    ) -> Result<usize> {
+        let index = match state.lookup(location) {
+            Some(index) => index,
+            None => {
+                let index = state
+                    .slots
+                    .iter()
+                    .position(|slot| slot.location.is_none())
+                    .unwrap_or_else(|| {
+                        (0..N)
+                            .min_by_key(|&index| state.slots[index].last_used)
+                            .unwrap()
+                    });
+                self.write_back(state, index)?;
+                state.slots[index] = CacheSlot {
+                    location: Some(location),
+                    ..Default::default()
+                };
+                index
+            }
+        };
+        state.touch(index);
+        Ok(index)
+    }
+}
+
+impl<T: FlashWrite, const N: usize> FlashRead for CachedFlash<'_, T, N> {
+    fn read_exact(&self, beginning: Location, buffer: &mut [u8]) -> Result<()> {
+        let state = self.state.borrow();
+        let erasable_block_size = self.erasable_block_size();
+        let end = (beginning as usize).saturating_add(buffer.len());
+        let cached = state.slots.iter().any(|slot| match slot.location {
+            Some(location) => {
+                (location as usize) < end
+                    && (beginning as usize)
+                        < location as usize + erasable_block_size
+            }
+            None => false,
+        });
+        if !cached {
+            return self.underlying.read_exact(beginning, buffer);
+        }
+        let mut offset = 0usize;
+        while offset < buffer.len() {
+            let location = beginning
+                .checked_add(offset.try_into().map_err(|_| Error::Size)?)
+                .ok_or(Error::Size)?;
+            let block_location = location & !self.erasable_block_mask();
+            let intra_block_offset = (location - block_location) as usize;
+            let size = (erasable_block_size - intra_block_offset)
+                .min(buffer.len() - offset);
+            let chunk = &mut buffer[offset..offset + size];
+            match state.lookup(block_location) {
+                Some(index) => chunk.copy_from_slice(
+                    &state.block(index, erasable_block_size)
+                        [intra_block_offset..intra_block_offset + size],
+                ),
+                None => self.underlying.read_exact(location, chunk)?,
+            }
+            offset += size;
+        }
+        Ok(())
+    }
+}
+
+impl<T: FlashWrite, const N: usize> FlashAlign for CachedFlash<'_, T, N> {
+    fn erasable_block_size(&self) -> usize {
+        self.underlying.erasable_block_size()
+    }
+}
+
+impl<T: FlashWrite, const N: usize> FlashWrite for CachedFlash<'_, T, N> {
+    fn erase_block(&self, location: ErasableLocation) -> Result<()> {
+        let location = self.location(location)?;
+        let erasable_block_size = self.erasable_block_size();
+        let mut state = self.state.borrow_mut();
+        let index = self.slot(&mut state, location)?;
+        state.block_mut(index, erasable_block_size).fill(0xff);
+        state.slots[index].dirty = true;
+        Ok(())
+    }
+    fn erase_and_write_block(
+        &self,
+        location: ErasableLocation,
+        buffer: &[u8],
+    ) -> Result<()> {
+        let location = self.location(location)?;
+        let erasable_block_size = self.erasable_block_size();
+        if buffer.len() > erasable_block_size {
+            return Err(Error::Size);
+        }
+        let mut state = self.state.borrow_mut();
+        let index = self.slot(&mut state, location)?;
+        let block = state.block_mut(index, erasable_block_size);
+        block[..buffer.len()].copy_from_slice(buffer);
+        block[buffer.len()..].fill(0xff);
+        state.slots[index].dirty = true;
+        Ok(())
+    }
+    fn erase_sizes(&self) -> &[usize] {
+        self.underlying.erase_sizes()
+    }
+    fn erase(&self, location: ErasableLocation, size: usize) -> Result<()> {
+        let beginning = self.location(location)?;
+        self.underlying.erase(location, size)?;
+        let end = beginning as usize + size;
+        let mut state = self.state.borrow_mut();
+        for slot in state.slots.iter_mut() {
+            if let Some(location) = slot.location
+                && location >= beginning
+                && (location as usize) < end
+            {
+                *slot = CacheSlot::default();
+            }
+        }
+        Ok(())
+    }
+    fn program(&self, location: Location, buffer: &[u8]) -> Result<()> {
+        let erasable_block_size = self.erasable_block_size();
+        let mut state = self.state.borrow_mut();
+        let mut offset = 0usize;
+        while offset < buffer.len() {
+            let location = location
+                .checked_add(offset.try_into().map_err(|_| Error::Size)?)
+                .ok_or(Error::Size)?;
+            let block_location = location & !self.erasable_block_mask();
+            let intra_block_offset = (location - block_location) as usize;
+            let size = (erasable_block_size - intra_block_offset)
+                .min(buffer.len() - offset);
+            let chunk = &buffer[offset..offset + size];
+            match state.lookup(block_location) {
+                Some(index) => {
+                    state.block_mut(index, erasable_block_size)
+                        [intra_block_offset..intra_block_offset + size]
+                        .copy_from_slice(chunk);
+                    state.slots[index].dirty = true;
+                    state.touch(index);
+                }
+                None => self.underlying.program(location, chunk)?,
+            }
+            offset += size;
+        }
+        Ok(())
+    }
+}
+
+/// What happens to the erase or program operation that a
+/// FaultInjectingFlash fails.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// The operation fails without changing anything.
+    Fail,
+    /// The block is erased, but nothing is written.
+    Erase,
+    /// The block is erased, but only the first SIZE Byte are written.
+    Truncate(usize),
+}
+
+/// This is a flash adapter that simulates a power loss: the erase or
+/// program operation with the configured (0-based) index fails as described
+/// by the configured Fault, and all the erase and program operations after it
+/// fail without changing anything.
+/// Reads are always passed through.
+pub struct FaultInjectingFlash<'a, T: FlashWrite> {
+    underlying: &'a T,
+    fault: Option<(usize, Fault)>,
+    operation_count: Cell<usize>,
+    faulted: Cell<bool>,
+}
+
+impl<'a, T: FlashWrite> FaultInjectingFlash<'a, T> {
+    /// Creates an adapter that doesn't fail anything (yet).
+    pub fn new(underlying: &'a T) -> Self {
+        Self {
+            underlying,
+            fault: None,
+            operation_count: Cell::new(0),
+            faulted: Cell::new(false),
+        }
+    }
+    /// Creates an adapter that fails the operation with index INDEX as
+    /// described by FAULT.
+    pub fn with_fault(underlying: &'a T, index: usize, fault: Fault) -> Self {
+        Self { fault: Some((index, fault)), ..Self::new(underlying) }
+    }
+    /// Returns how many erase and program operations have been attempted.
+    pub fn operation_count(&self) -> usize {
+        self.operation_count.get()
+    }
+    /// Returns whether the fault has happened.
+    pub fn faulted(&self) -> bool {
+        self.faulted.get()
+    }
+    /// Determines whether the next operation should fail and, if so, how.
+    fn next_fault(&self) -> Option<Fault> {
+        let index = self.operation_count.get();
+        self.operation_count.set(index + 1);
+        if self.faulted.get() {
+            return Some(Fault::Fail);
+        }
+        match self.fault {
+            Some((fault_index, fault)) if fault_index == index => {
+                self.faulted.set(true);
+                Some(fault)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl<T: FlashWrite> FlashRead for FaultInjectingFlash<'_, T> {
+    fn read_exact(&self, location: Location, buffer: &mut [u8]) -> Result<()> {
+        self.underlying.read_exact(location, buffer)
+    }
+}
+
+impl<T: FlashWrite> FlashAlign for FaultInjectingFlash<'_, T> {
+    fn erasable_block_size(&self) -> usize {
+        self.underlying.erasable_block_size()
+    }
+}
+
+impl<T: FlashWrite> FlashWrite for FaultInjectingFlash<'_, T> {
+    fn erase_block(&self, location: ErasableLocation) -> Result<()> {
+        let error = Error::Io(IoError::Erase {
+            start: self.location(location)?,
+            size: self.erasable_block_size(),
+        });
+        match self.next_fault() {
+            None => self.underlying.erase_block(location),
+            Some(Fault::Fail) => Err(error),
+            Some(Fault::Erase | Fault::Truncate(_)) => {
+                self.underlying.erase_block(location)?;
+                Err(error)
+            }
+        }
+    }
+    fn erase_and_write_block(
+        &self,
+        location: ErasableLocation,
+        buffer: &[u8],
+    ) -> Result<()> {
+        let error = Error::Io(IoError::Write {
+            start: self.location(location)?,
+            size: buffer.len(),
+        });
+        match self.next_fault() {
+            None => self.underlying.erase_and_write_block(location, buffer),
+            Some(Fault::Fail) => Err(error),
+            Some(Fault::Erase) => {
+                self.underlying.erase_block(location)?;
+                Err(error)
+            }
+            Some(Fault::Truncate(size)) => {
+                let size = size.min(buffer.len());
+                self.underlying
+                    .erase_and_write_block(location, &buffer[..size])?;
+                Err(error)
+            }
+        }
+    }
+    fn erase_sizes(&self) -> &[usize] {
+        self.underlying.erase_sizes()
+    }
+    fn erase(&self, location: ErasableLocation, size: usize) -> Result<()> {
+        let error =
+            Error::Io(IoError::Erase { start: self.location(location)?, size });
+        match self.next_fault() {
+            None => self.underlying.erase(location, size),
+            Some(Fault::Fail) => Err(error),
+            Some(Fault::Erase | Fault::Truncate(_)) => {
+                self.underlying.erase(location, size)?;
+                Err(error)
+            }
+        }
+    }
+    /// For programming, Fault::Erase means that nothing is programmed.
+    fn program(&self, location: Location, buffer: &[u8]) -> Result<()> {
+        let error =
+            Error::Io(IoError::Write { start: location, size: buffer.len() });
+        match self.next_fault() {
+            None => self.underlying.program(location, buffer),
+            Some(Fault::Fail | Fault::Erase) => Err(error),
+            Some(Fault::Truncate(size)) => {
+                let size = size.min(buffer.len());
+                self.underlying.program(location, &buffer[..size])?;
+                Err(error)
+            }
+        }
+    }
+}
+
+/// This is a flash adapter that refuses to erase or write anything inside
+/// PROTECTED_RANGES of the flash UNDERLYING (with Error::Protected).
+/// Reading is always allowed.
+pub struct ProtectedRegionsFlash<'a, T: FlashWrite> {
+    underlying: &'a T,
+    protected_ranges: &'a [ErasableRange],
+}
+
+impl<'a, T: FlashWrite> ProtectedRegionsFlash<'a, T> {
     pub fn new(
-        underlying_reader: &'a dyn FlashRead,
-        underlying_writer: &'a dyn FlashWrite,
+        underlying: &'a T,
+        protected_ranges: &'a [ErasableRange],
     ) -> Self {
-        Self { underlying_reader, underlying_writer }
+        Self { underlying, protected_ranges }
+    }
+
+    pub fn protected_ranges(&self) -> &'a [ErasableRange] {
+        self.protected_ranges
+    }
+
+    fn check(&self, start: Location, size: usize) -> Result<()> {
+        if self.protected_ranges.iter().any(|range| range.overlaps(start, size))
+        {
+            Err(Error::Protected { start, size })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<T: FlashWrite> FlashRead for ProtectedRegionsFlash<'_, T> {
+    fn read_exact(&self, location: Location, buffer: &mut [u8]) -> Result<()> {
+        self.underlying.read_exact(location, buffer)
+    }
+}
+
+impl<T: FlashWrite> FlashAlign for ProtectedRegionsFlash<'_, T> {
+    fn erasable_block_size(&self) -> usize {
+        self.underlying.erasable_block_size()
+    }
+}
+
+impl<T: FlashWrite> FlashWrite for ProtectedRegionsFlash<'_, T> {
+    fn erase_block(&self, location: ErasableLocation) -> Result<()> {
+        self.check(self.location(location)?, self.erasable_block_size())?;
+        self.underlying.erase_block(location)
+    }
+    fn erase_and_write_block(
+        &self,
+        location: ErasableLocation,
+        buffer: &[u8],
+    ) -> Result<()> {
+        self.check(self.location(location)?, self.erasable_block_size())?;
+        self.underlying.erase_and_write_block(location, buffer)
+    }
+    fn erase_sizes(&self) -> &[usize] {
+        self.underlying.erase_sizes()
+    }
+    fn erase(&self, location: ErasableLocation, size: usize) -> Result<()> {
+        self.check(self.location(location)?, size)?;
+        self.underlying.erase(location, size)
+    }
+    fn program(&self, location: Location, buffer: &[u8]) -> Result<()> {
+        self.check(location, buffer.len())?;
+        self.underlying.program(location, buffer)
+    }
+}
+
+/// This is a flash adapter that reads back everything that is erased or
+/// written on the flash UNDERLYING and compares it to what was expected.
+/// On mismatch, the operation fails with IoError::Verify.
+pub struct VerifyingFlash<'a, T: FlashWrite> {
+    underlying: &'a T,
+}
+
+impl<'a, T: FlashWrite> VerifyingFlash<'a, T> {
+    pub fn new(underlying: &'a T) -> Self {
+        Self { underlying }
+    }
+
+    /// Checks that the SIZE Bytes at LOCATION on UNDERLYING are EXPECTED,
+    /// followed by erased Bytes.
+    fn verify(
+        &self,
+        location: Location,
+        size: usize,
+        expected: &[u8],
+    ) -> Result<()> {
+        let mut buf = [0u8; 64];
+        let mut offset = 0usize;
+        while offset < size {
+            let chunk_size = buf.len().min(size - offset);
+            let chunk = &mut buf[..chunk_size];
+            let chunk_location = location
+                .checked_add(offset.try_into().map_err(|_| Error::Size)?)
+                .ok_or(Error::Size)?;
+            self.underlying.read_exact(chunk_location, chunk)?;
+            let mismatch = chunk.iter().enumerate().any(|(i, &x)| {
+                x != expected.get(offset + i).copied().unwrap_or(0xff)
+            });
+            if mismatch {
+                return Err(Error::Io(IoError::Verify {
+                    start: location,
+                    size,
+                }));
+            }
+            offset += chunk_size;
+        }
+        Ok(())
+    }
+}
+
+impl<T: FlashWrite> FlashRead for VerifyingFlash<'_, T> {
+    fn read_exact(&self, location: Location, buffer: &mut [u8]) -> Result<()> {
+        self.underlying.read_exact(location, buffer)
+    }
+}
+
+impl<T: FlashWrite> FlashAlign for VerifyingFlash<'_, T> {
+    fn erasable_block_size(&self) -> usize {
+        self.underlying.erasable_block_size()
+    }
+}
+
+impl<T: FlashWrite> FlashWrite for VerifyingFlash<'_, T> {
+    fn erase_block(&self, location: ErasableLocation) -> Result<()> {
+        self.underlying.erase_block(location)?;
+        self.verify(self.location(location)?, self.erasable_block_size(), &[])
+    }
+    fn erase_and_write_block(
+        &self,
+        location: ErasableLocation,
+        buffer: &[u8],
+    ) -> Result<()> {
+        self.underlying.erase_and_write_block(location, buffer)?;
+        self.verify(
+            self.location(location)?,
+            self.erasable_block_size(),
+            buffer,
+        )
+    }
+    fn erase_sizes(&self) -> &[usize] {
+        self.underlying.erase_sizes()
+    }
+    fn erase(&self, location: ErasableLocation, size: usize) -> Result<()> {
+        self.underlying.erase(location, size)?;
+        self.verify(self.location(location)?, size, &[])
+    }
+    fn program(&self, location: Location, buffer: &[u8]) -> Result<()> {
+        self.underlying.program(location, buffer)?;
+        self.verify(location, buffer.len(), buffer)
+    }
+}
+
+/// An operation that was done on a flash by RecordingFlash.
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub enum RecordedOperationKind {
+    /// erase_block or erase
+    Erase,
+    /// erase_and_write_block
+    EraseAndWrite,
+    /// program
+    Program,
+}
+
+/// An entry of the log of RecordingFlash.
+#[cfg(feature = "std")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct RecordedOperation {
+    pub kind: RecordedOperationKind,
+    pub location: Location,
+    /// Number of Bytes affected (for EraseAndWrite, that's the entire
+    /// erasable block).
+    pub size: usize,
+    /// SHA-256 of DATA.
+    pub hash: [u8; 32],
+    /// Data written (empty for Erase).
+    pub data: Vec<u8>,
+}
+
+#[cfg(feature = "std")]
+impl RecordedOperation {
+    fn new(
+        kind: RecordedOperationKind,
+        location: Location,
+        size: usize,
+        data: &[u8],
+    ) -> Self {
+        use sha2::Digest;
+        Self {
+            kind,
+            location,
+            size,
+            hash: sha2::Sha256::digest(data).into(),
+            data: data.to_vec(),
+        }
+    }
+    /// Returns whether this leaves the range it affects in a state that
+    /// doesn't depend on what was there before.
+    fn resets(&self) -> bool {
+        self.kind != RecordedOperationKind::Program
+    }
+    /// Returns the range of locations this affects.
+    fn range(&self) -> core::ops::Range<u64> {
+        let beginning = u64::from(self.location);
+        beginning..beginning + self.size as u64
+    }
+}
+
+#[cfg(feature = "std")]
+const RECORDED_OPERATIONS_MAGIC: [u8; 4] = *b"EFSL";
+#[cfg(feature = "std")]
+const RECORDED_OPERATIONS_VERSION: u8 = 1;
+
+/// Encodes OPERATIONS so that they can be stored or shipped (for example
+/// to a device that then replays them).
+/// The encoding is: "EFSL", version (1 Byte; currently 1), and then for
+/// each operation: kind (1 Byte; 0: Erase, 1: EraseAndWrite, 2: Program),
+/// location (u32 LE), size (u32 LE), hash (32 Byte), length of data
+/// (u32 LE) and data.
+#[cfg(feature = "std")]
+pub fn encode_operations(operations: &[RecordedOperation]) -> Result<Vec<u8>> {
+    let mut result = RECORDED_OPERATIONS_MAGIC.to_vec();
+    result.push(RECORDED_OPERATIONS_VERSION);
+    for operation in operations {
+        result.push(match operation.kind {
+            RecordedOperationKind::Erase => 0,
+            RecordedOperationKind::EraseAndWrite => 1,
+            RecordedOperationKind::Program => 2,
+        });
+        let size = u32::try_from(operation.size).map_err(|_| Error::Size)?;
+        let data_size =
+            u32::try_from(operation.data.len()).map_err(|_| Error::Size)?;
+        result.extend_from_slice(&operation.location.to_le_bytes());
+        result.extend_from_slice(&size.to_le_bytes());
+        result.extend_from_slice(&operation.hash);
+        result.extend_from_slice(&data_size.to_le_bytes());
+        result.extend_from_slice(&operation.data);
+    }
+    Ok(result)
+}
+
+/// Decodes operations encoded by encode_operations.
+/// Fails with IoError::Malformed if BUF isn't such an encoding.
+/// Note: This doesn't check the hashes; replay_operations does.
+#[cfg(feature = "std")]
+pub fn decode_operations(buf: &[u8]) -> Result<Vec<RecordedOperation>> {
+    let mut offset = 0usize;
+    let mut take = |size: usize| -> Result<&[u8]> {
+        let result = buf
+            .get(offset..offset.saturating_add(size))
+            .ok_or(Error::Io(IoError::Malformed { offset }))?;
+        offset += size;
+        Ok(result)
+    };
+    let header = take(RECORDED_OPERATIONS_MAGIC.len() + 1)?;
+    if header[..RECORDED_OPERATIONS_MAGIC.len()] != RECORDED_OPERATIONS_MAGIC
+        || header[RECORDED_OPERATIONS_MAGIC.len()]
+            != RECORDED_OPERATIONS_VERSION
+    {
+        return Err(Error::Io(IoError::Malformed { offset: 0 }));
+    }
+    let u32_from = |bytes: &[u8]| u32::from_le_bytes(bytes.try_into().unwrap());
+    let mut result = Vec::new();
+    while let Ok(kind) = take(1) {
+        let kind = match kind[0] {
+            0 => RecordedOperationKind::Erase,
+            1 => RecordedOperationKind::EraseAndWrite,
+            2 => RecordedOperationKind::Program,
+            _ => {
+                return Err(Error::Io(IoError::Malformed {
+                    offset: offset - 1,
+                }));
+            }
+        };
+        let location = u32_from(take(4)?);
+        let size = u32_from(take(4)?) as usize;
+        let hash = take(32)?.try_into().unwrap();
+        let data_size = u32_from(take(4)?) as usize;
+        let data = take(data_size)?.to_vec();
+        result.push(RecordedOperation { kind, location, size, hash, data });
+    }
+    Ok(result)
+}
+
+/// Returns OPERATIONS without the ones that don't make a difference when
+/// replaying: the ones whose range is next affected by an operation that
+/// resets all of it anyway (for example an erase followed by an
+/// erase_and_write_block of the same block).
+#[cfg(feature = "std")]
+pub fn minimize_operations(
+    operations: &[RecordedOperation],
+) -> Vec<RecordedOperation> {
+    operations
+        .iter()
+        .enumerate()
+        .filter(|(i, operation)| {
+            let range = operation.range();
+            let next = operations[i + 1..].iter().find(|other| {
+                let other_range = other.range();
+                other_range.start < range.end && range.start < other_range.end
+            });
+            !matches!(next, Some(next)
+                if next.resets()
+                    && next.range().start <= range.start
+                    && range.end <= next.range().end)
+        })
+        .map(|(_, operation)| operation.clone())
+        .collect()
+}
+
+/// This is a flash adapter that records every (successful) erase and write
+/// on the flash UNDERLYING, so that it can be audited and replayed onto
+/// another flash later (see replay_operations).
+#[cfg(feature = "std")]
+pub struct RecordingFlash<'a, T: FlashWrite> {
+    underlying: &'a T,
+    operations: RefCell<Vec<RecordedOperation>>,
+}
+
+#[cfg(feature = "std")]
+impl<'a, T: FlashWrite> RecordingFlash<'a, T> {
+    pub fn new(underlying: &'a T) -> Self {
+        Self { underlying, operations: RefCell::new(Vec::new()) }
+    }
+    /// Returns the operations so far, in order.
+    pub fn operations(&self) -> Vec<RecordedOperation> {
+        self.operations.borrow().clone()
+    }
+    pub fn into_operations(self) -> Vec<RecordedOperation> {
+        self.operations.into_inner()
+    }
+    fn record(
+        &self,
+        kind: RecordedOperationKind,
+        location: Location,
+        size: usize,
+        data: &[u8],
+    ) {
+        self.operations
+            .borrow_mut()
+            .push(RecordedOperation::new(kind, location, size, data));
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: FlashWrite> FlashRead for RecordingFlash<'_, T> {
+    fn read_exact(&self, location: Location, buffer: &mut [u8]) -> Result<()> {
+        self.underlying.read_exact(location, buffer)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: FlashWrite> FlashAlign for RecordingFlash<'_, T> {
+    fn erasable_block_size(&self) -> usize {
+        self.underlying.erasable_block_size()
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: FlashWrite> FlashWrite for RecordingFlash<'_, T> {
+    fn erase_block(&self, location: ErasableLocation) -> Result<()> {
+        self.underlying.erase_block(location)?;
+        self.record(
+            RecordedOperationKind::Erase,
+            self.location(location)?,
+            self.erasable_block_size(),
+            &[],
+        );
+        Ok(())
+    }
+    fn erase_and_write_block(
+        &self,
+        location: ErasableLocation,
+        buffer: &[u8],
+    ) -> Result<()> {
+        self.underlying.erase_and_write_block(location, buffer)?;
+        self.record(
+            RecordedOperationKind::EraseAndWrite,
+            self.location(location)?,
+            self.erasable_block_size(),
+            buffer,
+        );
+        Ok(())
+    }
+    fn erase_sizes(&self) -> &[usize] {
+        self.underlying.erase_sizes()
+    }
+    fn erase(&self, location: ErasableLocation, size: usize) -> Result<()> {
+        self.underlying.erase(location, size)?;
+        self.record(
+            RecordedOperationKind::Erase,
+            self.location(location)?,
+            size,
+            &[],
+        );
+        Ok(())
+    }
+    fn program(&self, location: Location, buffer: &[u8]) -> Result<()> {
+        self.underlying.program(location, buffer)?;
+        self.record(
+            RecordedOperationKind::Program,
+            location,
+            buffer.len(),
+            buffer,
+        );
+        Ok(())
+    }
+}
+
+/// Replays OPERATIONS (as recorded by RecordingFlash), in order, onto
+/// TARGET.
+/// Before an operation is replayed, its data is checked against its hash.
+/// If that fails, this stops with IoError::Verify.
+#[cfg(feature = "std")]
+pub fn replay_operations(
+    operations: &[RecordedOperation],
+    target: &impl FlashWrite,
+) -> Result<()> {
+    use sha2::Digest;
+    for operation in operations {
+        let hash: [u8; 32] = sha2::Sha256::digest(&operation.data).into();
+        if hash != operation.hash {
+            return Err(Error::Io(IoError::Verify {
+                start: operation.location,
+                size: operation.size,
+            }));
+        }
+        match operation.kind {
+            RecordedOperationKind::Erase => {
+                let location = target.erasable_location(operation.location)?;
+                if operation.size == target.erasable_block_size() {
+                    target.erase_block(location)?
+                } else {
+                    target.erase(location, operation.size)?
+                }
+            }
+            RecordedOperationKind::EraseAndWrite => {
+                if operation.size != target.erasable_block_size() {
+                    return Err(Error::Size);
+                }
+                target.erase_and_write_block(
+                    target.erasable_location(operation.location)?,
+                    &operation.data,
+                )?
+            }
+            RecordedOperationKind::Program => {
+                target.program(operation.location, &operation.data)?
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    const ERASABLE_BLOCK_SIZE: usize = 16;
+
+    struct CountingFlash {
+        buf: RefCell<[u8; 8 * ERASABLE_BLOCK_SIZE]>,
+        writes: Cell<usize>,
+    }
+
+    impl CountingFlash {
+        fn new() -> Self {
+            Self {
+                buf: RefCell::new([0xff; 8 * ERASABLE_BLOCK_SIZE]),
+                writes: Cell::new(0),
+            }
+        }
+    }
+
+    impl FlashRead for CountingFlash {
+        fn read_exact(
+            &self,
+            location: Location,
+            buffer: &mut [u8],
+        ) -> Result<()> {
+            let location = location as usize;
+            buffer.copy_from_slice(
+                &self.buf.borrow()[location..location + buffer.len()],
+            );
+            Ok(())
+        }
+    }
+
+    impl FlashAlign for CountingFlash {
+        fn erasable_block_size(&self) -> usize {
+            ERASABLE_BLOCK_SIZE
+        }
+    }
+
+    impl FlashWrite for CountingFlash {
+        fn erase_block(&self, location: ErasableLocation) -> Result<()> {
+            self.erase_and_write_block(location, &[])
+        }
+        fn erase_and_write_block(
+            &self,
+            location: ErasableLocation,
+            buffer: &[u8],
+        ) -> Result<()> {
+            let location = Location::from(location) as usize;
+            let mut buf = self.buf.borrow_mut();
+            let block = &mut buf[location..location + ERASABLE_BLOCK_SIZE];
+            block[..buffer.len()].copy_from_slice(buffer);
+            block[buffer.len()..].fill(0xff);
+            self.writes.set(self.writes.get() + 1);
+            Ok(())
+        }
+    }
+
+    /// A 32 MiB flash that only remembers the last location accessed.
+    struct LastAccessFlash {
+        last: Cell<Option<Location>>,
+    }
+
+    impl FlashRead for LastAccessFlash {
+        fn read_exact(
+            &self,
+            location: Location,
+            buffer: &mut [u8],
+        ) -> Result<()> {
+            if location as usize + buffer.len() > SIZE_32MIB as usize {
+                return Err(Error::Size);
+            }
+            self.last.set(Some(location));
+            buffer.fill(0xff);
+            Ok(())
+        }
+    }
+
+    impl FlashAlign for LastAccessFlash {
+        fn erasable_block_size(&self) -> usize {
+            ERASABLE_BLOCK_SIZE
+        }
+    }
+
+    impl FlashWrite for LastAccessFlash {
+        fn erase_block(&self, location: ErasableLocation) -> Result<()> {
+            self.erase_and_write_block(location, &[])
+        }
+        fn erase_and_write_block(
+            &self,
+            location: ErasableLocation,
+            _buffer: &[u8],
+        ) -> Result<()> {
+            self.last.set(Some(self.location(location)?));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn window_flash_adapter_32mib_spi_decoding() -> Result<()> {
+        let underlying = LastAccessFlash { last: Cell::new(None) };
+        let mut buf = [0u8; 4];
+        let window = WindowFlashAdapter::for_32mib_spi_decoding(
+            &underlying,
+            PspSoftFuseChain32MiBSpiDecoding::UpperHalf,
+        )?;
+        assert_eq!(window.size(), SIZE_32MIB);
+        window.read_exact(0x10, &mut buf)?;
+        assert_eq!(underlying.last.get(), Some(0x100_0010));
+        // Bit 24 set: that's the lower half again.
+        window.read_exact(0x100_0010, &mut buf)?;
+        assert_eq!(underlying.last.get(), Some(0x10));
+        window.erase_block(window.erasable_location(0x1ff_fff0)?)?;
+        assert_eq!(underlying.last.get(), Some(0xff_fff0));
+        assert!(window.read_exact(0x1ff_fffe, &mut buf).is_err());
+
+        let window = WindowFlashAdapter::for_32mib_spi_decoding(
+            &underlying,
+            PspSoftFuseChain32MiBSpiDecoding::LowerHalf,
+        )?;
+        window.read_exact(0x10, &mut buf)?;
+        assert_eq!(underlying.last.get(), Some(0x10));
+        window.read_exact(0x100_0010, &mut buf)?;
+        assert_eq!(underlying.last.get(), Some(0x100_0010));
+        Ok(())
+    }
+
+    #[test]
+    fn window_flash_adapter_wraps_around() -> Result<()> {
+        let underlying = CountingFlash::new();
+        let window = WindowFlashAdapter::new(&underlying, 0x60, 0x40, 0x80)?;
+        window
+            .erase_and_write_block(window.erasable_location(0x10)?, &[1; 16])?;
+        window
+            .erase_and_write_block(window.erasable_location(0x20)?, &[2; 16])?;
+        assert_eq!(underlying.buf.borrow()[0x70..0x80], [1; 16]);
+        assert_eq!(underlying.buf.borrow()[0x00..0x10], [2; 16]);
+        let mut buf = [0u8; 0x20];
+        window.read_exact(0x10, &mut buf)?;
+        assert_eq!(buf[..0x10], [1; 16]);
+        assert_eq!(buf[0x10..], [2; 16]);
+        assert!(matches!(
+            window.erase_block(window.erasable_location(0x40)?),
+            Err(Error::Size)
+        ));
+        assert!(matches!(window.read_exact(0x3f, &mut buf), Err(Error::Size)));
+        assert!(matches!(
+            WindowFlashAdapter::new(&underlying, 0x8, 0x40, 0x80),
+            Err(Error::Alignment { .. })
+        ));
+        Ok(())
+    }
+
+    /// Flash with a stuck-at-0 bit at location 0x21.
+    struct StuckBitFlash(CountingFlash);
+
+    impl FlashRead for StuckBitFlash {
+        fn read_exact(
+            &self,
+            location: Location,
+            buffer: &mut [u8],
+        ) -> Result<()> {
+            self.0.read_exact(location, buffer)
+        }
+    }
+
+    impl FlashAlign for StuckBitFlash {
+        fn erasable_block_size(&self) -> usize {
+            ERASABLE_BLOCK_SIZE
+        }
+    }
+
+    impl FlashWrite for StuckBitFlash {
+        fn erase_block(&self, location: ErasableLocation) -> Result<()> {
+            self.erase_and_write_block(location, &[])
+        }
+        fn erase_and_write_block(
+            &self,
+            location: ErasableLocation,
+            buffer: &[u8],
+        ) -> Result<()> {
+            self.0.erase_and_write_block(location, buffer)?;
+            self.0.buf.borrow_mut()[0x21] &= !1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn verifying_flash_detects_mismatch() -> Result<()> {
+        let underlying = StuckBitFlash(CountingFlash::new());
+        let flash = VerifyingFlash::new(&underlying);
+        flash.erase_and_write_block(flash.erasable_location(0x10)?, &[1; 4])?;
+        flash.erase_and_write_block(flash.erasable_location(0x20)?, &[0; 4])?;
+        assert!(matches!(
+            flash
+                .erase_and_write_block(flash.erasable_location(0x20)?, &[1; 4]),
+            Err(Error::Io(IoError::Verify { start: 0x20, size: 16 }))
+        ));
+        assert!(matches!(
+            flash.erase_block(flash.erasable_location(0x20)?),
+            Err(Error::Io(IoError::Verify { start: 0x20, size: 16 }))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn protected_regions_flash_rejects_writes() -> Result<()> {
+        let underlying = CountingFlash::new();
+        let protected_ranges = [ErasableRange::new(
+            underlying.erasable_location(0x20)?,
+            underlying.erasable_location(0x40)?,
+        )];
+        let flash = ProtectedRegionsFlash::new(&underlying, &protected_ranges);
+        flash
+            .erase_and_write_block(flash.erasable_location(0x10)?, &[1; 16])?;
+        flash.erase_block(flash.erasable_location(0x40)?)?;
+        assert!(matches!(
+            flash.erase_block(flash.erasable_location(0x30)?),
+            Err(Error::Protected { start: 0x30, size: 16 })
+        ));
+        assert!(matches!(
+            flash.erase_and_write_blocks(
+                flash.erasable_location(0x10)?,
+                &[2; 32]
+            ),
+            Err(Error::Protected { start: 0x20, size: 16 })
+        ));
+        assert_eq!(underlying.buf.borrow()[0x10..0x20], [2; 16]);
+        assert_eq!(underlying.writes.get(), 3);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn recording_flash_replays() -> Result<()> {
+        let underlying = CountingFlash::new();
+        let flash = RecordingFlash::new(&underlying);
+        flash
+            .erase_and_write_blocks(flash.erasable_location(0x10)?, &[1; 20])?;
+        flash.erase_block(flash.erasable_location(0x40)?)?;
+        let mut operations = flash.into_operations();
+        assert_eq!(operations.len(), 3);
+        assert_eq!(operations[1].kind, RecordedOperationKind::EraseAndWrite);
+        assert_eq!(operations[1].location, 0x20);
+        assert_eq!(operations[1].data, [1; 4]);
+        assert_eq!(operations[2].kind, RecordedOperationKind::Erase);
+
+        let target = CountingFlash::new();
+        target.buf.borrow_mut().fill(0);
+        replay_operations(&operations, &target)?;
+        assert_eq!(target.buf.borrow()[..0x10], [0; 0x10]);
+        assert_eq!(target.buf.borrow()[0x10..0x24], [1; 20]);
+        assert_eq!(target.buf.borrow()[0x24..0x30], [0xff; 12]);
+        assert_eq!(target.buf.borrow()[0x30..0x40], [0; 0x10]);
+        assert_eq!(target.buf.borrow()[0x40..0x50], [0xff; 0x10]);
+
+        operations[0].data[0] = 2;
+        assert!(matches!(
+            replay_operations(&operations, &target),
+            Err(Error::Io(IoError::Verify { start: 0x10, size: 16 }))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn recording_flash_minimizes_and_encodes() -> Result<()> {
+        let underlying = CountingFlash::new();
+        let flash = RecordingFlash::new(&underlying);
+        flash.erase_block(flash.erasable_location(0x10)?)?;
+        flash.erase_and_write_block(flash.erasable_location(0x10)?, &[1])?;
+        flash.erase_and_write_block(flash.erasable_location(0x20)?, &[2])?;
+        flash.erase_and_write_block(flash.erasable_location(0x10)?, &[3])?;
+        let operations = minimize_operations(&flash.into_operations());
+        assert_eq!(operations.len(), 2);
+        assert_eq!(operations[0].location, 0x20);
+        assert_eq!(operations[1].location, 0x10);
+        assert_eq!(operations[1].data, [3]);
+
+        let encoded = encode_operations(&operations)?;
+        assert_eq!(encoded[..5], *b"EFSL\x01");
+        assert_eq!(decode_operations(&encoded)?, operations);
+        assert!(matches!(
+            decode_operations(&encoded[..encoded.len() - 1]),
+            Err(Error::Io(IoError::Malformed { .. }))
+        ));
+        assert!(matches!(
+            decode_operations(b"EFSL\x02"),
+            Err(Error::Io(IoError::Malformed { offset: 0 }))
+        ));
+
+        let target = CountingFlash::new();
+        target.buf.borrow_mut().fill(0);
+        replay_operations(&decode_operations(&encoded)?, &target)?;
+        assert_eq!(
+            target.buf.borrow()[0x10..0x30],
+            underlying.buf.borrow()[0x10..0x30]
+        );
+        assert_eq!(target.writes.get(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn cached_flash_coalesces_writes() -> Result<()> {
+        let underlying = CountingFlash::new();
+        let mut buffer = [0u8; 2 * ERASABLE_BLOCK_SIZE];
+        let cache = CachedFlash::<_, 2>::new(&underlying, &mut buffer)?;
+        let beginning = cache.erasable_location(0)?;
+        cache.erase_and_write_block(beginning, &[1; 4])?;
+        cache.erase_and_write_block(beginning, &[2; 8])?;
+        cache.erase_block(cache.erasable_location(16)?)?;
+        assert_eq!(underlying.writes.get(), 0);
+        let mut buf = [0u8; 20];
+        cache.read_exact(4, &mut buf)?;
+        assert_eq!(buf[..4], [2; 4]);
+        assert_eq!(buf[4..], [0xff; 16]);
+        assert!(cache.is_dirty());
+        cache.flush()?;
+        assert!(!cache.is_dirty());
+        assert_eq!(underlying.writes.get(), 2);
+        let mut buf = [0u8; 8];
+        underlying.read_exact(0, &mut buf)?;
+        assert_eq!(buf, [2; 8]);
+        Ok(())
+    }
+
+    #[test]
+    fn cached_flash_evicts_least_recently_used() -> Result<()> {
+        let underlying = CountingFlash::new();
+        let mut buffer = [0u8; 2 * ERASABLE_BLOCK_SIZE];
+        let cache = CachedFlash::<_, 2>::new(&underlying, &mut buffer)?;
+        cache.erase_and_write_block(cache.erasable_location(0)?, &[1])?;
+        cache.erase_and_write_block(cache.erasable_location(16)?, &[2])?;
+        cache.erase_and_write_block(cache.erasable_location(0)?, &[3])?;
+        cache.erase_and_write_block(cache.erasable_location(32)?, &[4])?;
+        // Block 16 was the least recently used one.
+        assert_eq!(underlying.writes.get(), 1);
+        let mut buf = [0u8; 1];
+        underlying.read_exact(16, &mut buf)?;
+        assert_eq!(buf, [2]);
+        underlying.read_exact(0, &mut buf)?;
+        assert_eq!(buf, [0xff]);
+        Ok(())
+    }
+
+    #[test]
+    fn cached_flash_too_small_buffer() {
+        let underlying = CountingFlash::new();
+        let mut buffer = [0u8; ERASABLE_BLOCK_SIZE];
+        assert!(matches!(
+            CachedFlash::<_, 2>::new(&underlying, &mut buffer),
+            Err(Error::Size)
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn adapters_forward_erase_sizes_and_program() -> Result<()> {
+        use crate::flash::NorSimulator;
+        let data = vec![0x42; 0x1_0000];
+        let underlying =
+            NorSimulator::new(0x4_0000, 0x1000).with_erase_sizes(&[0x1_0000]);
+        let window =
+            WindowFlashAdapter::new(&underlying, 0x1_0000, 0x2_0000, 0x4_0000)?;
+        assert_eq!(window.erase_sizes(), [0x1_0000]);
+        window.erase_and_write_blocks(window.erasable_location(0)?, &data)?;
+        assert_eq!(underlying.erase_operation_count(), 1);
+        window.program(0x1_0000, &[1, 2])?;
+        let mut buf = [0u8; 2];
+        underlying.read_exact(0x2_0000, &mut buf)?;
+        assert_eq!(buf, [1, 2]);
+
+        let mut buffer = vec![0u8; 0x1000];
+        let cache = CachedFlash::<_, 1>::new(&underlying, &mut buffer)?;
+        cache
+            .erase_and_write_block(cache.erasable_location(0x3_0000)?, &[3])?;
+        cache.program(0x3_0001, &[4])?;
+        assert_eq!(cache.erase_sizes(), [0x1_0000]);
+        cache.erase_and_write_blocks(cache.erasable_location(0)?, &data)?;
+        assert_eq!(underlying.erase_operation_count(), 2);
+        cache.flush()?;
+        underlying.read_exact(0x3_0000, &mut buf)?;
+        assert_eq!(buf, [3, 4]);
+        cache.erase(cache.erasable_location(0x3_0000)?, 0x1_0000)?;
+        assert!(!cache.is_dirty());
+        underlying.read_exact(0x3_0000, &mut buf)?;
+        assert_eq!(buf, [0xff, 0xff]);
+
+        let flash =
+            FaultInjectingFlash::with_fault(&underlying, 1, Fault::Truncate(1));
+        assert_eq!(flash.erase_sizes(), [0x1_0000]);
+        assert!(
+            flash
+                .erase_and_write_blocks(flash.erasable_location(0)?, &data)
+                .is_err()
+        );
+        assert_eq!(flash.operation_count(), 2);
+        underlying.read_exact(0, &mut buf)?;
+        assert_eq!(buf, [0x42, 0xff]);
+        Ok(())
+    }
+
+    #[test]
+    fn fault_injecting_flash_truncates() -> Result<()> {
+        let underlying = CountingFlash::new();
+        let flash =
+            FaultInjectingFlash::with_fault(&underlying, 1, Fault::Truncate(2));
+        flash.erase_and_write_block(flash.erasable_location(0)?, &[1; 4])?;
+        assert!(!flash.faulted());
+        assert!(
+            flash
+                .erase_and_write_block(flash.erasable_location(16)?, &[2; 4])
+                .is_err()
+        );
+        assert!(flash.faulted());
+        assert!(flash.erase_block(flash.erasable_location(0)?).is_err());
+        assert_eq!(flash.operation_count(), 3);
+        let mut buf = [0u8; 20];
+        flash.read_exact(0, &mut buf)?;
+        assert_eq!(buf[..4], [1; 4]);
+        assert_eq!(buf[16..], [2, 2, 0xff, 0xff]);
+        Ok(())
     }
 }
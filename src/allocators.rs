@@ -1,17 +1,91 @@
+#[cfg(feature = "std")]
+use crate::efs::Efs;
 use crate::flash::{ErasableRange, Location};
 use crate::flash::{Error, Result};
+#[cfg(feature = "std")]
+use crate::flash::{FlashRead, FlashWrite};
+use crate::ondisk::{BhdDirectoryEntryType, PspDirectoryEntryType};
 
 pub trait FlashAllocate {
     fn take_at_least(&mut self, size: usize) -> Option<ErasableRange>;
+    /// Like take_at_least, but the range also has to begin at a multiple
+    /// of ALIGNMENT (a power of two) and has to be inside WITHIN (if any).
+    /// By default, this only succeeds if the range that take_at_least
+    /// returns happens to satisfy that (otherwise, it's released again).
+    fn take_with(
+        &mut self,
+        size: usize,
+        alignment: usize,
+        within: Option<ErasableRange>,
+    ) -> Option<ErasableRange> {
+        let result = self.take_at_least(size)?;
+        let beginning = Location::from(result.beginning);
+        let inside = within.is_none_or(|within| {
+            beginning >= Location::from(within.beginning)
+                && Location::from(result.end) <= Location::from(within.end)
+        });
+        if alignment.is_power_of_two()
+            && (beginning as usize).is_multiple_of(alignment)
+            && inside
+        {
+            Some(result)
+        } else {
+            self.release(result);
+            None
+        }
+    }
     fn max_contiguous_capacity(&self) -> usize;
+    /// Gives RANGE (which was taken before) back to the allocator.
+    /// Note: Depending on the allocator, it's possible that RANGE can't
+    /// be reused.  By default, RANGE is never reused (it's leaked).
+    fn release(&mut self, range: ErasableRange) {
+        let _ = range;
+    }
 }
 
-pub struct ArenaFlashAllocator {
+/// Determines where in FREE_RANGE a range of at least SIZE Bytes would be
+/// that begins at a multiple of ALIGNMENT (a power of two) and is inside
+/// WITHIN (if any), if possible.
+fn place(
+    free_range: &ErasableRange,
+    size: usize,
+    alignment: usize,
+    within: Option<&ErasableRange>,
+) -> Option<ErasableRange> {
+    if !alignment.is_power_of_two() {
+        return None;
+    }
+    let base = u64::from(Location::from(free_range.beginning));
+    let (mut beginning, mut end) =
+        (base, u64::from(Location::from(free_range.end)));
+    if let Some(within) = within {
+        beginning = beginning.max(u64::from(Location::from(within.beginning)));
+        end = end.min(u64::from(Location::from(within.end)));
+    }
+    let erasable_block_size = free_range.beginning.erasable_block_size();
+    let beginning =
+        beginning.next_multiple_of(alignment.max(erasable_block_size) as u64);
+    let size = (size as u64).next_multiple_of(erasable_block_size as u64);
+    if beginning.checked_add(size)? > end {
+        return None;
+    }
+    let x_beginning =
+        free_range.beginning.advance((beginning - base) as usize).ok()?;
+    let x_end = x_beginning.advance(size as usize).ok()?;
+    Some(ErasableRange::new(x_beginning, x_end))
+}
+
+/// How many separate free ranges an ArenaFlashAllocator can keep track of.
+const ARENA_FREE_RANGE_SLOTS: usize = 8;
+
+pub struct ArenaFlashAllocator<'a> {
     _efh_range: ErasableRange,
-    free_ranges: [ErasableRange; 2],
+    /// Sorted by location; the unused slots are empty and at the end.
+    free_ranges: [ErasableRange; ARENA_FREE_RANGE_SLOTS],
+    protected_ranges: &'a [ErasableRange],
 }
 
-impl ArenaFlashAllocator {
+impl<'a> ArenaFlashAllocator<'a> {
     /// Creates a new allocator that will use parts of the given ARENA.
     /// Depending on processor generation, a part of it will be cut out
     /// and not given to the user (since it needs to be at a fixed
@@ -28,21 +102,200 @@ impl ArenaFlashAllocator {
         let a = arena.take_at_least(a_size).ok_or(Error::Size)?;
         assert!(Location::from(a.end) as usize == a_size);
         let _efh_range = arena.take_at_least(efh_size).ok_or(Error::Size)?;
-        Ok(Self { _efh_range, free_ranges: [a, arena] })
+        let mut result = Self {
+            _efh_range,
+            free_ranges: [ErasableRange::new(arena.end, arena.end);
+                ARENA_FREE_RANGE_SLOTS],
+            protected_ranges: &[],
+        };
+        result.insert_free_range(a);
+        result.insert_free_range(arena);
+        Ok(result)
+    }
+
+    /// Adds RANGE to the free ranges, coalescing it with adjacent ones.
+    /// Returns false if there's no slot left to keep track of it.
+    fn insert_free_range(&mut self, range: ErasableRange) -> bool {
+        if range.capacity() == 0 {
+            return true;
+        }
+        let mut range = range;
+        for free_range in &mut self.free_ranges {
+            if free_range.capacity() == 0 {
+                continue;
+            }
+            if Location::from(free_range.end) == Location::from(range.beginning)
+            {
+                range.beginning = free_range.beginning;
+                *free_range = ErasableRange::new(range.end, range.end);
+            } else if Location::from(range.end)
+                == Location::from(free_range.beginning)
+            {
+                range.end = free_range.end;
+                *free_range = ErasableRange::new(range.end, range.end);
+            }
+        }
+        let Some(slot) = self
+            .free_ranges
+            .iter_mut()
+            .find(|free_range| free_range.capacity() == 0)
+        else {
+            return false;
+        };
+        *slot = range;
+        self.sort_free_ranges();
+        true
+    }
+
+    /// Sorts the free ranges by location, with the unused slots last.
+    fn sort_free_ranges(&mut self) {
+        self.free_ranges.sort_unstable_by_key(|free_range| {
+            (free_range.capacity() == 0, Location::from(free_range.beginning))
+        });
+    }
+
+    /// Makes the allocator never hand out anything inside PROTECTED_RANGES.
+    pub fn with_protected_ranges(
+        mut self,
+        protected_ranges: &'a [ErasableRange],
+    ) -> Self {
+        self.protected_ranges = protected_ranges;
+        self
+    }
+
+    /// Returns the end of the protected range overlapping RANGE that
+    /// ends last, if any.
+    fn protected_end(&self, range: &ErasableRange) -> Option<Location> {
+        self.protected_ranges
+            .iter()
+            .filter(|protected_range| {
+                protected_range
+                    .overlaps(range.beginning.into(), range.capacity())
+            })
+            .map(|protected_range| Location::from(protected_range.end))
+            .max()
+    }
+
+    /// Finds a range of at least SIZE Bytes in FREE_RANGE that doesn't
+    /// overlap any protected range (and begins at a multiple of ALIGNMENT,
+    /// and is inside WITHIN), if possible.
+    fn find_unprotected(
+        &self,
+        free_range: &ErasableRange,
+        size: usize,
+        alignment: usize,
+        within: Option<&ErasableRange>,
+    ) -> Option<ErasableRange> {
+        let mut rest = *free_range;
+        loop {
+            let candidate = place(&rest, size, alignment, within)?;
+            match self.protected_end(&candidate) {
+                None => {
+                    return Some(candidate);
+                }
+                Some(end) => {
+                    // Skip the protected range.
+                    if end >= Location::from(rest.end) {
+                        return None;
+                    }
+                    let amount = end - Location::from(rest.beginning);
+                    rest.beginning = rest
+                        .beginning
+                        .advance_at_least(amount as usize)
+                        .ok()?;
+                }
+            }
+        }
+    }
+
+    /// Returns the capacity of the biggest part of FREE_RANGE that doesn't
+    /// overlap any protected range.
+    fn max_unprotected_capacity(&self, free_range: &ErasableRange) -> usize {
+        let mut max_capacity = 0usize;
+        let mut rest = *free_range;
+        while rest.capacity() > 0 {
+            // The protected range overlapping REST that begins first.
+            let next = self
+                .protected_ranges
+                .iter()
+                .filter(|protected_range| {
+                    protected_range
+                        .overlaps(rest.beginning.into(), rest.capacity())
+                })
+                .min_by_key(|protected_range| {
+                    Location::from(protected_range.beginning)
+                });
+            let Some(next) = next else {
+                return max_capacity.max(rest.capacity());
+            };
+            let gap = Location::from(next.beginning)
+                .saturating_sub(Location::from(rest.beginning));
+            max_capacity = max_capacity.max(gap as usize);
+            if Location::from(next.end) >= Location::from(rest.end) {
+                break;
+            }
+            let amount =
+                Location::from(next.end) - Location::from(rest.beginning);
+            match rest.beginning.advance_at_least(amount as usize) {
+                Ok(beginning)
+                    if Location::from(beginning)
+                        <= Location::from(rest.end) =>
+                {
+                    rest.beginning = beginning
+                }
+                _ => break,
+            }
+        }
+        max_capacity
     }
 }
-impl FlashAllocate for ArenaFlashAllocator {
+impl FlashAllocate for ArenaFlashAllocator<'_> {
     /// From the free ranges, take a range of at least SIZE Bytes,
     /// if possible. Otherwise return None.
     fn take_at_least(&mut self, size: usize) -> Option<ErasableRange> {
-        self.free_ranges[0]
-            .take_at_least(size)
-            .or_else(|| self.free_ranges[1].take_at_least(size))
+        self.take_with(size, 1, None)
+    }
+    /// Note: The free range the result is taken from is split around it.
+    /// If there's no slot left for the part in front of the result, that
+    /// part is lost.
+    fn take_with(
+        &mut self,
+        size: usize,
+        alignment: usize,
+        within: Option<ErasableRange>,
+    ) -> Option<ErasableRange> {
+        let (index, result) = self.free_ranges.iter().enumerate().find_map(
+            |(index, range)| {
+                Some((
+                    index,
+                    self.find_unprotected(
+                        range,
+                        size,
+                        alignment,
+                        within.as_ref(),
+                    )?,
+                ))
+            },
+        )?;
+        let free_range = self.free_ranges[index];
+        self.free_ranges[index] =
+            ErasableRange::new(result.end, free_range.end);
+        self.sort_free_ranges();
+        self.insert_free_range(ErasableRange::new(
+            free_range.beginning,
+            result.beginning,
+        ));
+        Some(result)
+    }
+    /// Note: If there are too many (ARENA_FREE_RANGE_SLOTS) separate free
+    /// ranges already, RANGE can't be reused.
+    fn release(&mut self, range: ErasableRange) {
+        self.insert_free_range(range);
     }
     fn max_contiguous_capacity(&self) -> usize {
         let mut max_capacity = 0usize;
         for range in &self.free_ranges {
-            let capacity = range.capacity();
+            let capacity = self.max_unprotected_capacity(range);
             if capacity > max_capacity {
                 max_capacity = capacity
             }
@@ -51,6 +304,258 @@ impl FlashAllocate for ArenaFlashAllocator {
     }
 }
 
+/// This is an allocator that keeps a list of free ranges, sorted by
+/// location.  It takes the smallest free range that fits (best fit), and
+/// coalesces adjacent free ranges on release.
+#[cfg(feature = "std")]
+pub struct FreeListFlashAllocator {
+    free_ranges: Vec<ErasableRange>,
+}
+
+#[cfg(feature = "std")]
+impl FreeListFlashAllocator {
+    /// Creates a new allocator that will use all of ARENA.
+    pub fn new(arena: ErasableRange) -> Self {
+        let mut free_ranges = Vec::new();
+        if arena.capacity() > 0 {
+            free_ranges.push(arena);
+        }
+        Self { free_ranges }
+    }
+
+    /// Creates a new allocator that will use the parts of ARENA that are
+    /// not in use by EFS (that is, not by its EFH, its directories nor
+    /// their payloads).
+    pub fn from_efs<T: FlashRead + FlashWrite, const MAX_ENTRIES: usize>(
+        efs: &Efs<'_, T, MAX_ENTRIES>,
+        arena: ErasableRange,
+    ) -> crate::Result<Self> {
+        let mut result = Self::new(arena);
+        efs.visit_used_ranges(|beginning, size| {
+            result.mark_used(beginning, size)
+        })?;
+        Ok(result)
+    }
+
+    /// Removes all the erasable blocks that overlap the SIZE Byte starting
+    /// at BEGINNING from the free ranges.
+    pub fn mark_used(&mut self, beginning: Location, size: usize) {
+        let end = u64::from(beginning) + size as u64;
+        let mut free_ranges = Vec::with_capacity(self.free_ranges.len() + 1);
+        for range in self.free_ranges.drain(..) {
+            if !range.overlaps(beginning, size) {
+                free_ranges.push(range);
+                continue;
+            }
+            let range_beginning = Location::from(range.beginning);
+            if beginning > range_beginning {
+                let amount = (beginning - range_beginning)
+                    & !range.beginning.erasable_block_mask();
+                if amount > 0 {
+                    let end = range
+                        .beginning
+                        .advance(amount as usize)
+                        .expect("Location within range");
+                    free_ranges.push(ErasableRange::new(range.beginning, end));
+                }
+            }
+            if end < u64::from(Location::from(range.end)) {
+                let amount = end - u64::from(range_beginning);
+                let beginning = range
+                    .beginning
+                    .advance_at_least(amount as usize)
+                    .expect("Location within range");
+                if Location::from(beginning) < Location::from(range.end) {
+                    free_ranges.push(ErasableRange::new(beginning, range.end));
+                }
+            }
+        }
+        self.free_ranges = free_ranges;
+    }
+
+    /// Returns the free ranges, sorted by location.
+    pub fn free_ranges(&self) -> &[ErasableRange] {
+        &self.free_ranges
+    }
+}
+
+#[cfg(feature = "std")]
+impl FlashAllocate for FreeListFlashAllocator {
+    /// From the free ranges, take a range of at least SIZE Bytes,
+    /// if possible (from the smallest one that fits). Otherwise return None.
+    fn take_at_least(&mut self, size: usize) -> Option<ErasableRange> {
+        self.take_with(size, 1, None)
+    }
+    fn take_with(
+        &mut self,
+        size: usize,
+        alignment: usize,
+        within: Option<ErasableRange>,
+    ) -> Option<ErasableRange> {
+        let (index, result) = self
+            .free_ranges
+            .iter()
+            .enumerate()
+            .filter_map(|(index, range)| {
+                Some((index, place(range, size, alignment, within.as_ref())?))
+            })
+            .min_by_key(|(index, _)| self.free_ranges[*index].capacity())?;
+        let range = self.free_ranges.remove(index);
+        let after = ErasableRange::new(result.end, range.end);
+        if after.capacity() > 0 {
+            self.free_ranges.insert(index, after);
+        }
+        let before = ErasableRange::new(range.beginning, result.beginning);
+        if before.capacity() > 0 {
+            self.free_ranges.insert(index, before);
+        }
+        Some(result)
+    }
+    fn release(&mut self, range: ErasableRange) {
+        if range.capacity() == 0 {
+            return;
+        }
+        let index = self.free_ranges.partition_point(|free_range| {
+            Location::from(free_range.beginning)
+                < Location::from(range.beginning)
+        });
+        self.free_ranges.insert(index, range);
+        // Coalesce with the neighbors (if adjacent or overlapping).
+        let mut index = index.saturating_sub(1);
+        while index + 1 < self.free_ranges.len() {
+            let next = self.free_ranges[index + 1];
+            let current = &mut self.free_ranges[index];
+            if Location::from(next.beginning) <= Location::from(current.end) {
+                if Location::from(next.end) > Location::from(current.end) {
+                    current.end = next.end;
+                }
+                self.free_ranges.remove(index + 1);
+            } else if Location::from(next.beginning) > Location::from(range.end)
+            {
+                break;
+            } else {
+                index += 1;
+            }
+        }
+    }
+    fn max_contiguous_capacity(&self) -> usize {
+        self.free_ranges.iter().map(|range| range.capacity()).max().unwrap_or(0)
+    }
+}
+
+/// Up to where on the flash a payload can be placed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PlacementLimit {
+    Anywhere,
+    /// Inside the first 16 MiB of the flash.  The PSP only ever sees 16 MiB
+    /// of the flash at once (see README.md), so that's where everything it
+    /// loads has to be when STORAGE is the entire flash.
+    First16MiB,
+    /// Below the EFH.
+    BelowEfh,
+}
+
+/// Constraints for where a payload can be placed on the flash.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PlacementConstraints {
+    /// in Byte; a power of two. Payloads are always aligned to the
+    /// erasable block size in addition.
+    pub alignment: usize,
+    pub limit: PlacementLimit,
+}
+
+impl PlacementConstraints {
+    pub const DEFAULT: Self =
+        Self { alignment: 1, limit: PlacementLimit::Anywhere };
+    /// What the PSP loads.
+    const PSP_VISIBLE: Self =
+        Self { alignment: 1, limit: PlacementLimit::First16MiB };
+    /// Directories and what the boot ROM loads.
+    pub const DIRECTORY: Self =
+        Self { alignment: 0x1000, limit: PlacementLimit::First16MiB };
+    /// Payloads that are updated in place at runtime, possibly by erasing
+    /// 64 KiB blocks--which they therefore shouldn't share with anything.
+    const UPDATABLE: Self =
+        Self { alignment: 0x1_0000, limit: PlacementLimit::First16MiB };
+
+    /// Returns the default constraints for payloads of entries of type
+    /// TYPE_ in a PSP directory.
+    pub fn for_psp_entry_type(type_: PspDirectoryEntryType) -> Self {
+        match type_ {
+            PspDirectoryEntryType::AmdPublicKey
+            | PspDirectoryEntryType::PspBootloader
+            | PspDirectoryEntryType::PspRecoveryBootloader
+            | PspDirectoryEntryType::SecondLevelDirectory
+            | PspDirectoryEntryType::SecondLevelAPspDirectory
+            | PspDirectoryEntryType::SecondLevelBPspDirectory
+            | PspDirectoryEntryType::SecondLevelBhdDirectory => Self::DIRECTORY,
+            PspDirectoryEntryType::PspNvdata
+            | PspDirectoryEntryType::PspRpmcNvram
+            | PspDirectoryEntryType::TeeWriteOnceNvram => Self::UPDATABLE,
+            _ => Self::PSP_VISIBLE,
+        }
+    }
+
+    /// Returns the default constraints for payloads of entries of type
+    /// TYPE_ in a BHD directory.
+    pub fn for_bhd_entry_type(type_: BhdDirectoryEntryType) -> Self {
+        match type_ {
+            BhdDirectoryEntryType::Bios => {
+                Self { alignment: 0x1_0000, limit: PlacementLimit::First16MiB }
+            }
+            BhdDirectoryEntryType::SecondLevelDirectory => Self::DIRECTORY,
+            BhdDirectoryEntryType::Apcb
+            | BhdDirectoryEntryType::ApcbBackup
+            | BhdDirectoryEntryType::ApobNvCopy => Self::UPDATABLE,
+            _ => Self::PSP_VISIBLE,
+        }
+    }
+
+    /// Returns the part of ARENA that payloads have to be within, given
+    /// that the EFH begins at EFH_BEGINNING.
+    pub fn within(
+        &self,
+        arena: &ErasableRange,
+        efh_beginning: Location,
+    ) -> ErasableRange {
+        let limit = match self.limit {
+            PlacementLimit::Anywhere => return *arena,
+            PlacementLimit::First16MiB => 0x100_0000,
+            PlacementLimit::BelowEfh => efh_beginning,
+        };
+        let beginning = Location::from(arena.beginning);
+        if limit >= Location::from(arena.end) {
+            *arena
+        } else if limit <= beginning {
+            ErasableRange::new(arena.beginning, arena.beginning)
+        } else {
+            let amount =
+                (limit - beginning) & !arena.beginning.erasable_block_mask();
+            let end = arena
+                .beginning
+                .advance(amount as usize)
+                .expect("Location within arena");
+            ErasableRange::new(arena.beginning, end)
+        }
+    }
+
+    /// Takes a range of at least SIZE Bytes that satisfies these
+    /// constraints from ALLOCATOR, if possible.
+    pub fn take_at_least(
+        &self,
+        allocator: &mut impl FlashAllocate,
+        size: usize,
+        arena: &ErasableRange,
+        efh_beginning: Location,
+    ) -> Option<ErasableRange> {
+        allocator.take_with(
+            size,
+            self.alignment,
+            Some(self.within(arena, efh_beginning)),
+        )
+    }
+}
+
 #[cfg(test)]
 mod allocator_tests {
     use super::*;
@@ -75,7 +580,7 @@ mod allocator_tests {
         }
     }
     impl Buffer {
-        fn allocator(&self) -> ArenaFlashAllocator {
+        fn allocator(&self) -> ArenaFlashAllocator<'static> {
             let beginning = self.erasable_location(0).unwrap();
             let end = beginning.advance_at_least(0x4_0000).unwrap();
             ArenaFlashAllocator::new(
@@ -134,4 +639,280 @@ mod allocator_tests {
         assert!(Location::from(b.end) < 0x4_0000);
         assert!(Location::from(b.beginning) > 0x2_0000);
     }
+
+    #[test]
+    fn test_allocator_protected_ranges() {
+        let buf = Buffer {};
+        let protected_ranges = [
+            ErasableRange::new(
+                buf.erasable_location(0x1000).unwrap(),
+                buf.erasable_location(0x1_0000).unwrap(),
+            ),
+            ErasableRange::new(
+                buf.erasable_location(0x3_0000).unwrap(),
+                buf.erasable_location(0x3_8000).unwrap(),
+            ),
+        ];
+        let mut allocator =
+            buf.allocator().with_protected_ranges(&protected_ranges);
+        let efh_range = buf.efh_range();
+        assert_eq!(allocator.max_contiguous_capacity(), 0x1_0000);
+        let a = allocator.take_at_least(0x800).unwrap();
+        let b = allocator.take_at_least(0x1000).unwrap();
+        let c = allocator.take_at_least(0xf000).unwrap();
+        assert!(allocator.take_at_least(0x1_0000).is_none());
+        assert_eq!(allocator.max_contiguous_capacity(), 0xfe00);
+        let d = allocator.take_at_least(0xfe00).unwrap();
+        for x in [&a, &b, &c, &d] {
+            assert!(intersect(x, &efh_range).is_none());
+            for protected_range in &protected_ranges {
+                assert!(intersect(x, protected_range).is_none());
+            }
+        }
+        assert_eq!(Location::from(a.beginning), 0);
+        assert_eq!(Location::from(b.beginning), 0x1_0000);
+        assert_eq!(Location::from(c.beginning), 0x1_1000);
+        assert_eq!(Location::from(d.beginning), 0x2_0200);
+        assert_eq!(allocator.max_contiguous_capacity(), 0x8000);
+        // The space in front of the protected ranges is still available.
+        let e = allocator.take_at_least(0x800).unwrap();
+        assert_eq!(Location::from(e.beginning), 0x800);
+        let f = allocator.take_at_least(0x8000).unwrap();
+        assert_eq!(Location::from(f.beginning), 0x3_8000);
+        assert!(intersect(&f, &protected_ranges[1]).is_none());
+    }
+
+    #[test]
+    fn test_allocator_release() {
+        let buf = Buffer {};
+        let mut allocator = buf.allocator();
+        let a = allocator.take_at_least(0x100).unwrap();
+        let b = allocator.take_at_least(0x100).unwrap();
+        let c = allocator.take_at_least(0x100).unwrap();
+        allocator.release(a);
+        let x = allocator.take_at_least(0x100).unwrap();
+        assert_eq!(Location::from(x.beginning), Location::from(a.beginning));
+        allocator.release(b);
+        allocator.release(c);
+        let d = allocator.take_at_least(0x2_0000 - 0x100).unwrap();
+        assert_eq!(Location::from(d.beginning), 0x100);
+        let mut xs = [d; ARENA_FREE_RANGE_SLOTS + 1];
+        for x in &mut xs {
+            *x = allocator.take_at_least(0x100).unwrap();
+            allocator.take_at_least(0x100).unwrap();
+        }
+        for x in xs {
+            allocator.release(x);
+        }
+        // The last one didn't fit anymore.
+        assert_eq!(
+            allocator
+                .free_ranges
+                .iter()
+                .filter(|range| range.capacity() == 0x100)
+                .count(),
+            ARENA_FREE_RANGE_SLOTS - 1
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_free_list_allocator_best_fit_and_coalescing() {
+        let buf = Buffer {};
+        let beginning = buf.erasable_location(0).unwrap();
+        let end = beginning.advance(0x100).unwrap();
+        let mut allocator =
+            FreeListFlashAllocator::new(ErasableRange::new(beginning, end));
+        let a = allocator.take_at_least(0x10).unwrap();
+        let b = allocator.take_at_least(0x8).unwrap();
+        let c = allocator.take_at_least(0x20).unwrap();
+        let d = allocator.take_at_least(0x4).unwrap();
+        allocator.release(a);
+        allocator.release(c);
+        // Best fit: C's old range, not A's or the rest at the end.
+        let e = allocator.take_at_least(0x1c).unwrap();
+        assert_eq!(Location::from(e.beginning), 0x18);
+        assert_eq!(allocator.free_ranges().len(), 3);
+        allocator.release(b);
+        assert_eq!(allocator.free_ranges().len(), 3);
+        allocator.release(e);
+        allocator.release(d);
+        assert_eq!(allocator.free_ranges().len(), 1);
+        assert_eq!(allocator.max_contiguous_capacity(), 0x100);
+    }
+
+    #[test]
+    fn test_allocator_take_with() {
+        let buf = Buffer {};
+        let mut allocator = buf.allocator();
+        let efh_range = buf.efh_range();
+        let a = allocator.take_at_least(0x10).unwrap();
+        let b = allocator.take_with(0x10, 0x1000, None).unwrap();
+        assert_eq!(Location::from(a.beginning), 0);
+        assert_eq!(Location::from(b.beginning), 0x1000);
+        let within = ErasableRange::new(
+            buf.erasable_location(0x2_0000).unwrap(),
+            buf.erasable_location(0x2_1000).unwrap(),
+        );
+        let c = allocator.take_with(0x100, 0x100, Some(within)).unwrap();
+        assert!(intersect(&c, &efh_range).is_none());
+        assert_eq!(Location::from(c.beginning), 0x2_0200);
+        assert!(allocator.take_with(0x1000, 0x100, Some(within)).is_none());
+    }
+
+    #[test]
+    fn test_placement_constraints() {
+        let buf = Buffer {};
+        let beginning = buf.erasable_location(0).unwrap();
+        let arena = ErasableRange::new(
+            beginning,
+            beginning.advance(0x200_0000).unwrap(),
+        );
+        let constraints = PlacementConstraints::for_bhd_entry_type(
+            BhdDirectoryEntryType::Bios,
+        );
+        assert_eq!(constraints.alignment, 0x1_0000);
+        let within = constraints.within(&arena, 0xfa_0000);
+        assert_eq!(Location::from(within.end), 0x100_0000);
+        let constraints = PlacementConstraints::for_bhd_entry_type(
+            BhdDirectoryEntryType::ApobNvCopy,
+        );
+        assert_eq!(constraints, PlacementConstraints::UPDATABLE);
+        assert_eq!(
+            PlacementConstraints::for_psp_entry_type(
+                PspDirectoryEntryType::PspNvdata
+            ),
+            constraints
+        );
+        assert_eq!(
+            PlacementConstraints::for_psp_entry_type(
+                PspDirectoryEntryType::PspBootloader
+            ),
+            PlacementConstraints::DIRECTORY
+        );
+        assert_eq!(
+            PlacementConstraints::for_bhd_entry_type(
+                BhdDirectoryEntryType::SecondLevelDirectory
+            ),
+            PlacementConstraints::DIRECTORY
+        );
+        let constraints = PlacementConstraints::for_psp_entry_type(
+            PspDirectoryEntryType::PspTrustlets,
+        );
+        assert_eq!(constraints, PlacementConstraints::PSP_VISIBLE);
+        let within = constraints.within(&arena, 0xfa_0000);
+        assert_eq!(Location::from(within.end), 0x100_0000);
+        let constraints = PlacementConstraints {
+            alignment: 1,
+            limit: PlacementLimit::BelowEfh,
+        };
+        let within = constraints.within(&arena, 0xfa_0000);
+        assert_eq!(Location::from(within.end), 0xfa_0000);
+        assert_eq!(
+            PlacementConstraints::DEFAULT.within(&arena, 0xfa_0000).capacity(),
+            0x200_0000
+        );
+    }
+
+    /// An allocator that only implements what FlashAllocate requires.
+    struct BumpAllocator(ErasableRange);
+    impl FlashAllocate for BumpAllocator {
+        fn take_at_least(&mut self, size: usize) -> Option<ErasableRange> {
+            self.0.take_at_least(size)
+        }
+        fn max_contiguous_capacity(&self) -> usize {
+            self.0.capacity()
+        }
+    }
+
+    #[test]
+    fn test_default_take_with() {
+        let buf = Buffer {};
+        let beginning = buf.erasable_location(0).unwrap();
+        let mut allocator = BumpAllocator(ErasableRange::new(
+            beginning,
+            beginning.advance(0x100).unwrap(),
+        ));
+        let a = allocator.take_with(0x10, 0x10, None).unwrap();
+        assert_eq!(Location::from(a.beginning), 0);
+        assert!(allocator.take_with(0x10, 0x20, None).is_none());
+        let within = ErasableRange::new(beginning, a.end);
+        assert!(allocator.take_with(0x10, 1, Some(within)).is_none());
+        // The default release leaks.
+        assert_eq!(allocator.max_contiguous_capacity(), 0xd0);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_free_list_allocator_take_with() {
+        let buf = Buffer {};
+        let beginning = buf.erasable_location(0).unwrap();
+        let end = beginning.advance(0x100).unwrap();
+        let mut allocator =
+            FreeListFlashAllocator::new(ErasableRange::new(beginning, end));
+        let a = allocator.take_with(0x10, 0x40, None).unwrap();
+        let b = allocator.take_with(0x10, 0x40, None).unwrap();
+        assert_eq!(Location::from(a.beginning), 0);
+        assert_eq!(Location::from(b.beginning), 0x40);
+        // The gap in front of B is still free.
+        let c = allocator.take_at_least(0x30).unwrap();
+        assert_eq!(Location::from(c.beginning), 0x10);
+        assert_eq!(allocator.free_ranges().len(), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_free_list_allocator_from_efs_with_capacity() {
+        use crate::flash::NorSimulator;
+        use crate::{
+            AddressMode, PspDirectoryEntry, PspDirectoryEntryType,
+            PspDirectoryHeader, PspDirectoryWithCapacity,
+        };
+        use crate::{DEFAULT_MAX_DIRECTORY_ENTRIES, ProcessorGeneration};
+        const MAX_ENTRIES: usize = 2 * DEFAULT_MAX_DIRECTORY_ENTRIES;
+        let storage = NorSimulator::new(0x10_0000, 0x1000);
+        let mut efs =
+            Efs::create(&storage, ProcessorGeneration::Genoa, 0x2_0000, None)
+                .unwrap()
+                .with_max_directory_entries::<MAX_ENTRIES>();
+        let entries = (0..100)
+            .map(|i| {
+                PspDirectoryEntry::new_value(
+                    PspDirectoryEntryType::PspSoftFuseChain,
+                    i,
+                )
+                .unwrap()
+            })
+            .collect::<Vec<_>>();
+        let beginning = storage.erasable_location(0x4_0000).unwrap();
+        let range =
+            ErasableRange::new(beginning, beginning.advance(0x1000).unwrap());
+        let mut directory = efs
+            .create_psp_directory(
+                PspDirectoryHeader::FIRST_LEVEL_COOKIE,
+                beginning,
+                range.end,
+                None,
+                AddressMode::EfsRelativeOffset,
+                &entries,
+            )
+            .unwrap();
+        directory.write_to(&storage, &range).unwrap();
+        efs.set_main_psp_directory(&directory).unwrap();
+
+        let efs = Efs::load(&storage, Some(ProcessorGeneration::Genoa), None)
+            .unwrap();
+        assert!(efs.psp_directory().is_err());
+        let efs = efs.with_max_directory_entries::<MAX_ENTRIES>();
+        let directory: PspDirectoryWithCapacity<MAX_ENTRIES> =
+            efs.psp_directory().unwrap();
+        assert_eq!(directory.entries().count(), 100);
+        let arena_beginning = storage.erasable_location(0).unwrap();
+        let arena = ErasableRange::new(
+            arena_beginning,
+            arena_beginning.advance(0x10_0000).unwrap(),
+        );
+        let allocator = FreeListFlashAllocator::from_efs(&efs, arena).unwrap();
+        assert_eq!(allocator.free_ranges().len(), 3);
+    }
 }
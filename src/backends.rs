@@ -0,0 +1,515 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::flash;
+#[cfg(feature = "std")]
+use core::cell::Cell;
+use core::cell::RefCell;
+use flash::{ErasableLocation, FlashAlign, FlashRead, FlashWrite, Location};
+use flash::{Error, IoError, Result};
+#[cfg(feature = "std")]
+use std::io::{Read, Seek, SeekFrom, Write};
+
+/// Returns the range START..(START + SIZE) if it's within CAPACITY.
+fn checked_range(
+    start: Location,
+    size: usize,
+    capacity: usize,
+) -> Option<core::ops::Range<usize>> {
+    let beginning = start as usize;
+    let end = beginning.checked_add(size)?;
+    (end <= capacity).then_some(beginning..end)
+}
+
+/// This is a flash image in memory, for example a `Vec<u8>` or a
+/// `&mut [u8]`.
+/// Erasing sets the bytes to 0xFF.
+pub struct MemoryFlash<B: AsRef<[u8]> + AsMut<[u8]>> {
+    buf: RefCell<B>,
+    erasable_block_size: usize,
+}
+
+impl<B: AsRef<[u8]> + AsMut<[u8]>> MemoryFlash<B> {
+    /// Note: ERASABLE_BLOCK_SIZE is assumed to be a power of two.
+    pub fn new(buf: B, erasable_block_size: usize) -> Self {
+        assert!(erasable_block_size.is_power_of_two());
+        Self { buf: RefCell::new(buf), erasable_block_size }
+    }
+    /// in Byte
+    pub fn capacity(&self) -> usize {
+        self.buf.borrow().as_ref().len()
+    }
+    pub fn into_inner(self) -> B {
+        self.buf.into_inner()
+    }
+}
+
+impl<B: AsRef<[u8]> + AsMut<[u8]>> FlashRead for MemoryFlash<B> {
+    fn read_exact(&self, location: Location, buffer: &mut [u8]) -> Result<()> {
+        let buf = self.buf.borrow();
+        let buf = buf.as_ref();
+        let range = checked_range(location, buffer.len(), buf.len()).ok_or(
+            Error::Io(IoError::Read { start: location, size: buffer.len() }),
+        )?;
+        buffer.copy_from_slice(&buf[range]);
+        Ok(())
+    }
+}
+
+impl<B: AsRef<[u8]> + AsMut<[u8]>> FlashAlign for MemoryFlash<B> {
+    fn erasable_block_size(&self) -> usize {
+        self.erasable_block_size
+    }
+}
+
+impl<B: AsRef<[u8]> + AsMut<[u8]>> FlashWrite for MemoryFlash<B> {
+    fn erase_block(&self, location: ErasableLocation) -> Result<()> {
+        let location = self.location(location)?;
+        let mut buf = self.buf.borrow_mut();
+        let buf = buf.as_mut();
+        let range =
+            checked_range(location, self.erasable_block_size, buf.len())
+                .ok_or(Error::Io(IoError::Erase {
+                    start: location,
+                    size: self.erasable_block_size,
+                }))?;
+        buf[range].fill(0xff);
+        Ok(())
+    }
+    fn erase_and_write_block(
+        &self,
+        location: ErasableLocation,
+        buffer: &[u8],
+    ) -> Result<()> {
+        let location = self.location(location)?;
+        if buffer.len() > self.erasable_block_size {
+            return Err(Error::Size);
+        }
+        let mut buf = self.buf.borrow_mut();
+        let buf = buf.as_mut();
+        let range =
+            checked_range(location, self.erasable_block_size, buf.len())
+                .ok_or(Error::Io(IoError::Write {
+                    start: location,
+                    size: self.erasable_block_size,
+                }))?;
+        let block = &mut buf[range];
+        block[..buffer.len()].copy_from_slice(buffer);
+        block[buffer.len()..].fill(0xff);
+        Ok(())
+    }
+    /// This just copies BUFFER (it doesn't check that the flash there is
+    /// erased).
+    fn program(&self, location: Location, buffer: &[u8]) -> Result<()> {
+        let mut buf = self.buf.borrow_mut();
+        let buf = buf.as_mut();
+        let range = checked_range(location, buffer.len(), buf.len()).ok_or(
+            Error::Io(IoError::Write { start: location, size: buffer.len() }),
+        )?;
+        buf[range].copy_from_slice(buffer);
+        Ok(())
+    }
+}
+
+/// This is a flash image in a file (for example a ROM image).
+/// Erasing sets the bytes to 0xFF.
+/// The size of the file is fixed when it's opened.
+#[cfg(feature = "std")]
+pub struct FileFlash {
+    file: RefCell<std::fs::File>,
+    capacity: usize,
+    erasable_block_size: usize,
+}
+
+#[cfg(feature = "std")]
+impl FileFlash {
+    /// Opens the existing image at PATH for reading and writing.
+    /// Note: ERASABLE_BLOCK_SIZE is assumed to be a power of two.
+    pub fn open<P: AsRef<std::path::Path>>(
+        path: P,
+        erasable_block_size: usize,
+    ) -> Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(|_| Error::Io(IoError::Open))?;
+        Self::from_file(file, erasable_block_size)
+    }
+    /// Creates (or truncates) an image at PATH that is CAPACITY Byte big
+    /// and entirely erased.
+    /// Note: ERASABLE_BLOCK_SIZE is assumed to be a power of two.
+    pub fn create<P: AsRef<std::path::Path>>(
+        path: P,
+        capacity: usize,
+        erasable_block_size: usize,
+    ) -> Result<Self> {
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .map_err(|_| Error::Io(IoError::Open))?;
+        file.write_all(&vec![0xff; capacity]).map_err(|_| {
+            Error::Io(IoError::Write { start: 0, size: capacity })
+        })?;
+        Self::from_file(file, erasable_block_size)
+    }
+    /// Note: ERASABLE_BLOCK_SIZE is assumed to be a power of two.
+    pub fn from_file(
+        file: std::fs::File,
+        erasable_block_size: usize,
+    ) -> Result<Self> {
+        assert!(erasable_block_size.is_power_of_two());
+        let capacity = file
+            .metadata()
+            .map_err(|_| Error::Io(IoError::Open))?
+            .len()
+            .try_into()
+            .map_err(|_| Error::Size)?;
+        Ok(Self { file: RefCell::new(file), capacity, erasable_block_size })
+    }
+    /// in Byte
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+    /// Makes sure that everything written so far is on the disk.
+    pub fn flush(&self) -> Result<()> {
+        let mut file = self.file.borrow_mut();
+        file.flush().map_err(|_| Error::Io(IoError::Flush))?;
+        file.sync_data().map_err(|_| Error::Io(IoError::Flush))
+    }
+    fn write_at(
+        &self,
+        location: Location,
+        buffer: &[u8],
+        error: IoError,
+    ) -> Result<()> {
+        let mut file = self.file.borrow_mut();
+        file.seek(SeekFrom::Start(location.into()))
+            .and_then(|_| file.write_all(buffer))
+            .map_err(|_| Error::Io(error))
+    }
+}
+
+#[cfg(feature = "std")]
+impl FlashRead for FileFlash {
+    fn read_exact(&self, location: Location, buffer: &mut [u8]) -> Result<()> {
+        let size = buffer.len();
+        let error = || Error::Io(IoError::Read { start: location, size });
+        checked_range(location, size, self.capacity).ok_or_else(error)?;
+        let mut file = self.file.borrow_mut();
+        file.seek(SeekFrom::Start(location.into()))
+            .and_then(|_| file.read_exact(buffer))
+            .map_err(|_| error())
+    }
+}
+
+#[cfg(feature = "std")]
+impl FlashAlign for FileFlash {
+    fn erasable_block_size(&self) -> usize {
+        self.erasable_block_size
+    }
+}
+
+#[cfg(feature = "std")]
+impl FlashWrite for FileFlash {
+    fn erase_block(&self, location: ErasableLocation) -> Result<()> {
+        let location = self.location(location)?;
+        let error =
+            IoError::Erase { start: location, size: self.erasable_block_size };
+        if checked_range(location, self.erasable_block_size, self.capacity)
+            .is_none()
+        {
+            return Err(Error::Io(error));
+        }
+        self.write_at(location, &vec![0xff; self.erasable_block_size], error)
+    }
+    fn erase_and_write_block(
+        &self,
+        location: ErasableLocation,
+        buffer: &[u8],
+    ) -> Result<()> {
+        let location = self.location(location)?;
+        if buffer.len() > self.erasable_block_size {
+            return Err(Error::Size);
+        }
+        let error =
+            IoError::Write { start: location, size: self.erasable_block_size };
+        if checked_range(location, self.erasable_block_size, self.capacity)
+            .is_none()
+        {
+            return Err(Error::Io(error));
+        }
+        let mut block = vec![0xff; self.erasable_block_size];
+        block[..buffer.len()].copy_from_slice(buffer);
+        self.write_at(location, &block, error)
+    }
+    /// This just writes BUFFER (it doesn't check that the flash there is
+    /// erased).
+    fn program(&self, location: Location, buffer: &[u8]) -> Result<()> {
+        let error = IoError::Write { start: location, size: buffer.len() };
+        if checked_range(location, buffer.len(), self.capacity).is_none() {
+            return Err(Error::Io(error));
+        }
+        self.write_at(location, buffer, error)
+    }
+}
+
+/// This simulates a NOR flash chip: Erasing sets all the bits of an
+/// erasable block, and programming can only clear bits.  Programming a
+/// bit that is 0 to 1 (without erasing first) fails with
+/// IoError::NotErased.
+/// It also counts how often each erasable block has been erased.
+#[cfg(feature = "std")]
+pub struct NorSimulator {
+    buf: RefCell<Vec<u8>>,
+    erase_counts: RefCell<Vec<u32>>,
+    erase_operation_count: Cell<u64>,
+    erasable_block_size: usize,
+    erase_sizes: Vec<usize>,
+}
+
+#[cfg(feature = "std")]
+impl NorSimulator {
+    /// Creates an entirely erased flash of CAPACITY Byte.
+    /// Note: ERASABLE_BLOCK_SIZE is assumed to be a power of two.
+    pub fn new(capacity: usize, erasable_block_size: usize) -> Self {
+        Self::from_image(vec![0xff; capacity], erasable_block_size)
+    }
+    /// Creates a flash with the contents IMAGE.
+    /// Note: ERASABLE_BLOCK_SIZE is assumed to be a power of two.
+    pub fn from_image(image: Vec<u8>, erasable_block_size: usize) -> Self {
+        assert!(erasable_block_size.is_power_of_two());
+        let block_count = image.len().div_ceil(erasable_block_size);
+        Self {
+            buf: RefCell::new(image),
+            erase_counts: RefCell::new(vec![0; block_count]),
+            erase_operation_count: Cell::new(0),
+            erasable_block_size,
+            erase_sizes: Vec::new(),
+        }
+    }
+    /// Makes the flash also support erasing ERASE_SIZES Byte at once.
+    /// Note: Each of ERASE_SIZES has to be a power-of-two multiple of the
+    /// erasable block size.
+    pub fn with_erase_sizes(mut self, erase_sizes: &[usize]) -> Self {
+        for &size in erase_sizes {
+            assert!(size.is_power_of_two() && size >= self.erasable_block_size);
+        }
+        self.erase_sizes = erase_sizes.to_vec();
+        self
+    }
+    /// in Byte
+    pub fn capacity(&self) -> usize {
+        self.buf.borrow().len()
+    }
+    pub fn into_inner(self) -> Vec<u8> {
+        self.buf.into_inner()
+    }
+    /// Returns how often the erasable block at LOCATION has been erased.
+    pub fn erase_count(&self, location: ErasableLocation) -> Result<u32> {
+        let index =
+            self.location(location)? as usize / self.erasable_block_size;
+        self.erase_counts.borrow().get(index).copied().ok_or(Error::Size)
+    }
+    /// Returns how many times erasable blocks have been erased in total.
+    pub fn total_erase_count(&self) -> u64 {
+        self.erase_counts.borrow().iter().map(|&x| u64::from(x)).sum()
+    }
+    /// Returns how many erase operations (of any size) there have been.
+    pub fn erase_operation_count(&self) -> u64 {
+        self.erase_operation_count.get()
+    }
+}
+
+#[cfg(feature = "std")]
+impl FlashRead for NorSimulator {
+    fn read_exact(&self, location: Location, buffer: &mut [u8]) -> Result<()> {
+        let buf = self.buf.borrow();
+        let range = checked_range(location, buffer.len(), buf.len()).ok_or(
+            Error::Io(IoError::Read { start: location, size: buffer.len() }),
+        )?;
+        buffer.copy_from_slice(&buf[range]);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+impl FlashAlign for NorSimulator {
+    fn erasable_block_size(&self) -> usize {
+        self.erasable_block_size
+    }
+}
+
+#[cfg(feature = "std")]
+impl FlashWrite for NorSimulator {
+    fn erase_block(&self, location: ErasableLocation) -> Result<()> {
+        self.erase(location, self.erasable_block_size)
+    }
+    fn erase_sizes(&self) -> &[usize] {
+        &self.erase_sizes
+    }
+    fn erase(&self, location: ErasableLocation, size: usize) -> Result<()> {
+        let location = self.location(location)?;
+        if size != self.erasable_block_size && !self.erase_sizes.contains(&size)
+        {
+            return Err(Error::Unsupported);
+        }
+        let mut buf = self.buf.borrow_mut();
+        let range = checked_range(location, size, buf.len())
+            .filter(|_| (location as usize).is_multiple_of(size))
+            .ok_or(Error::Io(IoError::Erase { start: location, size }))?;
+        let blocks = range.start / self.erasable_block_size
+            ..range.end / self.erasable_block_size;
+        buf[range].fill(0xff);
+        for count in &mut self.erase_counts.borrow_mut()[blocks] {
+            *count += 1;
+        }
+        self.erase_operation_count.set(self.erase_operation_count.get() + 1);
+        Ok(())
+    }
+    /// This fails (without changing anything) if it would have to change
+    /// a 0 bit to a 1 bit.
+    fn program(&self, location: Location, buffer: &[u8]) -> Result<()> {
+        let mut buf = self.buf.borrow_mut();
+        let range = checked_range(location, buffer.len(), buf.len()).ok_or(
+            Error::Io(IoError::Write { start: location, size: buffer.len() }),
+        )?;
+        let target = &mut buf[range];
+        if target.iter().zip(buffer).any(|(&old, &new)| old & new != new) {
+            return Err(Error::Io(IoError::NotErased {
+                start: location,
+                size: buffer.len(),
+            }));
+        }
+        target.copy_from_slice(buffer);
+        Ok(())
+    }
+    fn erase_and_write_block(
+        &self,
+        location: ErasableLocation,
+        buffer: &[u8],
+    ) -> Result<()> {
+        if buffer.len() > self.erasable_block_size {
+            return Err(Error::Size);
+        }
+        self.erase_block(location)?;
+        self.program(self.location(location)?, buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_flash_erase_semantics() -> Result<()> {
+        let flash = MemoryFlash::new([0u8; 64], 16);
+        flash.erase_and_write_block(flash.erasable_location(16)?, &[1; 4])?;
+        flash.erase_block(flash.erasable_location(48)?)?;
+        let storage = flash.into_inner();
+        assert_eq!(storage[..16], [0; 16]);
+        assert_eq!(storage[16..20], [1; 4]);
+        assert_eq!(storage[20..32], [0xff; 12]);
+        assert_eq!(storage[32..48], [0; 16]);
+        assert_eq!(storage[48..], [0xff; 16]);
+        Ok(())
+    }
+
+    #[test]
+    fn memory_flash_program() -> Result<()> {
+        let flash = MemoryFlash::new([0xffu8; 64], 16);
+        flash.program(14, &[1, 2, 3, 4])?;
+        assert!(flash.program(62, &[5; 4]).is_err());
+        let storage = flash.into_inner();
+        assert_eq!(storage[12..20], [0xff, 0xff, 1, 2, 3, 4, 0xff, 0xff]);
+        assert_eq!(storage[60..], [0xff; 4]);
+        Ok(())
+    }
+
+    #[test]
+    fn memory_flash_out_of_range() -> Result<()> {
+        let flash = MemoryFlash::new([0u8; 64], 16);
+        let mut buf = [0u8; 2];
+        assert!(matches!(
+            flash.read_exact(63, &mut buf),
+            Err(Error::Io(IoError::Read { start: 63, size: 2 }))
+        ));
+        assert!(matches!(
+            flash.erase_block(flash.erasable_location(64)?),
+            Err(Error::Io(IoError::Erase { start: 64, size: 16 }))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn nor_simulator_uses_largest_erase() -> Result<()> {
+        let flash = NorSimulator::new(0x4_0000, 0x1000)
+            .with_erase_sizes(&[0x8000, 0x1_0000]);
+        let data = vec![0x42; 0x2_8100];
+        flash
+            .erase_and_write_blocks(flash.erasable_location(0x7000)?, &data)?;
+        // 4 KiB at 0x7000, 32 KiB at 0x8000, 64 KiB at 0x1_0000 and
+        // 0x2_0000.
+        assert_eq!(flash.erase_operation_count(), 4);
+        assert_eq!(flash.total_erase_count(), 0x29);
+        let image = flash.into_inner();
+        assert_eq!(image[..0x7000], [0xff; 0x7000]);
+        assert_eq!(image[0x7000..0x2_f100], data[..]);
+        assert!(image[0x2_f100..].iter().all(|&x| x == 0xff));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn nor_simulator_rejects_programming_ones() -> Result<()> {
+        let flash = NorSimulator::new(64, 16);
+        flash.program(3, &[0xf0, 0x0f])?;
+        flash.program(3, &[0x30, 0x00])?;
+        assert!(matches!(
+            flash.program(4, &[0x01, 0x00]),
+            Err(Error::Io(IoError::NotErased { start: 4, size: 2 }))
+        ));
+        let mut buf = [0u8; 3];
+        flash.read_exact(3, &mut buf)?;
+        assert_eq!(buf, [0x30, 0x00, 0xff]);
+        let beginning = flash.erasable_location(0)?;
+        flash.erase_and_write_block(beginning, &[0x55; 4])?;
+        flash.erase_and_write_block(beginning, &[0xaa; 4])?;
+        assert_eq!(flash.erase_count(beginning)?, 2);
+        assert_eq!(flash.erase_count(flash.erasable_location(16)?)?, 0);
+        assert_eq!(flash.total_erase_count(), 2);
+        flash.read_exact(0, &mut buf)?;
+        assert_eq!(buf, [0xaa; 3]);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn file_flash_roundtrip() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join(format!("amd-efs-file-flash-{}.bin", std::process::id()));
+        {
+            let flash = FileFlash::create(&path, 64, 16)?;
+            assert_eq!(flash.capacity(), 64);
+            flash.erase_and_write_block(
+                flash.erasable_location(16)?,
+                &[1, 2, 3],
+            )?;
+            flash.program(19, &[4])?;
+            assert!(flash.program(63, &[5, 6]).is_err());
+            flash.flush()?;
+        }
+        let flash = FileFlash::open(&path, 16)?;
+        let mut buf = [0u8; 6];
+        flash.read_exact(15, &mut buf)?;
+        assert_eq!(buf, [0xff, 1, 2, 3, 4, 0xff]);
+        assert!(flash.read_exact(60, &mut buf).is_err());
+        std::fs::remove_file(&path).unwrap();
+        Ok(())
+    }
+}
@@ -1,6 +1,7 @@
+#[cfg(feature = "std")]
+use crate::allocators::{FlashAllocate, PlacementConstraints};
 use crate::amdfletcher32::AmdFletcher32;
 use crate::flash;
-#[cfg(feature = "std")]
 use crate::ondisk::DirectoryAdditionalInfo;
 use crate::ondisk::DirectoryEntrySerde;
 use crate::ondisk::EFH_POSITION;
@@ -9,36 +10,50 @@ use crate::ondisk::header_from_collection;
 use crate::ondisk::header_from_collection_mut;
 use crate::ondisk::{
     AddressMode, BhdDirectoryEntry, BhdDirectoryEntryType, BhdDirectoryHeader,
-    ComboDirectoryEntry, ComboDirectoryHeader, DirectoryEntry, DirectoryHeader,
-    Efh, EfhBulldozerSpiMode, EfhEspiConfiguration, EfhNaplesSpiMode,
-    EfhRomeSpiMode, PspDirectoryEntry, PspDirectoryEntryType,
-    PspDirectoryHeader, ValueOrLocation, WEAK_ADDRESS_MODE, mmio_decode,
+    BhdDirectoryRomId, COMPRESSED_PAYLOAD_HEADER_SIZE,
+    COMPRESSED_PAYLOAD_SIZE_OFFSET, ComboDirectoryEntry, ComboDirectoryHeader,
+    DirectoryEntry, DirectoryHeader, Efh, EfhBulldozerSpiMode,
+    EfhEspiConfiguration, EfhNaplesSpiMode, EfhRomeSpiMode,
+    KeyedDirectoryEntry, PspDirectoryEntry, PspDirectoryEntryType,
+    PspDirectoryHeader, PspDirectoryRomId, ValueOrLocation, WEAK_ADDRESS_MODE,
+    mmio_decode, mmio_encode,
 };
 use crate::types::Error;
 use crate::types::Result;
 
 use core::convert::TryInto;
 use core::mem::size_of;
-#[cfg(feature = "std")]
 use flash::ErasableRange;
+use flash::{AsyncFlashRead, BlockingFlash, FlashAlign};
 use flash::{ErasableLocation, FlashRead, FlashWrite, Location};
+#[cfg(feature = "std")]
+use std::collections::BTreeSet;
 use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout};
 
-// XXX: This is arbitrary.
-const MAX_DIRECTORY_ENTRIES: usize = 64;
+/// The number of entries a Directory has room for, unless specified
+/// otherwise.
+pub const DEFAULT_MAX_DIRECTORY_ENTRIES: usize = 64;
+
+/// The largest erasable block size (in Byte) that Directory::write_to
+/// supports on flash that can't program.
+pub const WRITE_TO_BLOCK_BUFFER_SIZE: usize = 0x1000;
 
-// TODO: split into Directory and DirectoryContents (disjunct) if requested in additional_info.
 pub struct Directory<
     MainHeader,
     Item: DirectoryEntry + FromBytes + IntoBytes + Immutable + KnownLayout + Default,
     const MAIN_HEADER_SIZE: usize,
     const ITEM_SIZE: usize,
+    const MAX_ENTRIES: usize = DEFAULT_MAX_DIRECTORY_ENTRIES,
 > {
-    // Heuristics: It's the beginning of a PSP directory, if we want such a
-    // connection from the directory we are actually reading.
-    // Otherwise it's 0.
-    mode3_base: Location,
+    // What OtherDirectoryRelativeOffset is relative to: The beginning of the
+    // directory that refers to this one, if any.  Otherwise (first-level
+    // directories), the beginning of this directory.
+    other_directory_beginning: Location,
     beginning: Location, // mostly to help following outward pointers
+    // Where the contents (that DirectoryRelativeOffset refers to) begin, if
+    // that's not where the directory begins (see base_address in
+    // DirectoryAdditionalInfo).
+    contents_beginning: Option<Location>,
     directory_address_mode: AddressMode,
     header: MainHeader,
     // On AMD, this field specifies how much of the memory area under
@@ -46,7 +61,7 @@ pub struct Directory<
     // Flash. This is used in order to store pointers to other
     // areas on Flash (with ValueOrLocation::PhysicalAddress).
     amd_physical_mode_mmio_size: Option<u32>,
-    entries: [Item; MAX_DIRECTORY_ENTRIES],
+    entries: [Item; MAX_ENTRIES],
 }
 
 impl<
@@ -68,7 +83,8 @@ impl<
         + Default,
     const MAIN_HEADER_SIZE: usize,
     const ITEM_SIZE: usize,
-> Directory<MainHeader, Item, MAIN_HEADER_SIZE, ITEM_SIZE>
+    const MAX_ENTRIES: usize,
+> Directory<MainHeader, Item, MAIN_HEADER_SIZE, ITEM_SIZE, MAX_ENTRIES>
 {
     pub fn header(&self) -> MainHeader {
         self.header
@@ -76,6 +92,22 @@ impl<
     pub fn beginning(&self) -> Location {
         self.beginning
     }
+    /// Returns what OtherDirectoryRelativeOffset is relative to.
+    pub fn other_directory_beginning(&self) -> Location {
+        self.other_directory_beginning
+    }
+    /// Returns where the contents of the directory begin--that is, what
+    /// DirectoryRelativeOffset is relative to.
+    pub fn contents_beginning(&self) -> Location {
+        self.contents_beginning.unwrap_or(self.beginning)
+    }
+    /// Sets where the contents of the directory begin, if that's not where
+    /// the directory begins.
+    /// Note: This changes what existing DirectoryRelativeOffset sources
+    /// refer to.
+    pub fn set_contents_beginning(&mut self, value: Option<Location>) {
+        self.contents_beginning = value;
+    }
     pub fn directory_address_mode(&self) -> AddressMode {
         self.directory_address_mode
     }
@@ -89,18 +121,83 @@ impl<
             .ok_or(Error::DirectoryRangeCheck)
     }
 
+    /// Returns the size of the directory on flash: the max_size it
+    /// specifies, if any--otherwise the size of its header and entries.
+    pub fn directory_size(&self) -> Result<usize> {
+        let additional_info = self.header.additional_info();
+        let max_size = additional_info.max_size();
+        if u32::from(additional_info) != 0xffff_ffff && max_size != 0 {
+            Ok(usize::from(max_size) * DirectoryAdditionalInfo::UNIT)
+        } else {
+            Self::minimal_directory_size(self.header.total_entries() as usize)
+        }
+    }
+
     /// Note: Caller should check whether it is the right cookie (afterwards)
     /// This is only used to load the second-level directory when dumping.
     /// There are nicer accessors otherwise (bhd_directory, psp_directory etc)
+    /// OTHER_DIRECTORY_BEGINNING is what OtherDirectoryRelativeOffset is
+    /// relative to: the beginning of the directory that refers to the one
+    /// at BEGINNING (see AddressMode)--or, for a first-level directory,
+    /// BEGINNING itself.
     pub fn load<T: FlashRead>(
         storage: &T,
         beginning: Location,
-        mode3_base: Location,
+        other_directory_beginning: Location,
+        amd_physical_mode_mmio_size: Option<u32>,
+    ) -> Result<Self> {
+        flash::run_ready(Self::load_async(
+            &BlockingFlash(storage),
+            beginning,
+            other_directory_beginning,
+            amd_physical_mode_mmio_size,
+        ))
+    }
+
+    /// Like load, but does not verify the checksum.  This is meant for
+    /// inspecting corrupted directories.
+    pub fn load_unchecked<T: FlashRead>(
+        storage: &T,
+        beginning: Location,
+        other_directory_beginning: Location,
+        amd_physical_mode_mmio_size: Option<u32>,
+    ) -> Result<Self> {
+        flash::run_ready(Self::load_async_with_verification(
+            &BlockingFlash(storage),
+            beginning,
+            other_directory_beginning,
+            amd_physical_mode_mmio_size,
+            false,
+        ))
+    }
+
+    /// Like load, but for flash that is accessed asynchronously.
+    pub async fn load_async<T: AsyncFlashRead>(
+        storage: &T,
+        beginning: Location,
+        other_directory_beginning: Location,
+        amd_physical_mode_mmio_size: Option<u32>,
+    ) -> Result<Self> {
+        Self::load_async_with_verification(
+            storage,
+            beginning,
+            other_directory_beginning,
+            amd_physical_mode_mmio_size,
+            true,
+        )
+        .await
+    }
+
+    async fn load_async_with_verification<T: AsyncFlashRead>(
+        storage: &T,
+        beginning: Location,
+        other_directory_beginning: Location,
         amd_physical_mode_mmio_size: Option<u32>,
+        verify_checksum: bool,
     ) -> Result<Self> {
         let mut buf: [u8; MAIN_HEADER_SIZE] = [0xff; MAIN_HEADER_SIZE];
         assert_eq!(MAIN_HEADER_SIZE, size_of::<MainHeader>());
-        storage.read_exact(beginning, &mut buf)?;
+        storage.read_exact(beginning, &mut buf).await?;
         let header = header_from_collection::<MainHeader>(&buf[..])
             .ok_or(Error::Marshal)?;
         let cookie = header.cookie();
@@ -108,113 +205,118 @@ impl<
             return Err(Error::DirectoryTypeMismatch);
         }
         let directory_address_mode = header.additional_info().address_mode();
-        let directory_type_matches = matches!(
-            directory_address_mode,
-            AddressMode::PhysicalAddress
-                | AddressMode::EfsRelativeOffset
-                | AddressMode::DirectoryRelativeOffset
-        );
-        if !directory_type_matches {
-            return Err(Error::DirectoryTypeMismatch);
+        let total_entries = header.total_entries() as usize;
+        if total_entries > MAX_ENTRIES {
+            return Err(Error::DirectoryRangeCheck);
         }
-        let mut entries = [Item::default(); MAX_DIRECTORY_ENTRIES];
+        let mut entries = [Item::default(); MAX_ENTRIES];
         let mut cursor = beginning
             .checked_add(MAIN_HEADER_SIZE as u32)
             .ok_or(Error::DirectoryRangeCheck)?;
-        for (i, ie) in
-            entries.iter_mut().enumerate().take(header.total_entries() as usize)
-        {
-            if i >= MAX_DIRECTORY_ENTRIES {
-                return Err(Error::DirectoryRangeCheck);
-            }
+        for ie in entries.iter_mut().take(total_entries) {
             let mut buf: [u8; ITEM_SIZE] = [0xff; ITEM_SIZE];
             assert_eq!(ITEM_SIZE, size_of::<Item>()); // TODO: move to compile-time
-            storage.read_exact(cursor, &mut buf)?;
+            storage.read_exact(cursor, &mut buf).await?;
             cursor = cursor
                 .checked_add(ITEM_SIZE as u32)
                 .ok_or(Error::DirectoryRangeCheck)?;
             *ie = *header_from_collection::<Item>(&buf[..])
                 .ok_or(Error::Marshal)?;
         }
-        Ok(Self {
+        let additional_info = header.additional_info();
+        let base_address = additional_info.base_address();
+        let contents_beginning = if u32::from(additional_info) != 0xffff_ffff
+            && base_address != 0
+        {
+            Some(u32::from(base_address) * DirectoryAdditionalInfo::UNIT as u32)
+        } else {
+            None
+        };
+        let result = Self {
             beginning,
-            mode3_base,
+            contents_beginning,
+            other_directory_beginning,
             directory_address_mode,
             header: *header,
             amd_physical_mode_mmio_size,
             entries,
-        })
+        };
+        if verify_checksum {
+            let expected = result.header.checksum();
+            let actual = result.compute_checksum();
+            if expected != actual {
+                return Err(Error::ChecksumMismatch { expected, actual });
+            }
+        }
+        Ok(result)
     }
     fn create(
         beginning: Location,
-        mode3_base: Location,
+        other_directory_beginning: Location,
         directory_address_mode: AddressMode,
         cookie: [u8; 4],
         amd_physical_mode_mmio_size: Option<u32>,
         entries: &[Item],
     ) -> Result<Self> {
-        // This DIRECTORY mode is currently unsupported by PSP ABL.
-        if directory_address_mode == AddressMode::OtherDirectoryRelativeOffset {
-            return Err(Error::DirectoryTypeMismatch);
-        }
         let mut header = MainHeader::default();
         header.set_cookie(cookie);
         let mut result = Self {
             beginning,
-            mode3_base,
+            contents_beginning: None,
+            other_directory_beginning,
             directory_address_mode,
             header,
             amd_physical_mode_mmio_size,
-            entries: [Item::default(); MAX_DIRECTORY_ENTRIES],
+            entries: [Item::default(); MAX_ENTRIES],
         };
         for entry in entries {
             result.add_entry_direct(entry)?;
         }
         Ok(result)
     }
-    /// Updates the main header checksum.  Also updates total_entries (in the same header) to TOTAL_ENTRIES.
+    /// Computes the checksum over the main header (after the checksum
+    /// field) and all the entries.
+    fn compute_checksum(&self) -> u32 {
+        let mut checksummer = AmdFletcher32::new();
+        // Skip fields "signature" and "checksum"
+        let header = &self.header.as_bytes()[8..];
+        let total_entries = self.header.total_entries() as usize;
+        assert!(ITEM_SIZE.is_multiple_of(2));
+        // TODO: Optimize performance
+        header
+            .chunks(2)
+            .chain(self.entries[..total_entries].as_bytes().chunks(2))
+            .map(|bytes| u16::from_le_bytes(bytes.try_into().unwrap()))
+            .for_each(|item: u16| checksummer.update(&[item]));
+        checksummer.value().value()
+    }
+    /// Updates total_entries in the main header to TOTAL_ENTRIES, and then
+    /// updates the main header checksum.
     /// Precondition: Since the checksum is over the entire directory, that means that all the directory entries needs to be correct already.
-    #[allow(dead_code)]
     fn update_main_header(&mut self, total_entries: u32) -> Result<()> {
-        let mut checksummer = AmdFletcher32::new();
-        //let mut skip: usize = 12; // Skip fields "signature", "checksum" and "total_entries"
-        checksummer.update(&[
-            (total_entries & 0xffff) as u16,
-            (total_entries >> 16) as u16,
-        ]);
-        let additional_info = u32::from(self.header.additional_info());
-        checksummer.update(&[
-            (additional_info & 0xffff) as u16,
-            (additional_info >> 16) as u16,
-        ]);
-        assert!(ITEM_SIZE % 2 == 0);
-        for i in 0..(self.header.total_entries() as usize) {
-            let entry = &self.entries[i];
-            let bytes = entry.as_bytes();
-            let block = bytes
-                .chunks(2)
-                .map(|bytes| u16::from_le_bytes(bytes.try_into().unwrap()));
-            // TODO: Optimize performance
-            block.clone().for_each(|item: u16| checksummer.update(&[item]));
-        }
-
-        let checksum = checksummer.value().value();
+        self.header.set_total_entries(total_entries);
+        let checksum = self.compute_checksum();
         self.header.set_checksum(checksum);
         Ok(())
     }
-    #[cfg(feature = "std")]
-    pub fn save(
+    /// Updates the main header so that it describes the directory in RANGE.
+    fn prepare_header(
         &mut self,
         erasable_block_size: usize,
         range: &ErasableRange,
-        payloads_beginning: ErasableLocation,
-    ) -> Result<Vec<u8>> {
+        address_mode: AddressMode,
+    ) -> Result<()> {
         let cookie = self.header.cookie();
         if !MainHeader::ALLOWED_COOKIES.contains(&cookie) {
             return Err(Error::DirectoryTypeMismatch);
         }
 
         let total_entries = self.header.total_entries();
+        if Self::minimal_directory_size(total_entries as usize)?
+            > range.capacity()
+        {
+            return Err(Error::DirectoryRangeCheck);
+        }
         //let additional_info = self.header.additional_info();
         let additional_info = DirectoryAdditionalInfo::new()
             .with_max_size_checked(
@@ -227,20 +329,39 @@ impl<
                     .ok_or(Error::DirectoryRangeCheck)?,
             )
             .map_err(|_| Error::DirectoryRangeCheck)?
-            .with_base_address(
-                DirectoryAdditionalInfo::try_into_unit(
-                    Location::from(payloads_beginning)
-                        .try_into()
-                        .map_err(|_| Error::DirectoryRangeCheck)?,
-                )
-                // If that happens it's likely that your payload flash_location
-                // is not aligned 4 kiB.
-                .ok_or(Error::DirectoryPayloadMisaligned)?,
-            )
-            .with_address_mode(AddressMode::EfsRelativeOffset);
-        self.header.set_additional_info(additional_info);
+            .with_base_address(match self.contents_beginning {
+                None => 0,
+                Some(contents_beginning) => {
+                    DirectoryAdditionalInfo::try_into_unit(
+                        contents_beginning
+                            .try_into()
+                            .map_err(|_| Error::DirectoryRangeCheck)?,
+                    )
+                    // If that happens it's likely that your payload
+                    // flash_location is not aligned 4 kiB.
+                    .ok_or(Error::DirectoryPayloadMisaligned)?
+                }
+            })
+            .with_address_mode(address_mode);
         self.header.set_additional_info(additional_info);
-        self.update_main_header(total_entries)?;
+        self.update_main_header(total_entries)
+    }
+    /// Returns the serialized directory (for RANGE), with the contents
+    /// beginning at PAYLOADS_BEGINNING.
+    #[cfg(feature = "std")]
+    pub fn save(
+        &mut self,
+        erasable_block_size: usize,
+        range: &ErasableRange,
+        payloads_beginning: ErasableLocation,
+    ) -> Result<Vec<u8>> {
+        self.contents_beginning = Some(payloads_beginning.into());
+        self.prepare_header(
+            erasable_block_size,
+            range,
+            AddressMode::EfsRelativeOffset,
+        )?;
+        let total_entries = self.header.total_entries();
         //let size = Self::minimal_directory_size(total_entries)?;
         //let _ = range.take_at_least(size as usize);
         let mut result = Vec::<u8>::new();
@@ -250,6 +371,56 @@ impl<
         }
         Ok(result)
     }
+    /// Erases RANGE on STORAGE and writes the directory (with updated
+    /// checksum and additional info) to the beginning of it.
+    /// If STORAGE doesn't support program, the directory is written by
+    /// read-modify-write instead--which needs the erasable block size of
+    /// STORAGE to be at most WRITE_TO_BLOCK_BUFFER_SIZE.
+    pub fn write_to(
+        &mut self,
+        storage: &impl FlashWrite,
+        range: &ErasableRange,
+    ) -> Result<()> {
+        let erasable_block_size = storage.erasable_block_size();
+        self.prepare_header(
+            erasable_block_size,
+            range,
+            self.directory_address_mode,
+        )?;
+        let mut location = range.beginning;
+        while Location::from(location) < Location::from(range.end) {
+            storage.erase_block(location)?;
+            location = location.advance(erasable_block_size)?;
+        }
+        let beginning = storage.location(range.beginning)?;
+        let total_entries = self.header.total_entries() as usize;
+        let entries_beginning = beginning + MAIN_HEADER_SIZE as Location;
+        let entries = self.entries[..total_entries].as_bytes();
+        match storage.program(beginning, self.header.as_bytes()) {
+            Err(flash::Error::Unsupported) => {
+                let mut block_buffer = [0xff; WRITE_TO_BLOCK_BUFFER_SIZE];
+                let block_buffer = block_buffer
+                    .get_mut(..erasable_block_size)
+                    .ok_or(flash::Error::Size)?;
+                storage.write_preserving_with_buffer(
+                    beginning,
+                    self.header.as_bytes(),
+                    block_buffer,
+                )?;
+                storage.write_preserving_with_buffer(
+                    entries_beginning,
+                    entries,
+                    block_buffer,
+                )?;
+            }
+            result => {
+                result?;
+                storage.program(entries_beginning, entries)?;
+            }
+        }
+        self.beginning = beginning;
+        Ok(())
+    }
     pub fn entries(&self) -> impl Iterator<Item = Item> + '_ {
         let mut index = 0usize;
         core::iter::from_fn(move || {
@@ -289,7 +460,7 @@ impl<
             }
             ValueOrLocation::EfsRelativeOffset(x) => Ok(x),
             ValueOrLocation::DirectoryRelativeOffset(y) => Ok(self
-                .beginning
+                .contents_beginning()
                 .checked_add(y)
                 .ok_or(Error::DirectoryPayloadRangeCheck)?),
             ValueOrLocation::OtherDirectoryRelativeOffset(y) => Ok(y
@@ -299,7 +470,397 @@ impl<
     }
     pub fn payload_beginning(&self, entry: &Item) -> Result<Location> {
         let source = entry.source(self.directory_address_mode)?;
-        self.location_of_source(source, self.mode3_base)
+        self.location_of_source(source, self.other_directory_beginning)
+    }
+
+    /// Returns a source in the same address mode as SOURCE, but pointing
+    /// to LOCATION instead.  This is the inverse of location_of_source.
+    pub fn source_at_location(
+        &self,
+        source: &ValueOrLocation,
+        location: Location,
+        entry_base_location: Location,
+    ) -> Result<ValueOrLocation> {
+        Ok(match source {
+            ValueOrLocation::Value(_) => {
+                return Err(Error::DirectoryTypeMismatch);
+            }
+            ValueOrLocation::PhysicalAddress(_) => {
+                ValueOrLocation::PhysicalAddress(mmio_encode(
+                    location,
+                    self.amd_physical_mode_mmio_size,
+                )?)
+            }
+            ValueOrLocation::EfsRelativeOffset(_) => {
+                ValueOrLocation::EfsRelativeOffset(location)
+            }
+            ValueOrLocation::DirectoryRelativeOffset(_) => {
+                ValueOrLocation::DirectoryRelativeOffset(
+                    location
+                        .checked_sub(self.contents_beginning())
+                        .ok_or(Error::DirectoryPayloadRangeCheck)?,
+                )
+            }
+            ValueOrLocation::OtherDirectoryRelativeOffset(_) => {
+                ValueOrLocation::OtherDirectoryRelativeOffset(
+                    location
+                        .checked_sub(entry_base_location)
+                        .ok_or(Error::DirectoryPayloadRangeCheck)?,
+                )
+            }
+        })
+    }
+
+    /// Makes the entry at INDEX refer to a payload at LOCATION (in the
+    /// address mode it had).
+    #[cfg(feature = "std")]
+    fn set_payload_beginning(
+        &mut self,
+        index: usize,
+        location: Location,
+    ) -> Result<()> {
+        let source = self.source_at_location(
+            &self.entries[index].source(self.directory_address_mode)?,
+            location,
+            self.other_directory_beginning,
+        )?;
+        self.entries[index].set_source(self.directory_address_mode, source)
+    }
+
+    /// Makes the directory begin at BEGINNING, with the directory that
+    /// refers to it at OTHER_DIRECTORY_BEGINNING (see load), without
+    /// changing what its entries refer to: DirectoryRelativeOffset stays
+    /// relative to where the contents began before, and
+    /// OtherDirectoryRelativeOffset sources are updated.
+    /// This doesn't write anything; see write_to.
+    #[cfg(feature = "std")]
+    fn move_to(
+        &mut self,
+        beginning: Location,
+        other_directory_beginning: Location,
+    ) -> Result<()> {
+        let mut directory_relative = false;
+        let mut other_directory_relative = Vec::new();
+        for (index, entry) in self.entries().enumerate() {
+            match entry.source(self.directory_address_mode) {
+                Ok(ValueOrLocation::DirectoryRelativeOffset(_)) => {
+                    directory_relative = true;
+                }
+                Ok(ValueOrLocation::OtherDirectoryRelativeOffset(_)) => {
+                    other_directory_relative
+                        .push((index, self.payload_beginning(&entry)?));
+                }
+                _ => {}
+            }
+        }
+        if directory_relative {
+            self.contents_beginning = Some(self.contents_beginning());
+        }
+        self.beginning = beginning;
+        self.other_directory_beginning = other_directory_beginning;
+        for (index, location) in other_directory_relative {
+            self.set_payload_beginning(index, location)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the directory to RANGE on STORAGE instead of where it was
+    /// (see move_to and write_to), with the entries at the indices in
+    /// SUBDIRECTORIES referring to the respective locations instead.
+    #[cfg(feature = "std")]
+    fn rewrite_to<T: FlashWrite>(
+        &mut self,
+        storage: &T,
+        range: &ErasableRange,
+        other_directory_beginning: Location,
+        subdirectories: &[(usize, Location)],
+    ) -> Result<()> {
+        self.move_to(range.beginning.into(), other_directory_beginning)?;
+        for &(index, location) in subdirectories {
+            self.set_payload_beginning(index, location)?;
+        }
+        self.write_to(storage, range)
+    }
+
+    /// Returns a source, in the address mode of the directory, that
+    /// points to LOCATION.
+    pub fn source_for_location(
+        &self,
+        location: Location,
+    ) -> Result<ValueOrLocation> {
+        let source = match self.directory_address_mode {
+            AddressMode::PhysicalAddress => ValueOrLocation::PhysicalAddress(0),
+            AddressMode::EfsRelativeOffset => {
+                ValueOrLocation::EfsRelativeOffset(0)
+            }
+            AddressMode::DirectoryRelativeOffset => {
+                ValueOrLocation::DirectoryRelativeOffset(0)
+            }
+            AddressMode::OtherDirectoryRelativeOffset => {
+                ValueOrLocation::OtherDirectoryRelativeOffset(0)
+            }
+        };
+        self.source_at_location(
+            &source,
+            location,
+            self.other_directory_beginning,
+        )
+    }
+
+    /// Writes DATA to RANGE on STORAGE and adds ENTRY (with size set to
+    /// SIZE and source updated accordingly).  If that fails, the directory is left
+    /// as it was.
+    /// Returns the entry that was added.
+    #[cfg(feature = "std")]
+    fn add_payload<T: FlashWrite>(
+        &mut self,
+        storage: &T,
+        entry: &Item,
+        data: &[u8],
+        size: usize,
+        range: ErasableRange,
+    ) -> Result<Item>
+    where
+        Item: KeyedDirectoryEntry,
+    {
+        if self.position(&entry.key()?).is_some() {
+            return Err(Error::Duplicate);
+        }
+        let mut entry = *entry;
+        entry.set_size(Some(
+            u32::try_from(size)
+                .map_err(|_| Error::DirectoryPayloadRangeCheck)?,
+        ));
+        entry.set_source(
+            self.directory_address_mode,
+            self.source_for_location(range.beginning.into())?,
+        )?;
+        storage.erase_and_write_blocks(range.beginning, data)?;
+        self.add_entry(&entry)?;
+        Ok(entry)
+    }
+
+    /// Copies the payloads of the entries for which CONSTRAINTS returns
+    /// Some to the free range (taken from ALLOCATOR) nearest to the
+    /// beginning of STORAGE, if that is before them, and updates the
+    /// entries accordingly (only in memory).
+    /// Payloads that overlap more ranges in USED than their own, and
+    /// payloads that cannot be located, are not moved.
+    /// ALLOCATOR needs to support release.
+    /// Returns the ranges the payloads were moved away from--without
+    /// releasing them, since other payloads can share their erase blocks.
+    #[cfg(feature = "std")]
+    fn compact_payloads<T: FlashWrite>(
+        &mut self,
+        storage: &T,
+        allocator: &mut impl FlashAllocate,
+        used: &[(Location, usize)],
+        efh_beginning: Location,
+        constraints: impl Fn(&Item) -> Option<PlacementConstraints>,
+    ) -> Result<Vec<ErasableRange>> {
+        let arena_beginning = storage.erasable_location(0)?;
+        let mut vacated = Vec::new();
+        for i in 0..self.header.total_entries() as usize {
+            let entry = self.entries[i];
+            let Some(constraints) = constraints(&entry) else {
+                continue;
+            };
+            let Ok(size) = self.payload_extent(storage, &entry) else {
+                continue;
+            };
+            let Ok(beginning) = self.payload_beginning(&entry) else {
+                continue;
+            };
+            let end = u64::from(beginning) + size as u64;
+            let overlapping = used
+                .iter()
+                .filter(|&&(used_beginning, used_size)| {
+                    used_size > 0
+                        && u64::from(used_beginning) < end
+                        && u64::from(used_beginning) + used_size as u64
+                            > u64::from(beginning)
+                })
+                .count();
+            if overlapping > 1 {
+                continue;
+            }
+            let Ok(old_beginning) = storage.erasable_location(beginning) else {
+                continue;
+            };
+            let old = ErasableRange::new(
+                old_beginning,
+                old_beginning.advance_at_least(size)?,
+            );
+            // Shrink the arena until nothing fits anymore, so the payload
+            // ends up as near to the beginning as possible.
+            let mut new = None;
+            let mut arena_end = old_beginning;
+            while let Some(range) = constraints.take_at_least(
+                allocator,
+                size,
+                &ErasableRange::new(arena_beginning, arena_end),
+                efh_beginning,
+            ) {
+                arena_end = range.beginning;
+                if let Some(previous) = new.replace(range) {
+                    allocator.release(previous);
+                }
+            }
+            let Some(new) = new else {
+                continue;
+            };
+            let mut buf = vec![0xff; size];
+            storage.read_exact(beginning, &mut buf)?;
+            storage.erase_and_write_blocks(new.beginning, &buf)?;
+            self.set_payload_beginning(i, new.beginning.into())?;
+            vacated.push(old);
+        }
+        Ok(vacated)
+    }
+
+    /// Returns how many entries the directory has room for: MAX_ENTRIES,
+    /// or less if the max_size it specifies doesn't allow for that many.
+    pub fn max_entries(&self) -> usize {
+        let additional_info = self.header.additional_info();
+        let max_size = additional_info.max_size();
+        if u32::from(additional_info) != 0xffff_ffff && max_size != 0 {
+            let max_size =
+                usize::from(max_size) * DirectoryAdditionalInfo::UNIT;
+            MAX_ENTRIES.min(
+                max_size.saturating_sub(size_of::<MainHeader>())
+                    / size_of::<Item>(),
+            )
+        } else {
+            MAX_ENTRIES
+        }
+    }
+
+    /// Returns how many Byte the payload of ENTRY takes up on STORAGE.
+    /// For compressed payloads, that's the size of the compressed payload
+    /// header plus the size of the zlib stream (as specified in that
+    /// header)--not the size of ENTRY.
+    pub fn payload_extent<T: FlashRead>(
+        &self,
+        storage: &T,
+        entry: &Item,
+    ) -> Result<usize> {
+        let size = entry.size().ok_or(Error::EntryTypeMismatch)?;
+        if !entry.payload_compressed() {
+            return Ok(size as usize);
+        }
+        let beginning = self.payload_beginning(entry)?;
+        let mut buf = [0u8; 4];
+        storage
+            .read_exact(
+                beginning
+                    .checked_add(COMPRESSED_PAYLOAD_SIZE_OFFSET as u32)
+                    .ok_or(Error::DirectoryPayloadRangeCheck)?,
+                &mut buf,
+            )
+            .map_err(|_| Error::DirectoryPayloadRangeCheck)?;
+        (u32::from_le_bytes(buf) as usize)
+            .checked_add(COMPRESSED_PAYLOAD_HEADER_SIZE)
+            .ok_or(Error::DirectoryPayloadRangeCheck)
+    }
+
+    /// Returns a reader for the payload of ENTRY on STORAGE.
+    /// The reader reads the payload as it is on STORAGE--that is, for
+    /// compressed payloads, it reads the header and the zlib stream.
+    /// Fails if the payload overlaps the directory (including the space
+    /// reserved for it by max_size), or is not entirely on STORAGE.
+    /// Note: STORAGE has to be the flash chip that ENTRY's rom_id refers
+    /// to; Efs::psp_payload_reader and Efs::bhd_payload_reader resolve it.
+    pub fn payload_reader<'s, T: FlashRead>(
+        &self,
+        storage: &'s T,
+        entry: &Item,
+    ) -> Result<PayloadReader<'s, T>> {
+        let size = self.payload_extent(storage, entry)?;
+        let beginning = self.payload_beginning(entry)?;
+        let end = beginning
+            .checked_add(
+                size.try_into()
+                    .map_err(|_| Error::DirectoryPayloadRangeCheck)?,
+            )
+            .ok_or(Error::DirectoryPayloadRangeCheck)?;
+        let directory_end = self
+            .beginning
+            .checked_add(
+                self.directory_size()?
+                    .try_into()
+                    .map_err(|_| Error::DirectoryRangeCheck)?,
+            )
+            .ok_or(Error::DirectoryRangeCheck)?;
+        if beginning < directory_end && end > self.beginning {
+            return Err(Error::DirectoryPayloadRangeCheck);
+        }
+        if size > 0 {
+            // Make sure that the payload is not beyond the end of the flash.
+            let mut buf = [0u8; 1];
+            storage
+                .read_exact(end - 1, &mut buf)
+                .map_err(|_| Error::DirectoryPayloadRangeCheck)?;
+        }
+        Ok(PayloadReader { storage, beginning, size, position: 0 })
+    }
+
+    /// Reads the payload of ENTRY on STORAGE into the beginning of BUF.
+    /// Compressed payloads are decompressed (which needs feature "std").
+    /// Returns the (uncompressed) size of the payload.
+    /// Note: STORAGE has to be the flash chip that ENTRY's rom_id refers
+    /// to; Efs::read_psp_payload and Efs::read_bhd_payload resolve it.
+    pub fn read_payload<T: FlashRead>(
+        &self,
+        storage: &T,
+        entry: &Item,
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        if entry.payload_compressed() {
+            return self.read_compressed_payload(storage, entry, buf);
+        }
+        let mut reader = self.payload_reader(storage, entry)?;
+        let size = reader.size();
+        let buf =
+            buf.get_mut(..size).ok_or(Error::DirectoryPayloadRangeCheck)?;
+        reader.read_exact(buf)?;
+        Ok(size)
+    }
+
+    #[cfg(feature = "std")]
+    fn read_compressed_payload<T: FlashRead>(
+        &self,
+        storage: &T,
+        entry: &Item,
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        let size = entry.size().ok_or(Error::EntryTypeMismatch)? as usize;
+        let mut reader = self.payload_reader(storage, entry)?;
+        let mut compressed = vec![0xff; reader.size()];
+        reader.read_exact(&mut compressed)?;
+        let stream = compressed
+            .get(COMPRESSED_PAYLOAD_HEADER_SIZE..)
+            .ok_or(Error::Compression)?;
+        let data = miniz_oxide::inflate::decompress_to_vec_zlib_with_limit(
+            stream, size,
+        )
+        .map_err(|_| Error::Compression)?;
+        if data.len() != size {
+            return Err(Error::Compression);
+        }
+        buf.get_mut(..size)
+            .ok_or(Error::DirectoryPayloadRangeCheck)?
+            .copy_from_slice(&data);
+        Ok(size)
+    }
+
+    #[cfg(not(feature = "std"))]
+    fn read_compressed_payload<T: FlashRead>(
+        &self,
+        _storage: &T,
+        _entry: &Item,
+        _buf: &mut [u8],
+    ) -> Result<usize> {
+        Err(Error::Compression)
     }
 
     pub(crate) fn add_entry_direct(&mut self, entry: &Item) -> Result<()> {
@@ -308,46 +869,167 @@ impl<
             .total_entries()
             .checked_add(1)
             .ok_or(Error::DirectoryRangeCheck)?;
+        if total_entries as usize > self.max_entries() {
+            return Err(Error::DirectoryRangeCheck);
+        }
         self.entries[total_entries as usize - 1] = *entry;
         self.header.set_total_entries(total_entries);
         Ok(())
     }
+
+    /// Returns the index of the entry with key KEY, if any.
+    fn position(&self, key: &Item::Key) -> Option<usize>
+    where
+        Item: KeyedDirectoryEntry,
+    {
+        self.entries().position(|entry| entry.key().ok().as_ref() == Some(key))
+    }
+
+    /// Adds ENTRY to the directory, unless there's an entry with the same
+    /// key already.
+    pub fn add_entry(&mut self, entry: &Item) -> Result<()>
+    where
+        Item: KeyedDirectoryEntry,
+    {
+        if self.position(&entry.key()?).is_some() {
+            return Err(Error::Duplicate);
+        }
+        self.add_entry_direct(entry)
+    }
+
+    /// Returns the entry with key KEY.
+    /// Note: The checksum is updated when the directory is written.
+    pub fn entry_mut(&mut self, key: &Item::Key) -> Result<&mut Item>
+    where
+        Item: KeyedDirectoryEntry,
+    {
+        let index = self.position(key).ok_or(Error::EntryNotFound)?;
+        Ok(&mut self.entries[index])
+    }
+
+    /// Replaces the entry with the same key as ENTRY by ENTRY.
+    /// Returns the entry that was replaced.
+    pub fn replace_entry(&mut self, entry: &Item) -> Result<Item>
+    where
+        Item: KeyedDirectoryEntry,
+    {
+        let entry_mut = self.entry_mut(&entry.key()?)?;
+        Ok(core::mem::replace(entry_mut, *entry))
+    }
+
+    /// Removes the entry with key KEY, keeping the order of the other
+    /// entries.  Returns the entry that was removed.
+    pub fn remove_entry(&mut self, key: &Item::Key) -> Result<Item>
+    where
+        Item: KeyedDirectoryEntry,
+    {
+        let index = self.position(key).ok_or(Error::EntryNotFound)?;
+        let total_entries = self.header.total_entries() as usize;
+        let result = self.entries[index];
+        self.entries.copy_within(index + 1..total_entries, index);
+        self.entries[total_entries - 1] = Item::default();
+        self.header.set_total_entries(total_entries as u32 - 1);
+        Ok(result)
+    }
+}
+
+/// Returns DATA as a compressed payload: a compressed payload header
+/// followed by the zlib stream.
+#[cfg(feature = "std")]
+fn compress_payload(data: &[u8]) -> Result<Vec<u8>> {
+    let stream = miniz_oxide::deflate::compress_to_vec_zlib(data, 6);
+    let stream_size =
+        u32::try_from(stream.len()).map_err(|_| Error::Compression)?;
+    let mut result = vec![0u8; COMPRESSED_PAYLOAD_HEADER_SIZE];
+    result[COMPRESSED_PAYLOAD_SIZE_OFFSET..COMPRESSED_PAYLOAD_SIZE_OFFSET + 4]
+        .copy_from_slice(&stream_size.to_le_bytes());
+    result.extend_from_slice(&stream);
+    Ok(result)
+}
+
+/// Reads a payload (in chunks), without ever reading beyond its end.
+pub struct PayloadReader<'a, T> {
+    storage: &'a T,
+    beginning: Location,
+    size: usize,
+    position: usize,
+}
+
+impl<T: FlashRead> PayloadReader<'_, T> {
+    pub fn beginning(&self) -> Location {
+        self.beginning
+    }
+    /// in Byte
+    pub fn size(&self) -> usize {
+        self.size
+    }
+    /// in Byte
+    pub fn remaining(&self) -> usize {
+        self.size - self.position
+    }
+    /// Reads up to BUF.len() Byte of the payload.  Returns how many Byte
+    /// were read--which is 0 once the end of the payload is reached.
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let size = buf.len().min(self.remaining());
+        let location = self
+            .beginning
+            .checked_add(self.position as Location)
+            .ok_or(Error::DirectoryPayloadRangeCheck)?;
+        self.storage.read_exact(location, &mut buf[..size])?;
+        self.position += size;
+        Ok(size)
+    }
+    /// Reads exactly BUF.len() Byte of the payload.
+    pub fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
+        if buf.len() > self.remaining() {
+            return Err(Error::DirectoryPayloadRangeCheck);
+        }
+        self.read(buf)?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: FlashRead> std::io::Read for PayloadReader<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        PayloadReader::read(self, buf).map_err(std::io::Error::other)
+    }
 }
 
-pub type PspDirectory = Directory<
+pub type PspDirectoryWithCapacity<const MAX_ENTRIES: usize> = Directory<
     PspDirectoryHeader,
     PspDirectoryEntry,
     { size_of::<PspDirectoryHeader>() },
     { size_of::<PspDirectoryEntry>() },
+    MAX_ENTRIES,
 >;
-pub type BhdDirectory = Directory<
+pub type BhdDirectoryWithCapacity<const MAX_ENTRIES: usize> = Directory<
     BhdDirectoryHeader,
     BhdDirectoryEntry,
     { size_of::<BhdDirectoryHeader>() },
     { size_of::<BhdDirectoryEntry>() },
+    MAX_ENTRIES,
 >;
-pub type ComboDirectory = Directory<
+pub type ComboDirectoryWithCapacity<const MAX_ENTRIES: usize> = Directory<
     ComboDirectoryHeader,
     ComboDirectoryEntry,
     { size_of::<ComboDirectoryHeader>() },
     { size_of::<ComboDirectoryEntry>() },
+    MAX_ENTRIES,
 >;
+pub type PspDirectory = PspDirectoryWithCapacity<DEFAULT_MAX_DIRECTORY_ENTRIES>;
+pub type BhdDirectory = BhdDirectoryWithCapacity<DEFAULT_MAX_DIRECTORY_ENTRIES>;
+pub type ComboDirectory =
+    ComboDirectoryWithCapacity<DEFAULT_MAX_DIRECTORY_ENTRIES>;
 
-impl
-    Directory<
-        PspDirectoryHeader,
-        PspDirectoryEntry,
-        { size_of::<PspDirectoryHeader>() },
-        { size_of::<PspDirectoryEntry>() },
-    >
-{
+impl<const MAX_ENTRIES: usize> PspDirectoryWithCapacity<MAX_ENTRIES> {
     // TODO: Type-check value
     pub fn add_value_entry(
         &mut self,
         entry: &mut PspDirectoryEntry,
     ) -> Result<()> {
         if let ValueOrLocation::Value(_) = entry.source(WEAK_ADDRESS_MODE)? {
-            self.add_entry_direct(entry)?;
+            self.add_entry(entry)?;
             Ok(())
         } else {
             Err(Error::EntryTypeMismatch)
@@ -366,26 +1048,110 @@ pub const fn preferred_efh_location(
     }
 }
 
-pub struct Efs<'a, T: FlashRead + FlashWrite> {
-    storage: &'a T,
-    efh_beginning: ErasableLocation,
-    efh: Efh,
-    amd_physical_mode_mmio_size: Option<u32>,
+/// A directory that Efs::update_directories loaded.
+#[cfg(feature = "std")]
+enum AnyDirectory<const MAX_ENTRIES: usize> {
+    Psp(Box<PspDirectoryWithCapacity<MAX_ENTRIES>>),
+    Bhd(Box<BhdDirectoryWithCapacity<MAX_ENTRIES>>),
+    Combo(Box<ComboDirectoryWithCapacity<MAX_ENTRIES>>),
 }
 
-impl<'a, T: FlashRead + FlashWrite> Efs<'a, T> {
-    pub fn compatible_with_processor_generation(
-        &self,
-        processor_generation: ProcessorGeneration,
-    ) -> bool {
-        self.efh.compatible_with_processor_generation(processor_generation)
+/// A directory together with the directories it refers to (the ones that
+/// Efs::visit_used_ranges follows), each with the index of the entry that
+/// refers to it.
+#[cfg(feature = "std")]
+struct DirectoryTree<const MAX_ENTRIES: usize> {
+    directory: AnyDirectory<MAX_ENTRIES>,
+    subdirectories: Vec<(usize, DirectoryTree<MAX_ENTRIES>)>,
+}
+
+#[cfg(feature = "std")]
+impl<const MAX_ENTRIES: usize> DirectoryTree<MAX_ENTRIES> {
+    /// Returns the beginning and the size of the directory at the root.
+    fn extent(&self) -> Result<(Location, usize)> {
+        Ok(match &self.directory {
+            AnyDirectory::Psp(directory) => {
+                (directory.beginning, directory.directory_size()?)
+            }
+            AnyDirectory::Bhd(directory) => {
+                (directory.beginning, directory.directory_size()?)
+            }
+            AnyDirectory::Combo(directory) => {
+                (directory.beginning, directory.directory_size()?)
+            }
+        })
     }
 
-    // TODO: If we wanted to, we could also try the whole thing on the top 16 MiB again
-    // (I think it would be better to have the user just construct two
-    // different Efs instances in that case)
-    pub(crate) fn efh_beginning(
-        storage: &T,
+    /// Calls UPDATE_PSP and UPDATE_BHD, respectively, on all the PSP and
+    /// BHD directories in the tree.
+    /// Returns whether any of them returned true (that is, changed the
+    /// directory).
+    fn update<A: FlashAllocate>(
+        &mut self,
+        allocator: &mut A,
+        update_psp: &mut impl FnMut(
+            &mut PspDirectoryWithCapacity<MAX_ENTRIES>,
+            &mut A,
+        ) -> Result<bool>,
+        update_bhd: &mut impl FnMut(
+            &mut BhdDirectoryWithCapacity<MAX_ENTRIES>,
+            &mut A,
+        ) -> Result<bool>,
+    ) -> Result<bool> {
+        let mut changed = match &mut self.directory {
+            AnyDirectory::Psp(directory) => update_psp(directory, allocator)?,
+            AnyDirectory::Bhd(directory) => update_bhd(directory, allocator)?,
+            AnyDirectory::Combo(_) => false,
+        };
+        for (_, subdirectory) in &mut self.subdirectories {
+            if subdirectory.update(allocator, update_psp, update_bhd)? {
+                changed = true;
+            }
+        }
+        Ok(changed)
+    }
+}
+
+/// What Efs::update_directories wrote.
+#[cfg(feature = "std")]
+#[derive(Default)]
+struct DirectoryRelocations {
+    /// The ranges the directories were written to.
+    staged: Vec<ErasableRange>,
+    /// The ranges the directories were in before.
+    retired: Vec<ErasableRange>,
+    /// For each directory that was written: where it began before, where
+    /// it begins now, and what its OtherDirectoryRelativeOffset is now
+    /// relative to (see Directory::load).
+    relocated: Vec<(Location, Location, Location)>,
+}
+
+/// MAX_ENTRIES is the number of entries that the directories returned
+/// have room for.
+pub struct Efs<'a, T, const MAX_ENTRIES: usize = DEFAULT_MAX_DIRECTORY_ENTRIES>
+{
+    storage: &'a T,
+    // Flash chip on SPI chip select 2, if any.  Directories are always on
+    // the chip on SPI chip select 1 (STORAGE).
+    spi_cs2_storage: Option<&'a T>,
+    efh_beginning: ErasableLocation,
+    efh: Efh,
+    amd_physical_mode_mmio_size: Option<u32>,
+}
+
+impl<'a, T, const MAX_ENTRIES: usize> Efs<'a, T, MAX_ENTRIES> {
+    pub fn compatible_with_processor_generation(
+        &self,
+        processor_generation: ProcessorGeneration,
+    ) -> bool {
+        self.efh.compatible_with_processor_generation(processor_generation)
+    }
+
+    // TODO: If we wanted to, we could also try the whole thing on the top 16 MiB again
+    // (I think it would be better to have the user just construct two
+    // different Efs instances in that case)
+    pub(crate) async fn efh_beginning<S: AsyncFlashRead + FlashAlign>(
+        storage: &S,
         processor_generation: Option<ProcessorGeneration>,
     ) -> Result<ErasableLocation> {
         let positions = if let Some(
@@ -399,7 +1165,7 @@ impl<'a, T: FlashRead + FlashWrite> Efs<'a, T> {
         };
         for position in positions.iter() {
             let mut xbuf: [u8; size_of::<Efh>()] = [0; size_of::<Efh>()];
-            storage.read_exact(*position, &mut xbuf)?;
+            storage.read_exact(*position, &mut xbuf).await?;
             if let Some(item) = header_from_collection::<Efh>(&xbuf[..]) {
                 // Note: only one Efh with second_gen_efs() allowed in entire Flash!
                 if item.signature().ok().unwrap_or(0) == 0x55AA55AA
@@ -418,21 +1184,20 @@ impl<'a, T: FlashRead + FlashWrite> Efs<'a, T> {
         // Old firmware header is better than no firmware header; TODO: Warn.
         for position in positions.iter() {
             let mut xbuf: [u8; size_of::<Efh>()] = [0; size_of::<Efh>()];
-            storage.read_exact(*position, &mut xbuf)?;
-            if let Some(item) = header_from_collection::<Efh>(&xbuf[..]) {
-                if item.signature().ok().unwrap_or(0) == 0x55AA55AA
-                    && !item.second_gen_efs()
-                    && match processor_generation {
-                        //Some(x) => item.compatible_with_processor_generation(x),
-                        None => true,
-                        Some(ProcessorGeneration::Naples) => true,
-                        _ => false,
-                    }
-                {
-                    return storage
-                        .erasable_location(*position)
-                        .map_err(|_| Error::Misaligned);
+            storage.read_exact(*position, &mut xbuf).await?;
+            if let Some(item) = header_from_collection::<Efh>(&xbuf[..])
+                && item.signature().ok().unwrap_or(0) == 0x55AA55AA
+                && !item.second_gen_efs()
+                && match processor_generation {
+                    //Some(x) => item.compatible_with_processor_generation(x),
+                    None => true,
+                    Some(ProcessorGeneration::Naples) => true,
+                    _ => false,
                 }
+            {
+                return storage
+                    .erasable_location(*position)
+                    .map_err(|_| Error::Misaligned);
             }
         }
         Err(Error::EfsHeaderNotFound)
@@ -442,144 +1207,6 @@ impl<'a, T: FlashRead + FlashWrite> Efs<'a, T> {
         self.efh.physical_address_mode()
     }
 
-    /// This loads the Embedded Firmware Structure (EFS) from STORAGE.
-    /// Should the EFS be old enough to still use physical mmio addresses
-    /// for pointers on the Flash, AMD_PHYSICAL_MODE_MMIO_SIZE is required.
-    /// Otherwise, AMD_PHYSICAL_MODE_MMIO_SIZE is allowed to be None.
-    pub fn load(
-        storage: &'a T,
-        processor_generation: Option<ProcessorGeneration>,
-        amd_physical_mode_mmio_size: Option<u32>,
-    ) -> Result<Self> {
-        let efh_beginning = Self::efh_beginning(storage, processor_generation)?;
-        let mut xbuf: [u8; size_of::<Efh>()] = [0; size_of::<Efh>()];
-        storage.read_exact(efh_beginning.into(), &mut xbuf)?;
-        let efh = header_from_collection::<Efh>(&xbuf[..])
-            .ok_or(Error::EfsHeaderNotFound)?;
-        if efh.signature().ok().unwrap_or(0) != 0x55aa_55aa {
-            return Err(Error::EfsHeaderNotFound);
-        }
-
-        Ok(Self {
-            storage,
-            efh_beginning,
-            efh: *efh,
-            amd_physical_mode_mmio_size,
-        })
-    }
-    pub fn create(
-        storage: &'a T,
-        processor_generation: ProcessorGeneration,
-        efh_beginning: Location,
-        amd_physical_mode_mmio_size: Option<u32>,
-    ) -> Result<Self> {
-        if !EFH_POSITION.contains(&efh_beginning)
-            || preferred_efh_location(processor_generation) != efh_beginning
-        {
-            return Err(Error::EfsRangeCheck);
-        }
-
-        let mut buf: [u8; size_of::<Efh>()] = [0xFF; size_of::<Efh>()]; // FIXME should be: EFH_SIZE == size_of::<Efh>()
-        let item =
-            header_from_collection_mut(&mut buf[..]).ok_or(Error::Marshal)?;
-        let mut efh: Efh = Efh::default();
-        efh.efs_generations.set(Efh::efs_generations_for_processor_generation(
-            processor_generation,
-        ));
-        *item = efh;
-
-        storage.erase_and_write_blocks(
-            storage
-                .erasable_location(efh_beginning)
-                .map_err(|_| Error::Misaligned)?,
-            &buf,
-        )?;
-        Self::load(
-            storage,
-            Some(processor_generation),
-            amd_physical_mode_mmio_size,
-        )
-    }
-
-    /// Note: Either psp_directory or psp_combo_directory will succeed--but not both.
-    pub fn psp_directory(&self) -> Result<PspDirectory> {
-        let psp_directory_table_location = self
-            .efh
-            .psp_directory_table_location_zen()
-            .ok()
-            .unwrap_or(0xffff_ffff);
-        if Efh::is_invalid_directory_table_location(
-            psp_directory_table_location,
-        ) {
-            // Note: We could also check efh.psp_directory_location_naples(),
-            // but not even a newer Naples did that.
-            Err(Error::PspDirectoryHeaderNotFound)
-        } else {
-            let psp_directory_table_location = if self.physical_address_mode() {
-                assert!(Efh::is_invalid_directory_table_location(
-                    self.efh.psp_directory_table_location_naples()?
-                ));
-                Efh::de_mmio(
-                    psp_directory_table_location,
-                    self.amd_physical_mode_mmio_size,
-                )
-                .ok_or(Error::Marshal)?
-            } else {
-                assert!(Efh::is_likely_location(psp_directory_table_location));
-                psp_directory_table_location
-            };
-            let directory = PspDirectory::load(
-                self.storage,
-                psp_directory_table_location,
-                psp_directory_table_location,
-                self.amd_physical_mode_mmio_size,
-            )?;
-            if directory.header.cookie != PspDirectoryHeader::FIRST_LEVEL_COOKIE
-            {
-                return Err(Error::DirectoryTypeMismatch);
-            }
-            Ok(directory)
-        }
-    }
-
-    /// Note: Either psp_directory or psp_combo_directory will succeed--but not both.
-    pub fn psp_combo_directory(&self) -> Result<ComboDirectory> {
-        let psp_directory_table_location = self
-            .efh
-            .psp_directory_table_location_zen()
-            .ok()
-            .unwrap_or(0xffff_ffff);
-        if Efh::is_invalid_directory_table_location(
-            psp_directory_table_location,
-        ) {
-            Err(Error::PspDirectoryHeaderNotFound)
-        } else {
-            let psp_directory_table_location = if self.physical_address_mode() {
-                assert!(Efh::is_invalid_directory_table_location(
-                    self.efh.psp_directory_table_location_naples()?
-                ));
-                Efh::de_mmio(
-                    psp_directory_table_location,
-                    self.amd_physical_mode_mmio_size,
-                )
-                .ok_or(Error::Marshal)?
-            } else {
-                assert!(Efh::is_likely_location(psp_directory_table_location));
-                psp_directory_table_location
-            };
-            let directory = ComboDirectory::load(
-                self.storage,
-                psp_directory_table_location,
-                0,
-                self.amd_physical_mode_mmio_size,
-            )?;
-            if directory.header.cookie != ComboDirectoryHeader::PSP_COOKIE {
-                return Err(Error::DirectoryTypeMismatch);
-            }
-            Ok(directory)
-        }
-    }
-
     /// Returns an iterator over level 1 BHD directories.
     /// If PROCESSOR_GENERATION is Some, then only return the directories
     /// matching that generation.
@@ -641,72 +1268,310 @@ impl<'a, T: FlashRead + FlashWrite> Efs<'a, T> {
         Ok(IntoIterator::into_iter(positions).flatten())
     }
 
-    /// Return the directory matching PROCESSOR_GENERATION,
-    /// or any directory if the former is None.
-    /// Note: Either bhd_directory or bhd_combo_directory will succeed--but not both.
-    pub fn bhd_directory(
-        &self,
+    async fn load_efh<S: AsyncFlashRead + FlashAlign>(
+        storage: &S,
         processor_generation: Option<ProcessorGeneration>,
-    ) -> Result<BhdDirectory> {
-        let bhd_directory_table_location = self
-            .bhd_directories(processor_generation)?
-            .next()
-            .ok_or(Error::BhdDirectoryHeaderNotFound)?;
-        let directory = BhdDirectory::load(
-            self.storage,
-            bhd_directory_table_location,
-            0,
-            self.amd_physical_mode_mmio_size,
-        )?;
-        if directory.header.cookie != BhdDirectoryHeader::FIRST_LEVEL_COOKIE {
-            return Err(Error::DirectoryTypeMismatch);
+    ) -> Result<(ErasableLocation, Efh)> {
+        let efh_beginning =
+            Self::efh_beginning(storage, processor_generation).await?;
+        let mut xbuf: [u8; size_of::<Efh>()] = [0; size_of::<Efh>()];
+        storage.read_exact(efh_beginning.into(), &mut xbuf).await?;
+        let efh = header_from_collection::<Efh>(&xbuf[..])
+            .ok_or(Error::EfsHeaderNotFound)?;
+        if efh.signature().ok().unwrap_or(0) != 0x55aa_55aa {
+            return Err(Error::EfsHeaderNotFound);
         }
-        Ok(directory)
+        Ok((efh_beginning, *efh))
     }
 
-    /// Return the directory matching PROCESSOR_GENERATION,
-    /// or any directory if the former is None.
-    /// Note: Either bhd_directory or bhd_combo_directory will succeed--but not both.
-    pub fn bhd_combo_directory(
-        &self,
-        processor_generation: Option<ProcessorGeneration>,
-    ) -> Result<ComboDirectory> {
-        let bhd_directory_table_location = self
-            .bhd_directories(processor_generation)?
-            .next()
-            .ok_or(Error::BhdDirectoryHeaderNotFound)?;
-        let directory = ComboDirectory::load(
-            self.storage,
-            bhd_directory_table_location,
-            0,
-            self.amd_physical_mode_mmio_size,
-        )?;
-        if directory.header.cookie != ComboDirectoryHeader::BHD_COOKIE {
-            return Err(Error::DirectoryTypeMismatch);
+    /// Returns the location of the PSP directory (or PSP combo directory).
+    fn psp_directory_table_location(&self) -> Result<Location> {
+        let psp_directory_table_location = self
+            .efh
+            .psp_directory_table_location_zen()
+            .ok()
+            .unwrap_or(0xffff_ffff);
+        if Efh::is_invalid_directory_table_location(
+            psp_directory_table_location,
+        ) {
+            // Note: We could also check efh.psp_directory_location_naples(),
+            // but not even a newer Naples did that.
+            Err(Error::PspDirectoryHeaderNotFound)
+        } else if self.physical_address_mode() {
+            assert!(Efh::is_invalid_directory_table_location(
+                self.efh.psp_directory_table_location_naples()?
+            ));
+            Efh::de_mmio(
+                psp_directory_table_location,
+                self.amd_physical_mode_mmio_size,
+            )
+            .ok_or(Error::Marshal)
+        } else {
+            assert!(Efh::is_likely_location(psp_directory_table_location));
+            Ok(psp_directory_table_location)
         }
-        Ok(directory)
     }
+}
 
-    fn write_efh(&mut self) -> Result<()> {
-        let mut buf: [u8; size_of::<Efh>()] = [0xFF; size_of::<Efh>()];
-        if let Some(item) = header_from_collection_mut(&mut buf[..]) {
-            *item = self.efh;
-        }
-
-        self.storage.erase_and_write_blocks(self.efh_beginning, &buf)?;
-        Ok(())
+impl<'a, T: FlashRead + FlashWrite> Efs<'a, T> {
+    /// This loads the Embedded Firmware Structure (EFS) from STORAGE.
+    /// Should the EFS be old enough to still use physical mmio addresses
+    /// for pointers on the Flash, AMD_PHYSICAL_MODE_MMIO_SIZE is required.
+    /// Otherwise, AMD_PHYSICAL_MODE_MMIO_SIZE is allowed to be None.
+    pub fn load(
+        storage: &'a T,
+        processor_generation: Option<ProcessorGeneration>,
+        amd_physical_mode_mmio_size: Option<u32>,
+    ) -> Result<Self> {
+        let (efh_beginning, efh) = flash::run_ready(Self::load_efh(
+            &BlockingFlash(storage),
+            processor_generation,
+        ))?;
+        Ok(Self {
+            storage,
+            spi_cs2_storage: None,
+            efh_beginning,
+            efh,
+            amd_physical_mode_mmio_size,
+        })
     }
+    pub fn create(
+        storage: &'a T,
+        processor_generation: ProcessorGeneration,
+        efh_beginning: Location,
+        amd_physical_mode_mmio_size: Option<u32>,
+    ) -> Result<Self> {
+        if !EFH_POSITION.contains(&efh_beginning)
+            || preferred_efh_location(processor_generation) != efh_beginning
+        {
+            return Err(Error::EfsRangeCheck);
+        }
 
-    pub fn spi_mode_bulldozer(&self) -> Result<Option<EfhBulldozerSpiMode>> {
-        self.efh.spi_mode_bulldozer()
-    }
+        let mut buf: [u8; size_of::<Efh>()] = [0xFF; size_of::<Efh>()]; // FIXME should be: EFH_SIZE == size_of::<Efh>()
+        let item =
+            header_from_collection_mut(&mut buf[..]).ok_or(Error::Marshal)?;
+        let mut efh: Efh = Efh::default();
+        efh.efs_generations.set(Efh::efs_generations_for_processor_generation(
+            processor_generation,
+        ));
+        *item = efh;
 
-    pub fn set_spi_mode_bulldozer(
-        &mut self,
-        value: Option<EfhBulldozerSpiMode>,
-    ) {
-        self.efh.set_spi_mode_bulldozer(value)
-    }
+        storage.erase_and_write_blocks(
+            storage
+                .erasable_location(efh_beginning)
+                .map_err(|_| Error::Misaligned)?,
+            &buf,
+        )?;
+        Self::load(
+            storage,
+            Some(processor_generation),
+            amd_physical_mode_mmio_size,
+        )
+    }
+
+    /// Returns the same EFS, but with directories that have room for
+    /// MAX_ENTRIES entries each.
+    pub fn with_max_directory_entries<const MAX_ENTRIES: usize>(
+        self,
+    ) -> Efs<'a, T, MAX_ENTRIES> {
+        Efs {
+            storage: self.storage,
+            spi_cs2_storage: self.spi_cs2_storage,
+            efh_beginning: self.efh_beginning,
+            efh: self.efh,
+            amd_physical_mode_mmio_size: self.amd_physical_mode_mmio_size,
+        }
+    }
+}
+
+impl<'a, T: FlashRead + FlashWrite, const MAX_ENTRIES: usize>
+    Efs<'a, T, MAX_ENTRIES>
+{
+    /// Returns the flash chip on SPI chip select 2, if any.
+    pub fn spi_cs2_storage(&self) -> Option<&'a T> {
+        self.spi_cs2_storage
+    }
+
+    /// Sets the flash chip on SPI chip select 2 (that is, the one that
+    /// entries with rom_id SpiCs2 refer to) to SPI_CS2_STORAGE.
+    pub fn set_spi_cs2_storage(&mut self, spi_cs2_storage: Option<&'a T>) {
+        self.spi_cs2_storage = spi_cs2_storage;
+    }
+
+    /// Returns the flash chip that the payload of ENTRY is stored on.
+    pub fn psp_payload_storage(
+        &self,
+        entry: &PspDirectoryEntry,
+    ) -> Result<&'a T> {
+        match entry.rom_id_or_err()? {
+            PspDirectoryRomId::SpiCs1 => Ok(self.storage),
+            PspDirectoryRomId::SpiCs2 => {
+                self.spi_cs2_storage.ok_or(Error::FlashChipNotFound)
+            }
+        }
+    }
+
+    /// Returns the flash chip that the payload of ENTRY is stored on.
+    pub fn bhd_payload_storage(
+        &self,
+        entry: &BhdDirectoryEntry,
+    ) -> Result<&'a T> {
+        match entry.rom_id_or_err()? {
+            BhdDirectoryRomId::SpiCs1 => Ok(self.storage),
+            BhdDirectoryRomId::SpiCs2 => {
+                self.spi_cs2_storage.ok_or(Error::FlashChipNotFound)
+            }
+        }
+    }
+
+    /// Returns a reader for the payload of ENTRY in DIRECTORY, on the flash
+    /// chip that ENTRY's rom_id refers to.
+    /// See Directory::payload_reader.
+    pub fn psp_payload_reader(
+        &self,
+        directory: &PspDirectoryWithCapacity<MAX_ENTRIES>,
+        entry: &PspDirectoryEntry,
+    ) -> Result<PayloadReader<'a, T>> {
+        directory.payload_reader(self.psp_payload_storage(entry)?, entry)
+    }
+
+    /// Reads the payload of ENTRY in DIRECTORY, from the flash chip that
+    /// ENTRY's rom_id refers to, into the beginning of BUF.
+    /// See Directory::read_payload.
+    pub fn read_psp_payload(
+        &self,
+        directory: &PspDirectoryWithCapacity<MAX_ENTRIES>,
+        entry: &PspDirectoryEntry,
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        directory.read_payload(self.psp_payload_storage(entry)?, entry, buf)
+    }
+
+    /// Returns a reader for the payload of ENTRY in DIRECTORY, on the flash
+    /// chip that ENTRY's rom_id refers to.
+    /// See Directory::payload_reader.
+    pub fn bhd_payload_reader(
+        &self,
+        directory: &BhdDirectoryWithCapacity<MAX_ENTRIES>,
+        entry: &BhdDirectoryEntry,
+    ) -> Result<PayloadReader<'a, T>> {
+        directory.payload_reader(self.bhd_payload_storage(entry)?, entry)
+    }
+
+    /// Reads the payload of ENTRY in DIRECTORY, from the flash chip that
+    /// ENTRY's rom_id refers to, into the beginning of BUF.
+    /// See Directory::read_payload.
+    pub fn read_bhd_payload(
+        &self,
+        directory: &BhdDirectoryWithCapacity<MAX_ENTRIES>,
+        entry: &BhdDirectoryEntry,
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        directory.read_payload(self.bhd_payload_storage(entry)?, entry, buf)
+    }
+
+    /// Note: Either psp_directory or psp_combo_directory will succeed--but not both.
+    pub fn psp_directory(
+        &self,
+    ) -> Result<PspDirectoryWithCapacity<MAX_ENTRIES>> {
+        let psp_directory_table_location =
+            self.psp_directory_table_location()?;
+        let directory = PspDirectoryWithCapacity::<MAX_ENTRIES>::load(
+            self.storage,
+            psp_directory_table_location,
+            psp_directory_table_location,
+            self.amd_physical_mode_mmio_size,
+        )?;
+        if directory.header.cookie != PspDirectoryHeader::FIRST_LEVEL_COOKIE {
+            return Err(Error::DirectoryTypeMismatch);
+        }
+        Ok(directory)
+    }
+
+    /// Note: Either psp_directory or psp_combo_directory will succeed--but not both.
+    pub fn psp_combo_directory(
+        &self,
+    ) -> Result<ComboDirectoryWithCapacity<MAX_ENTRIES>> {
+        let psp_directory_table_location =
+            self.psp_directory_table_location()?;
+        let directory = ComboDirectoryWithCapacity::<MAX_ENTRIES>::load(
+            self.storage,
+            psp_directory_table_location,
+            psp_directory_table_location,
+            self.amd_physical_mode_mmio_size,
+        )?;
+        if directory.header.cookie != ComboDirectoryHeader::PSP_COOKIE {
+            return Err(Error::DirectoryTypeMismatch);
+        }
+        Ok(directory)
+    }
+
+    /// Return the directory matching PROCESSOR_GENERATION,
+    /// or any directory if the former is None.
+    /// Note: Either bhd_directory or bhd_combo_directory will succeed--but not both.
+    pub fn bhd_directory(
+        &self,
+        processor_generation: Option<ProcessorGeneration>,
+    ) -> Result<BhdDirectoryWithCapacity<MAX_ENTRIES>> {
+        let bhd_directory_table_location = self
+            .bhd_directories(processor_generation)?
+            .next()
+            .ok_or(Error::BhdDirectoryHeaderNotFound)?;
+        let directory = BhdDirectoryWithCapacity::<MAX_ENTRIES>::load(
+            self.storage,
+            bhd_directory_table_location,
+            bhd_directory_table_location,
+            self.amd_physical_mode_mmio_size,
+        )?;
+        if directory.header.cookie != BhdDirectoryHeader::FIRST_LEVEL_COOKIE {
+            return Err(Error::DirectoryTypeMismatch);
+        }
+        Ok(directory)
+    }
+
+    /// Return the directory matching PROCESSOR_GENERATION,
+    /// or any directory if the former is None.
+    /// Note: Either bhd_directory or bhd_combo_directory will succeed--but not both.
+    pub fn bhd_combo_directory(
+        &self,
+        processor_generation: Option<ProcessorGeneration>,
+    ) -> Result<ComboDirectoryWithCapacity<MAX_ENTRIES>> {
+        let bhd_directory_table_location = self
+            .bhd_directories(processor_generation)?
+            .next()
+            .ok_or(Error::BhdDirectoryHeaderNotFound)?;
+        let directory = ComboDirectoryWithCapacity::<MAX_ENTRIES>::load(
+            self.storage,
+            bhd_directory_table_location,
+            bhd_directory_table_location,
+            self.amd_physical_mode_mmio_size,
+        )?;
+        if directory.header.cookie != ComboDirectoryHeader::BHD_COOKIE {
+            return Err(Error::DirectoryTypeMismatch);
+        }
+        Ok(directory)
+    }
+
+    fn write_efh(&mut self) -> Result<()> {
+        let mut buf: [u8; size_of::<Efh>()] = [0xFF; size_of::<Efh>()];
+        if let Some(item) = header_from_collection_mut(&mut buf[..]) {
+            *item = self.efh;
+        }
+
+        self.storage.erase_and_write_blocks(self.efh_beginning, &buf)?;
+        Ok(())
+    }
+
+    pub fn spi_mode_bulldozer(&self) -> Result<Option<EfhBulldozerSpiMode>> {
+        self.efh.spi_mode_bulldozer()
+    }
+
+    pub fn set_spi_mode_bulldozer(
+        &mut self,
+        value: Option<EfhBulldozerSpiMode>,
+    ) {
+        self.efh.set_spi_mode_bulldozer(value)
+    }
     pub fn spi_mode_zen_naples(&self) -> Result<Option<EfhNaplesSpiMode>> {
         self.efh.spi_mode_zen_naples()
     }
@@ -747,14 +1612,18 @@ impl<'a, T: FlashRead + FlashWrite> Efs<'a, T> {
 
     /// Create a directory but don't set it as the Efs main bhd directory.
     /// The idea is to use this also for creating a second level directory.
+    /// OTHER_DIRECTORY_BEGINNING is the beginning of the directory that
+    /// will refer to the new one, if any; None for a first-level directory.
+    /// See Directory::load.
     pub fn create_bhd_directory(
         &mut self,
         cookie: [u8; 4],
         beginning: ErasableLocation,
         end: ErasableLocation,
+        other_directory_beginning: Option<Location>,
         default_entry_address_mode: AddressMode,
         entries: &[BhdDirectoryEntry],
-    ) -> Result<BhdDirectory> {
+    ) -> Result<BhdDirectoryWithCapacity<MAX_ENTRIES>> {
         assert_eq!(beginning.erasable_block_size(), end.erasable_block_size());
         match default_entry_address_mode {
             AddressMode::PhysicalAddress => {
@@ -763,16 +1632,16 @@ impl<'a, T: FlashRead + FlashWrite> Efs<'a, T> {
                 }
             }
             AddressMode::EfsRelativeOffset
-            | AddressMode::DirectoryRelativeOffset => {
+            | AddressMode::DirectoryRelativeOffset
+            | AddressMode::OtherDirectoryRelativeOffset => {
                 if self.physical_address_mode() {
                     return Err(Error::DirectoryTypeMismatch);
                 }
             }
-            _ => return Err(Error::DirectoryTypeMismatch),
         }
-        BhdDirectory::create(
+        BhdDirectoryWithCapacity::<MAX_ENTRIES>::create(
             beginning.into(),
-            0,
+            other_directory_beginning.unwrap_or(beginning.into()),
             default_entry_address_mode,
             cookie,
             self.amd_physical_mode_mmio_size,
@@ -781,7 +1650,7 @@ impl<'a, T: FlashRead + FlashWrite> Efs<'a, T> {
     }
     pub fn set_main_bhd_directory(
         &mut self,
-        directory: &BhdDirectory,
+        directory: &BhdDirectoryWithCapacity<MAX_ENTRIES>,
     ) -> Result<()> {
         let beginning = directory.beginning;
         if self
@@ -805,14 +1674,18 @@ impl<'a, T: FlashRead + FlashWrite> Efs<'a, T> {
     }
 
     // Note: BEGINNING, END are coordinates (in Byte).
+    /// OTHER_DIRECTORY_BEGINNING is the beginning of the directory that
+    /// will refer to the new one, if any; None for a first-level directory.
+    /// See Directory::load.
     pub fn create_psp_directory(
         &mut self,
         cookie: [u8; 4],
         beginning: ErasableLocation,
         end: ErasableLocation,
+        other_directory_beginning: Option<Location>,
         default_entry_address_mode: AddressMode,
         entries: &[PspDirectoryEntry],
-    ) -> Result<PspDirectory> {
+    ) -> Result<PspDirectoryWithCapacity<MAX_ENTRIES>> {
         assert_eq!(beginning.erasable_block_size(), end.erasable_block_size());
         match default_entry_address_mode {
             AddressMode::PhysicalAddress => {
@@ -821,16 +1694,16 @@ impl<'a, T: FlashRead + FlashWrite> Efs<'a, T> {
                 }
             }
             AddressMode::EfsRelativeOffset
-            | AddressMode::DirectoryRelativeOffset => {
+            | AddressMode::DirectoryRelativeOffset
+            | AddressMode::OtherDirectoryRelativeOffset => {
                 if self.physical_address_mode() {
                     return Err(Error::DirectoryTypeMismatch);
                 }
             }
-            _ => return Err(Error::DirectoryTypeMismatch),
         }
-        let result = PspDirectory::create(
-            beginning.into(),
+        let result = PspDirectoryWithCapacity::<MAX_ENTRIES>::create(
             beginning.into(),
+            other_directory_beginning.unwrap_or(beginning.into()),
             default_entry_address_mode,
             cookie,
             self.amd_physical_mode_mmio_size,
@@ -840,7 +1713,7 @@ impl<'a, T: FlashRead + FlashWrite> Efs<'a, T> {
     }
     pub fn set_main_psp_directory(
         &mut self,
-        directory: &PspDirectory,
+        directory: &PspDirectoryWithCapacity<MAX_ENTRIES>,
     ) -> Result<()> {
         let beginning = directory.beginning;
         // TODO: Boards older than Rome have 0xff at the top bits.  Depends on address_mode maybe.
@@ -850,43 +1723,43 @@ impl<'a, T: FlashRead + FlashWrite> Efs<'a, T> {
     }
     pub fn psp_combo_subdirectory(
         &self,
-        directory: &ComboDirectory,
+        directory: &ComboDirectoryWithCapacity<MAX_ENTRIES>,
         entry: &ComboDirectoryEntry,
-    ) -> Result<PspDirectory> {
+    ) -> Result<PspDirectoryWithCapacity<MAX_ENTRIES>> {
         let beginning = directory.payload_beginning(entry)?;
-        PspDirectory::load(
+        PspDirectoryWithCapacity::<MAX_ENTRIES>::load(
             self.storage,
             beginning,
-            directory.beginning, // TODO: verify.
+            directory.beginning,
             self.amd_physical_mode_mmio_size,
         )
     }
     pub fn bhd_combo_subdirectory(
         &self,
-        directory: &ComboDirectory,
+        directory: &ComboDirectoryWithCapacity<MAX_ENTRIES>,
         entry: &ComboDirectoryEntry,
-    ) -> Result<BhdDirectory> {
+    ) -> Result<BhdDirectoryWithCapacity<MAX_ENTRIES>> {
         let beginning = directory.payload_beginning(entry)?;
-        BhdDirectory::load(
+        BhdDirectoryWithCapacity::<MAX_ENTRIES>::load(
             self.storage,
             beginning,
-            directory.beginning, // TODO: verify.
+            directory.beginning,
             self.amd_physical_mode_mmio_size,
         )
     }
     pub fn psp_subdirectory(
         &self,
-        directory: &PspDirectory,
-    ) -> Result<PspDirectory> {
+        directory: &PspDirectoryWithCapacity<MAX_ENTRIES>,
+    ) -> Result<PspDirectoryWithCapacity<MAX_ENTRIES>> {
         for entry in directory.entries() {
             if let Ok(PspDirectoryEntryType::SecondLevelDirectory) =
                 entry.typ_or_err()
             {
                 let beginning = directory.payload_beginning(&entry)?;
-                return PspDirectory::load(
+                return PspDirectoryWithCapacity::<MAX_ENTRIES>::load(
                     self.storage,
                     beginning,
-                    beginning,
+                    directory.beginning,
                     self.amd_physical_mode_mmio_size,
                 );
             }
@@ -895,17 +1768,17 @@ impl<'a, T: FlashRead + FlashWrite> Efs<'a, T> {
     }
     pub fn bhd_subdirectory(
         &self,
-        directory: &BhdDirectory,
-    ) -> Result<BhdDirectory> {
+        directory: &BhdDirectoryWithCapacity<MAX_ENTRIES>,
+    ) -> Result<BhdDirectoryWithCapacity<MAX_ENTRIES>> {
         for entry in directory.entries() {
             if let Ok(BhdDirectoryEntryType::SecondLevelDirectory) =
                 entry.typ_or_err()
             {
                 let beginning = directory.payload_beginning(&entry)?;
-                return BhdDirectory::load(
+                return BhdDirectoryWithCapacity::<MAX_ENTRIES>::load(
                     self.storage,
                     beginning,
-                    beginning,
+                    directory.beginning,
                     self.amd_physical_mode_mmio_size,
                 );
             }
@@ -916,14 +1789,14 @@ impl<'a, T: FlashRead + FlashWrite> Efs<'a, T> {
     /// that is a payload of the former and return that.
     pub fn psp_ab_bhd_subdirectory(
         &self,
-        directory: &PspDirectory,
-    ) -> Result<BhdDirectory> {
+        directory: &PspDirectoryWithCapacity<MAX_ENTRIES>,
+    ) -> Result<BhdDirectoryWithCapacity<MAX_ENTRIES>> {
         for entry in directory.entries() {
             if let Ok(PspDirectoryEntryType::SecondLevelBhdDirectory) =
                 entry.typ_or_err()
             {
                 let beginning = directory.payload_beginning(&entry)?;
-                return BhdDirectory::load(
+                return BhdDirectoryWithCapacity::<MAX_ENTRIES>::load(
                     self.storage,
                     beginning,
                     directory.beginning,
@@ -935,144 +1808,1603 @@ impl<'a, T: FlashRead + FlashWrite> Efs<'a, T> {
     }
     pub fn create_psp_subdirectory(
         &self,
-        directory: &mut PspDirectory,
+        directory: &mut PspDirectoryWithCapacity<MAX_ENTRIES>,
         beginning: ErasableLocation,
         end: ErasableLocation,
         amd_physical_mode_mmio_size: Option<u32>,
         entries: &[PspDirectoryEntry],
-    ) -> Result<PspDirectory> {
+    ) -> Result<PspDirectoryWithCapacity<MAX_ENTRIES>> {
         if directory.header.cookie() != PspDirectoryHeader::FIRST_LEVEL_COOKIE {
             return Err(Error::DirectoryTypeMismatch);
         }
-        // TODO
-        // if let Err(Error::EntryNotFound) = self.psp_subdirectory(directory) {
-        directory.add_entry_direct(&PspDirectoryEntry::new_payload(
+        directory.add_entry(&PspDirectoryEntry::new_payload(
             directory.directory_address_mode(),
             PspDirectoryEntryType::SecondLevelDirectory,
             Some(ErasableLocation::extent(beginning, end)),
-            Some(ValueOrLocation::EfsRelativeOffset(beginning.into())),
+            Some(directory.source_for_location(beginning.into())?),
         )?)?;
-        PspDirectory::create(
-            beginning.into(),
+        PspDirectoryWithCapacity::<MAX_ENTRIES>::create(
             beginning.into(),
+            directory.beginning,
             directory.directory_address_mode,
             *b"$PL2",
             amd_physical_mode_mmio_size,
             entries,
         )
-        // } else {
-        //     Err(Error::Duplicate)
-        // }
     }
 
-    pub fn create_second_level_psp_directory(
+    /// Calls VISIT with the beginning and the size of every range of the
+    /// flash that is in use: the EFH, all the directories that can be
+    /// reached from it (including the A/B ones), and all their payloads.
+    /// Note: Ranges can overlap, and a range can be visited more than once.
+    /// Note: Entries whose payload cannot be located (for example physical
+    /// addresses without a known MMIO size) are skipped.
+    /// Note: Payloads on the chip on SPI chip select 2 are skipped, since
+    /// they are not on this flash.
+    pub fn visit_used_ranges(
         &self,
-        beginning: ErasableLocation,
-        end: ErasableLocation,
-        entries: &[PspDirectoryEntry],
-    ) -> Result<PspDirectory> {
-        let mut psp_directory = self.psp_directory()?;
-        self.create_psp_subdirectory(
-            &mut psp_directory,
-            beginning,
-            end,
-            self.amd_physical_mode_mmio_size,
-            entries,
-        )
-    }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::{EfhBulldozerSpiMode, EfhNaplesSpiMode, EfhRomeSpiMode};
-    use crate::Efh;
-    use crate::Efs;
-    use crate::Error;
-    use crate::flash;
-    use crate::ondisk::{
-        SpiFastSpeedNew, SpiNaplesMicronMode, SpiReadMode, SpiRomeMicronMode,
-    };
-    use flash::{ErasableLocation, FlashAlign, FlashRead, FlashWrite};
-
-    struct Storage {}
-    impl FlashAlign for Storage {
-        fn erasable_block_size(&self) -> usize {
-            16
+        mut visit: impl FnMut(Location, usize),
+    ) -> Result<()> {
+        visit(self.efh_beginning.into(), size_of::<Efh>());
+        match self.psp_directory() {
+            Ok(directory) => {
+                self.visit_psp_directory(&directory, true, &mut visit)?
+            }
+            Err(Error::DirectoryTypeMismatch) => {
+                let directory = self.psp_combo_directory()?;
+                visit(directory.beginning, directory.directory_size()?);
+                for entry in directory.entries() {
+                    let subdirectory =
+                        self.psp_combo_subdirectory(&directory, &entry)?;
+                    self.visit_psp_directory(&subdirectory, true, &mut visit)?;
+                }
+            }
+            Err(Error::PspDirectoryHeaderNotFound) => {}
+            Err(e) => return Err(e),
         }
-    }
-    impl FlashRead for Storage {
-        fn read_exact(
-            &self,
-            _: u32,
-            _: &mut [u8],
-        ) -> core::result::Result<(), flash::Error> {
-            todo!()
+        for beginning in self.bhd_directories(None)? {
+            if Efh::is_invalid_directory_table_location(beginning) {
+                continue;
+            }
+            match BhdDirectoryWithCapacity::<MAX_ENTRIES>::load(
+                self.storage,
+                beginning,
+                beginning,
+                self.amd_physical_mode_mmio_size,
+            ) {
+                Ok(directory) => {
+                    self.visit_bhd_directory(&directory, true, &mut visit)?
+                }
+                Err(Error::DirectoryTypeMismatch) => {
+                    let directory =
+                        ComboDirectoryWithCapacity::<MAX_ENTRIES>::load(
+                            self.storage,
+                            beginning,
+                            beginning,
+                            self.amd_physical_mode_mmio_size,
+                        )?;
+                    visit(directory.beginning, directory.directory_size()?);
+                    for entry in directory.entries() {
+                        let subdirectory =
+                            self.bhd_combo_subdirectory(&directory, &entry)?;
+                        self.visit_bhd_directory(
+                            &subdirectory,
+                            true,
+                            &mut visit,
+                        )?;
+                    }
+                }
+                Err(e) => return Err(e),
+            }
         }
+        Ok(())
     }
-    impl FlashWrite for Storage {
-        fn erase_block(
-            &self,
-            _: ErasableLocation,
-        ) -> core::result::Result<(), flash::Error> {
-            todo!()
-        }
-        fn erase_and_write_block(
-            &self,
-            _: ErasableLocation,
-            _: &[u8],
-        ) -> core::result::Result<(), flash::Error> {
-            todo!()
+
+    /// Visits DIRECTORY, its payloads and its second-level BHD directory
+    /// (if any)--and, if FIRST_LEVEL, its second-level PSP directories
+    /// (including the A/B ones, which can have second-level BHD
+    /// directories of their own).
+    fn visit_psp_directory(
+        &self,
+        directory: &PspDirectoryWithCapacity<MAX_ENTRIES>,
+        first_level: bool,
+        visit: &mut impl FnMut(Location, usize),
+    ) -> Result<()> {
+        visit(directory.beginning, directory.directory_size()?);
+        for entry in directory.entries() {
+            let Some(size) = entry.size() else {
+                continue;
+            };
+            if let Ok(PspDirectoryRomId::SpiCs2) = entry.rom_id_or_err() {
+                continue;
+            }
+            let Ok(beginning) = directory.payload_beginning(&entry) else {
+                continue;
+            };
+            visit(beginning, size as usize);
+            match entry.typ_or_err() {
+                Ok(
+                    PspDirectoryEntryType::SecondLevelDirectory
+                    | PspDirectoryEntryType::SecondLevelAPspDirectory
+                    | PspDirectoryEntryType::SecondLevelBPspDirectory,
+                ) if first_level => {
+                    let subdirectory =
+                        PspDirectoryWithCapacity::<MAX_ENTRIES>::load(
+                            self.storage,
+                            beginning,
+                            directory.beginning,
+                            self.amd_physical_mode_mmio_size,
+                        )?;
+                    self.visit_psp_directory(&subdirectory, false, visit)?;
+                }
+                Ok(PspDirectoryEntryType::SecondLevelBhdDirectory) => {
+                    let subdirectory =
+                        BhdDirectoryWithCapacity::<MAX_ENTRIES>::load(
+                            self.storage,
+                            beginning,
+                            directory.beginning,
+                            self.amd_physical_mode_mmio_size,
+                        )?;
+                    self.visit_bhd_directory(&subdirectory, false, visit)?;
+                }
+                _ => {}
+            }
         }
+        Ok(())
     }
 
-    fn setup_efs_test(storage: &Storage) -> Efs<Storage> {
-        let efh_beginning = storage.erasable_location(0).unwrap();
-        Efs {
-            amd_physical_mode_mmio_size: None,
-            efh: Efh::default(),
-            efh_beginning,
-            storage: &storage,
+    /// Visits DIRECTORY and its payloads--and, if FIRST_LEVEL, its
+    /// second-level directories.
+    fn visit_bhd_directory(
+        &self,
+        directory: &BhdDirectoryWithCapacity<MAX_ENTRIES>,
+        first_level: bool,
+        visit: &mut impl FnMut(Location, usize),
+    ) -> Result<()> {
+        visit(directory.beginning, directory.directory_size()?);
+        for entry in directory.entries() {
+            let Some(size) = entry.size() else {
+                continue;
+            };
+            if let Ok(BhdDirectoryRomId::SpiCs2) = entry.rom_id_or_err() {
+                continue;
+            }
+            let Ok(beginning) = directory.payload_beginning(&entry) else {
+                continue;
+            };
+            let size = if entry.payload_compressed() {
+                directory.payload_extent(self.storage, &entry)?
+            } else {
+                size as usize
+            };
+            visit(beginning, size);
+            if first_level
+                && let Ok(BhdDirectoryEntryType::SecondLevelDirectory) =
+                    entry.typ_or_err()
+            {
+                let subdirectory =
+                    BhdDirectoryWithCapacity::<MAX_ENTRIES>::load(
+                        self.storage,
+                        beginning,
+                        directory.beginning,
+                        self.amd_physical_mode_mmio_size,
+                    )?;
+                self.visit_bhd_directory(&subdirectory, false, visit)?;
+            }
         }
+        Ok(())
     }
 
-    #[test]
-    fn test_spi_mode_all_off() {
-        let storage = Storage {};
-        let mut setup = setup_efs_test(&storage);
-        assert!(setup.spi_mode_bulldozer().unwrap().is_none());
-        assert!(setup.spi_mode_zen_naples().unwrap().is_none());
-        assert!(setup.spi_mode_zen_rome().unwrap().is_none());
-        setup.set_spi_mode_bulldozer(None);
-        setup.set_spi_mode_zen_naples(None);
-        setup.set_spi_mode_zen_rome(None);
-        assert!(setup.spi_mode_bulldozer().unwrap().is_none());
-        assert!(setup.spi_mode_zen_naples().unwrap().is_none());
-        assert!(setup.spi_mode_zen_rome().unwrap().is_none());
+    /// Takes a range of at least SIZE Byte, satisfying CONSTRAINTS, from
+    /// ALLOCATOR.
+    #[cfg(feature = "std")]
+    fn take_payload_range(
+        &self,
+        storage: &T,
+        allocator: &mut impl FlashAllocate,
+        size: usize,
+        constraints: PlacementConstraints,
+    ) -> Result<ErasableRange> {
+        let arena_beginning = storage.erasable_location(0)?;
+        // The allocator knows which parts of that actually exist.
+        let arena = ErasableRange::new(
+            arena_beginning,
+            storage.erasable_location(
+                Location::MAX & !storage.erasable_block_mask(),
+            )?,
+        );
+        Ok(constraints
+            .take_at_least(allocator, size, &arena, self.efh_beginning.into())
+            .ok_or(flash::Error::Size)?)
     }
 
-    #[test]
-    fn test_spi_mode_bulldozer() -> Result<(), Error> {
-        let storage = Storage {};
-        let mut setup = setup_efs_test(&storage);
-        let spi_mode = EfhBulldozerSpiMode {
-            read_mode: SpiReadMode::Dual112,
-            fast_speed_new: SpiFastSpeedNew::_33_33MHz,
-        };
-        setup.set_spi_mode_bulldozer(Some(spi_mode));
-        assert!(
-            setup.spi_mode_bulldozer()?.unwrap().read_mode
-                == SpiReadMode::Dual112
+    /// Inserts a payload with contents DATA into DIRECTORY (which has to
+    /// have been loaded from this Efs): Writes DATA to a range taken from
+    /// ALLOCATOR (respecting the default placement constraints for the type
+    /// of ENTRY) and adds ENTRY (with size and source set accordingly).
+    /// The directories are then written to new ranges (also taken from
+    /// ALLOCATOR), and the EFH is rewritten to refer to them (see
+    /// update_directories); afterwards, DIRECTORY is reloaded from where it
+    /// was written to.  Changes to DIRECTORY that were not written before
+    /// are not taken into account.
+    /// If that fails, the ranges taken are given back to ALLOCATOR and both
+    /// DIRECTORY and the directories on the flash are left as they were.
+    /// Note: This is not atomic on flash: should rewriting the EFH (in
+    /// place) be interrupted, the EFH can be lost.  Up to that point, an
+    /// interruption leaves the directories as they were.
+    /// Note: ALLOCATOR has to be for the flash this Efs is on, so ENTRY's
+    /// rom_id has to be SpiCs1.
+    /// Returns the entry that was added.
+    #[cfg(feature = "std")]
+    pub fn insert_psp_payload(
+        &mut self,
+        directory: &mut PspDirectoryWithCapacity<MAX_ENTRIES>,
+        entry: &PspDirectoryEntry,
+        data: &[u8],
+        allocator: &mut impl FlashAllocate,
+    ) -> Result<PspDirectoryEntry> {
+        if entry.rom_id_or_err()? != PspDirectoryRomId::SpiCs1 {
+            return Err(Error::EntryTypeMismatch);
+        }
+        let range = self.take_payload_range(
+            self.storage,
+            allocator,
+            data.len(),
+            PlacementConstraints::for_psp_entry_type(entry.typ_or_err()?),
+        )?;
+        let storage = self.storage;
+        let beginning = directory.beginning;
+        let mut added = false;
+        let result = self.update_directories(
+            allocator,
+            &mut |candidate, _| {
+                if added || candidate.beginning != beginning {
+                    return Ok(false);
+                }
+                candidate.add_payload(
+                    storage,
+                    entry,
+                    data,
+                    data.len(),
+                    range,
+                )?;
+                added = true;
+                Ok(true)
+            },
+            &mut |_, _| Ok(false),
         );
-        assert!(setup.spi_mode_zen_naples()?.is_none());
-        assert!(setup.spi_mode_zen_rome()?.is_none());
-        Ok(())
+        let relocations = match result {
+            Ok(_) if !added => Err(Error::EntryNotFound),
+            result => result,
+        }
+        .inspect_err(|_| allocator.release(range))?;
+        if let Some(&(_, beginning, other_directory_beginning)) =
+            relocations.relocated.iter().find(|(old, _, _)| *old == beginning)
+        {
+            *directory = PspDirectoryWithCapacity::<MAX_ENTRIES>::load(
+                self.storage,
+                beginning,
+                other_directory_beginning,
+                self.amd_physical_mode_mmio_size,
+            )?;
+        }
+        self.release_unused(allocator, relocations.retired)?;
+        Ok(*directory.entry_mut(&entry.key()?)?)
     }
 
-    #[test]
-    fn test_spi_mode_zen_naples() -> Result<(), Error> {
-        let storage = Storage {};
-        let mut setup = setup_efs_test(&storage);
-        let spi_mode = EfhNaplesSpiMode {
+    /// Like insert_psp_payload, but for BHD directories.
+    /// If ENTRY is marked compressed, DATA is compressed before it's
+    /// written (the size of ENTRY is still set to the size of DATA).
+    #[cfg(feature = "std")]
+    pub fn insert_bhd_payload(
+        &mut self,
+        directory: &mut BhdDirectoryWithCapacity<MAX_ENTRIES>,
+        entry: &BhdDirectoryEntry,
+        data: &[u8],
+        allocator: &mut impl FlashAllocate,
+    ) -> Result<BhdDirectoryEntry> {
+        if entry.rom_id_or_err()? != BhdDirectoryRomId::SpiCs1 {
+            return Err(Error::EntryTypeMismatch);
+        }
+        let compressed;
+        let payload = if entry.payload_compressed() {
+            compressed = compress_payload(data)?;
+            &compressed[..]
+        } else {
+            data
+        };
+        let range = self.take_payload_range(
+            self.storage,
+            allocator,
+            payload.len(),
+            PlacementConstraints::for_bhd_entry_type(entry.typ_or_err()?),
+        )?;
+        let storage = self.storage;
+        let beginning = directory.beginning;
+        let mut added = false;
+        let result = self.update_directories(
+            allocator,
+            &mut |_, _| Ok(false),
+            &mut |candidate, _| {
+                if added || candidate.beginning != beginning {
+                    return Ok(false);
+                }
+                candidate.add_payload(
+                    storage,
+                    entry,
+                    payload,
+                    data.len(),
+                    range,
+                )?;
+                added = true;
+                Ok(true)
+            },
+        );
+        let relocations = match result {
+            Ok(_) if !added => Err(Error::EntryNotFound),
+            result => result,
+        }
+        .inspect_err(|_| allocator.release(range))?;
+        if let Some(&(_, beginning, other_directory_beginning)) =
+            relocations.relocated.iter().find(|(old, _, _)| *old == beginning)
+        {
+            *directory = BhdDirectoryWithCapacity::<MAX_ENTRIES>::load(
+                self.storage,
+                beginning,
+                other_directory_beginning,
+                self.amd_physical_mode_mmio_size,
+            )?;
+        }
+        self.release_unused(allocator, relocations.retired)?;
+        Ok(*directory.entry_mut(&entry.key()?)?)
+    }
+
+    /// Moves payloads towards the beginning of the flash, into ranges that
+    /// ALLOCATOR has free, in order to close the gaps between them.
+    /// ALLOCATOR is usually a FreeListFlashAllocator::from_efs of this Efs.
+    /// Payloads on the chip on SPI chip select 2, payloads that cannot be
+    /// located and payloads that more than one entry refers to stay where
+    /// they are.
+    /// The directories are then written to new ranges (also taken from
+    /// ALLOCATOR), and the EFH is rewritten to refer to them (see
+    /// update_directories)--so, should this be interrupted before that, the
+    /// EFH still refers to the old directories, which still refer to the
+    /// old payloads.  Only at the end, the erase blocks the payloads and directories were
+    /// moved away from are released to ALLOCATOR, and only the ones
+    /// nothing uses anymore.
+    /// ALLOCATOR needs to support release.
+    #[cfg(feature = "std")]
+    pub fn compact(
+        &mut self,
+        allocator: &mut impl FlashAllocate,
+    ) -> Result<()> {
+        let mut used = Vec::new();
+        self.visit_used_ranges(|beginning, size| used.push((beginning, size)))?;
+        let storage = self.storage;
+        let efh_beginning = self.efh_beginning.into();
+        let mut psp_vacated = Vec::new();
+        let mut bhd_vacated = Vec::new();
+        let relocations = self.update_directories(
+            allocator,
+            &mut |directory, allocator| {
+                let vacated = directory.compact_payloads(
+                    storage,
+                    allocator,
+                    &used,
+                    efh_beginning,
+                    |entry| match (entry.typ_or_err(), entry.rom_id_or_err()) {
+                        (
+                            Ok(PspDirectoryEntryType::SecondLevelDirectory)
+                            | Ok(PspDirectoryEntryType::SecondLevelAPspDirectory)
+                            | Ok(PspDirectoryEntryType::SecondLevelBPspDirectory)
+                            | Ok(PspDirectoryEntryType::SecondLevelBhdDirectory),
+                            _,
+                        ) => None,
+                        (Ok(type_), Ok(PspDirectoryRomId::SpiCs1)) => {
+                            Some(PlacementConstraints::for_psp_entry_type(type_))
+                        }
+                        _ => None,
+                    },
+                )?;
+                let changed = !vacated.is_empty();
+                psp_vacated.extend(vacated);
+                Ok(changed)
+            },
+            &mut |directory, allocator| {
+                let vacated = directory.compact_payloads(
+                    storage,
+                    allocator,
+                    &used,
+                    efh_beginning,
+                    |entry| match (entry.typ_or_err(), entry.rom_id_or_err()) {
+                        (Ok(BhdDirectoryEntryType::SecondLevelDirectory), _) => {
+                            None
+                        }
+                        (Ok(type_), Ok(BhdDirectoryRomId::SpiCs1)) => {
+                            Some(PlacementConstraints::for_bhd_entry_type(type_))
+                        }
+                        _ => None,
+                    },
+                )?;
+                let changed = !vacated.is_empty();
+                bhd_vacated.extend(vacated);
+                Ok(changed)
+            },
+        )?;
+        self.release_unused(
+            allocator,
+            psp_vacated
+                .into_iter()
+                .chain(bhd_vacated)
+                .chain(relocations.retired),
+        )
+    }
+
+    /// Releases the erase blocks in RANGES that nothing uses (anymore) to
+    /// ALLOCATOR.
+    #[cfg(feature = "std")]
+    fn release_unused(
+        &self,
+        allocator: &mut impl FlashAllocate,
+        ranges: impl IntoIterator<Item = ErasableRange>,
+    ) -> Result<()> {
+        let mut used = Vec::new();
+        self.visit_used_ranges(|beginning, size| used.push((beginning, size)))?;
+        let mut blocks = BTreeSet::new();
+        for range in ranges {
+            let mut block = range.beginning;
+            while Location::from(block) < Location::from(range.end) {
+                let next = block.advance(block.erasable_block_size())?;
+                let block_range = ErasableRange::new(block, next);
+                if !used.iter().any(|&(beginning, size)| {
+                    block_range.overlaps(beginning, size)
+                }) {
+                    blocks.insert(Location::from(block));
+                }
+                block = next;
+            }
+        }
+        for block in blocks {
+            let block = self.storage.erasable_location(block)?;
+            allocator.release(ErasableRange::new(
+                block,
+                block.advance(block.erasable_block_size())?,
+            ));
+        }
+        Ok(())
+    }
+
+    /// Calls UPDATE_PSP and UPDATE_BHD, respectively, on (copies of) all
+    /// the PSP and BHD directories that visit_used_ranges visits; they
+    /// return whether they changed the directory.
+    /// Every first-level (or combo) directory for which that was the case
+    /// (for it or for a directory it refers to) is then, together with all
+    /// the directories it refers to, written to new ranges taken from
+    /// ALLOCATOR--and, finally, the EFH is rewritten (once) to refer to
+    /// the new directories instead.
+    /// That way, should this be interrupted before the EFH is rewritten,
+    /// the directories the EFH refers to are still the old ones, intact.
+    /// Rewriting the EFH (in place, since it can't move) is the only step
+    /// that is not safe against power loss.
+    /// If this fails (without the EFH having been rewritten), the new
+    /// ranges are given back to ALLOCATOR.
+    /// The ranges the old directories were in are not released to
+    /// ALLOCATOR (see release_unused).
+    #[cfg(feature = "std")]
+    fn update_directories<A: FlashAllocate>(
+        &mut self,
+        allocator: &mut A,
+        update_psp: &mut impl FnMut(
+            &mut PspDirectoryWithCapacity<MAX_ENTRIES>,
+            &mut A,
+        ) -> Result<bool>,
+        update_bhd: &mut impl FnMut(
+            &mut BhdDirectoryWithCapacity<MAX_ENTRIES>,
+            &mut A,
+        ) -> Result<bool>,
+    ) -> Result<DirectoryRelocations> {
+        let efh = self.efh;
+        let mut relocations = DirectoryRelocations::default();
+        let result =
+            self.relocate_updated_directories(
+                allocator,
+                update_psp,
+                update_bhd,
+                &mut relocations,
+            )
+            .and_then(|changed| {
+                if changed { self.write_efh() } else { Ok(()) }
+            });
+        if let Err(e) = result {
+            self.efh = efh;
+            for range in relocations.staged {
+                allocator.release(range);
+            }
+            return Err(e);
+        }
+        Ok(relocations)
+    }
+
+    /// See update_directories.  This only updates the EFH in memory.
+    /// Returns whether it did.
+    #[cfg(feature = "std")]
+    fn relocate_updated_directories<A: FlashAllocate>(
+        &mut self,
+        allocator: &mut A,
+        update_psp: &mut impl FnMut(
+            &mut PspDirectoryWithCapacity<MAX_ENTRIES>,
+            &mut A,
+        ) -> Result<bool>,
+        update_bhd: &mut impl FnMut(
+            &mut BhdDirectoryWithCapacity<MAX_ENTRIES>,
+            &mut A,
+        ) -> Result<bool>,
+        relocations: &mut DirectoryRelocations,
+    ) -> Result<bool> {
+        let mut changed = false;
+        let tree = match self.psp_directory() {
+            Ok(directory) => Some(self.load_psp_tree(directory, true)?),
+            Err(Error::DirectoryTypeMismatch) => {
+                Some(self.load_combo_tree(self.psp_combo_directory()?, false)?)
+            }
+            Err(Error::PspDirectoryHeaderNotFound) => None,
+            Err(e) => return Err(e),
+        };
+        if let Some(mut tree) = tree
+            && tree.update(allocator, update_psp, update_bhd)?
+        {
+            let beginning = self.psp_directory_table_location()?;
+            let new_beginning =
+                self.relocate_tree(&mut tree, None, allocator, relocations)?;
+            let value = self.efh.psp_directory_table_location_zen()?;
+            self.efh.set_psp_directory_table_location_zen(
+                if value == beginning {
+                    new_beginning
+                } else {
+                    mmio_encode(
+                        new_beginning,
+                        self.amd_physical_mode_mmio_size,
+                    )?
+                },
+            );
+            changed = true;
+        }
+        let mut beginnings = Vec::new();
+        for beginning in self.bhd_directories(None)? {
+            if !Efh::is_invalid_directory_table_location(beginning)
+                && !beginnings.contains(&beginning)
+            {
+                beginnings.push(beginning);
+            }
+        }
+        for beginning in beginnings {
+            let mut tree = match BhdDirectoryWithCapacity::<MAX_ENTRIES>::load(
+                self.storage,
+                beginning,
+                beginning,
+                self.amd_physical_mode_mmio_size,
+            ) {
+                Ok(directory) => self.load_bhd_tree(directory, true)?,
+                Err(Error::DirectoryTypeMismatch) => self.load_combo_tree(
+                    ComboDirectoryWithCapacity::<MAX_ENTRIES>::load(
+                        self.storage,
+                        beginning,
+                        beginning,
+                        self.amd_physical_mode_mmio_size,
+                    )?,
+                    true,
+                )?,
+                Err(e) => return Err(e),
+            };
+            if !tree.update(allocator, update_psp, update_bhd)? {
+                continue;
+            }
+            let new_beginning =
+                self.relocate_tree(&mut tree, None, allocator, relocations)?;
+            let amd_physical_mode_mmio_size = self.amd_physical_mode_mmio_size;
+            if self.efh.bhd_directory_table_milan().ok() == Some(beginning) {
+                self.efh.set_bhd_directory_table_milan(new_beginning);
+            }
+            for table in &mut self.efh.bhd_directory_tables {
+                let value = table.get();
+                if Efh::de_mmio(value, amd_physical_mode_mmio_size)
+                    == Some(beginning)
+                {
+                    table.set(if value == beginning {
+                        new_beginning
+                    } else {
+                        mmio_encode(new_beginning, amd_physical_mode_mmio_size)?
+                    });
+                }
+            }
+            changed = true;
+        }
+        Ok(changed)
+    }
+
+    /// Loads the directories that DIRECTORY refers to (see
+    /// visit_psp_directory).
+    #[cfg(feature = "std")]
+    fn load_psp_tree(
+        &self,
+        directory: PspDirectoryWithCapacity<MAX_ENTRIES>,
+        first_level: bool,
+    ) -> Result<DirectoryTree<MAX_ENTRIES>> {
+        let mut subdirectories = Vec::new();
+        for (index, entry) in directory.entries().enumerate() {
+            if let Ok(PspDirectoryRomId::SpiCs2) = entry.rom_id_or_err() {
+                continue;
+            }
+            let Ok(beginning) = directory.payload_beginning(&entry) else {
+                continue;
+            };
+            match entry.typ_or_err() {
+                Ok(
+                    PspDirectoryEntryType::SecondLevelDirectory
+                    | PspDirectoryEntryType::SecondLevelAPspDirectory
+                    | PspDirectoryEntryType::SecondLevelBPspDirectory,
+                ) if first_level => {
+                    let subdirectory =
+                        PspDirectoryWithCapacity::<MAX_ENTRIES>::load(
+                            self.storage,
+                            beginning,
+                            directory.beginning,
+                            self.amd_physical_mode_mmio_size,
+                        )?;
+                    subdirectories.push((
+                        index,
+                        self.load_psp_tree(subdirectory, false)?,
+                    ));
+                }
+                Ok(PspDirectoryEntryType::SecondLevelBhdDirectory) => {
+                    let subdirectory =
+                        BhdDirectoryWithCapacity::<MAX_ENTRIES>::load(
+                            self.storage,
+                            beginning,
+                            directory.beginning,
+                            self.amd_physical_mode_mmio_size,
+                        )?;
+                    subdirectories.push((
+                        index,
+                        self.load_bhd_tree(subdirectory, false)?,
+                    ));
+                }
+                _ => {}
+            }
+        }
+        Ok(DirectoryTree {
+            directory: AnyDirectory::Psp(Box::new(directory)),
+            subdirectories,
+        })
+    }
+
+    /// Loads the directories that DIRECTORY refers to (see
+    /// visit_bhd_directory).
+    #[cfg(feature = "std")]
+    fn load_bhd_tree(
+        &self,
+        directory: BhdDirectoryWithCapacity<MAX_ENTRIES>,
+        first_level: bool,
+    ) -> Result<DirectoryTree<MAX_ENTRIES>> {
+        let mut subdirectories = Vec::new();
+        if first_level {
+            for (index, entry) in directory.entries().enumerate() {
+                if let Ok(BhdDirectoryRomId::SpiCs2) = entry.rom_id_or_err() {
+                    continue;
+                }
+                let Ok(beginning) = directory.payload_beginning(&entry) else {
+                    continue;
+                };
+                if let Ok(BhdDirectoryEntryType::SecondLevelDirectory) =
+                    entry.typ_or_err()
+                {
+                    let subdirectory =
+                        BhdDirectoryWithCapacity::<MAX_ENTRIES>::load(
+                            self.storage,
+                            beginning,
+                            directory.beginning,
+                            self.amd_physical_mode_mmio_size,
+                        )?;
+                    subdirectories.push((
+                        index,
+                        self.load_bhd_tree(subdirectory, false)?,
+                    ));
+                }
+            }
+        }
+        Ok(DirectoryTree {
+            directory: AnyDirectory::Bhd(Box::new(directory)),
+            subdirectories,
+        })
+    }
+
+    /// Loads the (first-level) directories that DIRECTORY refers to--BHD
+    /// directories if BHD, PSP directories otherwise--and the directories
+    /// those refer to.
+    #[cfg(feature = "std")]
+    fn load_combo_tree(
+        &self,
+        directory: ComboDirectoryWithCapacity<MAX_ENTRIES>,
+        bhd: bool,
+    ) -> Result<DirectoryTree<MAX_ENTRIES>> {
+        let mut subdirectories = Vec::new();
+        for (index, entry) in directory.entries().enumerate() {
+            let subdirectory = if bhd {
+                self.load_bhd_tree(
+                    self.bhd_combo_subdirectory(&directory, &entry)?,
+                    true,
+                )?
+            } else {
+                self.load_psp_tree(
+                    self.psp_combo_subdirectory(&directory, &entry)?,
+                    true,
+                )?
+            };
+            subdirectories.push((index, subdirectory));
+        }
+        Ok(DirectoryTree {
+            directory: AnyDirectory::Combo(Box::new(directory)),
+            subdirectories,
+        })
+    }
+
+    /// Writes all the directories in TREE to new ranges taken from
+    /// ALLOCATOR, updating the entries that refer to the subdirectories
+    /// accordingly, and records that in RELOCATIONS.
+    /// OTHER_DIRECTORY_BEGINNING is the (new) beginning of the directory
+    /// that refers to the one at the root of TREE, if any.
+    /// Returns where the directory at the root of TREE begins now.
+    #[cfg(feature = "std")]
+    fn relocate_tree(
+        &self,
+        tree: &mut DirectoryTree<MAX_ENTRIES>,
+        other_directory_beginning: Option<Location>,
+        allocator: &mut impl FlashAllocate,
+        relocations: &mut DirectoryRelocations,
+    ) -> Result<Location> {
+        let (beginning, size) = tree.extent()?;
+        let range = self.take_payload_range(
+            self.storage,
+            allocator,
+            size,
+            PlacementConstraints::DIRECTORY,
+        )?;
+        relocations.staged.push(range);
+        let new_beginning = Location::from(range.beginning);
+        let other_directory_beginning =
+            other_directory_beginning.unwrap_or(new_beginning);
+        let mut subdirectories = Vec::new();
+        for (index, subdirectory) in &mut tree.subdirectories {
+            subdirectories.push((
+                *index,
+                self.relocate_tree(
+                    subdirectory,
+                    Some(new_beginning),
+                    allocator,
+                    relocations,
+                )?,
+            ));
+        }
+        match &mut tree.directory {
+            AnyDirectory::Psp(directory) => directory.rewrite_to(
+                self.storage,
+                &range,
+                other_directory_beginning,
+                &subdirectories,
+            )?,
+            AnyDirectory::Bhd(directory) => directory.rewrite_to(
+                self.storage,
+                &range,
+                other_directory_beginning,
+                &subdirectories,
+            )?,
+            AnyDirectory::Combo(directory) => directory.rewrite_to(
+                self.storage,
+                &range,
+                other_directory_beginning,
+                &subdirectories,
+            )?,
+        }
+        let block_beginning = self.storage.erasable_location(
+            beginning & !self.storage.erasable_block_mask(),
+        )?;
+        relocations.retired.push(ErasableRange::new(
+            block_beginning,
+            block_beginning.advance_at_least(
+                (beginning - Location::from(block_beginning)) as usize + size,
+            )?,
+        ));
+        relocations.relocated.push((
+            beginning,
+            new_beginning,
+            other_directory_beginning,
+        ));
+        Ok(new_beginning)
+    }
+
+    pub fn create_second_level_psp_directory(
+        &self,
+        beginning: ErasableLocation,
+        end: ErasableLocation,
+        entries: &[PspDirectoryEntry],
+    ) -> Result<PspDirectoryWithCapacity<MAX_ENTRIES>> {
+        let mut psp_directory = self.psp_directory()?;
+        self.create_psp_subdirectory(
+            &mut psp_directory,
+            beginning,
+            end,
+            self.amd_physical_mode_mmio_size,
+            entries,
+        )
+    }
+}
+
+impl<'a, T: AsyncFlashRead + FlashAlign> Efs<'a, T> {
+    /// Like load, but for flash that is accessed asynchronously.
+    pub async fn load_async(
+        storage: &'a T,
+        processor_generation: Option<ProcessorGeneration>,
+        amd_physical_mode_mmio_size: Option<u32>,
+    ) -> Result<Self> {
+        let (efh_beginning, efh) =
+            Self::load_efh(storage, processor_generation).await?;
+        Ok(Self {
+            storage,
+            spi_cs2_storage: None,
+            efh_beginning,
+            efh,
+            amd_physical_mode_mmio_size,
+        })
+    }
+}
+
+impl<'a, T: AsyncFlashRead + FlashAlign, const MAX_ENTRIES: usize>
+    Efs<'a, T, MAX_ENTRIES>
+{
+    /// Like psp_directory, but for flash that is accessed asynchronously.
+    pub async fn psp_directory_async(
+        &self,
+    ) -> Result<PspDirectoryWithCapacity<MAX_ENTRIES>> {
+        let psp_directory_table_location =
+            self.psp_directory_table_location()?;
+        let directory = PspDirectoryWithCapacity::<MAX_ENTRIES>::load_async(
+            self.storage,
+            psp_directory_table_location,
+            psp_directory_table_location,
+            self.amd_physical_mode_mmio_size,
+        )
+        .await?;
+        if directory.header.cookie != PspDirectoryHeader::FIRST_LEVEL_COOKIE {
+            return Err(Error::DirectoryTypeMismatch);
+        }
+        Ok(directory)
+    }
+
+    /// Like psp_combo_directory, but for flash that is accessed
+    /// asynchronously.
+    pub async fn psp_combo_directory_async(
+        &self,
+    ) -> Result<ComboDirectoryWithCapacity<MAX_ENTRIES>> {
+        let psp_directory_table_location =
+            self.psp_directory_table_location()?;
+        let directory = ComboDirectoryWithCapacity::<MAX_ENTRIES>::load_async(
+            self.storage,
+            psp_directory_table_location,
+            psp_directory_table_location,
+            self.amd_physical_mode_mmio_size,
+        )
+        .await?;
+        if directory.header.cookie != ComboDirectoryHeader::PSP_COOKIE {
+            return Err(Error::DirectoryTypeMismatch);
+        }
+        Ok(directory)
+    }
+
+    /// Like bhd_directory, but for flash that is accessed asynchronously.
+    pub async fn bhd_directory_async(
+        &self,
+        processor_generation: Option<ProcessorGeneration>,
+    ) -> Result<BhdDirectoryWithCapacity<MAX_ENTRIES>> {
+        let bhd_directory_table_location = self
+            .bhd_directories(processor_generation)?
+            .next()
+            .ok_or(Error::BhdDirectoryHeaderNotFound)?;
+        let directory = BhdDirectoryWithCapacity::<MAX_ENTRIES>::load_async(
+            self.storage,
+            bhd_directory_table_location,
+            bhd_directory_table_location,
+            self.amd_physical_mode_mmio_size,
+        )
+        .await?;
+        if directory.header.cookie != BhdDirectoryHeader::FIRST_LEVEL_COOKIE {
+            return Err(Error::DirectoryTypeMismatch);
+        }
+        Ok(directory)
+    }
+
+    /// Like bhd_combo_directory, but for flash that is accessed
+    /// asynchronously.
+    pub async fn bhd_combo_directory_async(
+        &self,
+        processor_generation: Option<ProcessorGeneration>,
+    ) -> Result<ComboDirectoryWithCapacity<MAX_ENTRIES>> {
+        let bhd_directory_table_location = self
+            .bhd_directories(processor_generation)?
+            .next()
+            .ok_or(Error::BhdDirectoryHeaderNotFound)?;
+        let directory = ComboDirectoryWithCapacity::<MAX_ENTRIES>::load_async(
+            self.storage,
+            bhd_directory_table_location,
+            bhd_directory_table_location,
+            self.amd_physical_mode_mmio_size,
+        )
+        .await?;
+        if directory.header.cookie != ComboDirectoryHeader::BHD_COOKIE {
+            return Err(Error::DirectoryTypeMismatch);
+        }
+        Ok(directory)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EfhBulldozerSpiMode, EfhNaplesSpiMode, EfhRomeSpiMode};
+    use crate::Efh;
+    use crate::Efs;
+    use crate::Error;
+    use crate::flash;
+    use crate::ondisk::{
+        SpiFastSpeedNew, SpiNaplesMicronMode, SpiReadMode, SpiRomeMicronMode,
+    };
+    #[cfg(feature = "std")]
+    use flash::{Fault, FaultInjectingFlash};
+    use flash::{FlashAlign, FlashWrite, MemoryFlash};
+
+    type Storage = MemoryFlash<[u8; 256]>;
+    #[cfg(feature = "std")]
+    type ImageStorage = MemoryFlash<[u8; 0x5_0000]>;
+
+    fn setup_efs_test(storage: &Storage) -> Efs<'_, Storage> {
+        let efh_beginning = storage.erasable_location(0).unwrap();
+        Efs {
+            amd_physical_mode_mmio_size: None,
+            efh: Efh::default(),
+            efh_beginning,
+            storage,
+            spi_cs2_storage: None,
+        }
+    }
+
+    #[test]
+    fn test_payload_storage_by_rom_id() -> Result<(), Error> {
+        use crate::PspDirectory;
+        use crate::ondisk::{
+            AddressMode, PspDirectoryEntry, PspDirectoryEntryType,
+            PspDirectoryHeader, PspDirectoryRomId, ValueOrLocation,
+        };
+        use flash::FlashRead;
+        let storage = Storage::new([0xff; 256], 16);
+        let spi_cs2_storage = Storage::new([0xff; 256], 16);
+        spi_cs2_storage.erase_and_write_block(
+            spi_cs2_storage.erasable_location(0x40)?,
+            &[0x42; 16],
+        )?;
+        let mut efs = setup_efs_test(&storage);
+        let mut entry = PspDirectoryEntry::new_payload(
+            AddressMode::EfsRelativeOffset,
+            PspDirectoryEntryType::PspBootloader,
+            Some(16),
+            Some(ValueOrLocation::EfsRelativeOffset(0x40)),
+        )?;
+        assert!(core::ptr::eq(efs.psp_payload_storage(&entry)?, &storage));
+        entry.set_rom_id(PspDirectoryRomId::SpiCs2);
+        assert!(matches!(
+            efs.psp_payload_storage(&entry),
+            Err(Error::FlashChipNotFound)
+        ));
+        efs.set_spi_cs2_storage(Some(&spi_cs2_storage));
+        let mut buf = [0u8; 16];
+        efs.psp_payload_storage(&entry)?.read_exact(0x40, &mut buf)?;
+        assert_eq!(buf, [0x42; 16]);
+
+        let mut directory = PspDirectory::create(
+            0x80,
+            0x80,
+            AddressMode::EfsRelativeOffset,
+            PspDirectoryHeader::FIRST_LEVEL_COOKIE,
+            None,
+            &[],
+        )?;
+        directory.add_entry(&entry)?;
+        let mut buf = [0u8; 16];
+        assert_eq!(efs.read_psp_payload(&directory, &entry, &mut buf)?, 16);
+        assert_eq!(buf, [0x42; 16]);
+        assert_eq!(efs.psp_payload_reader(&directory, &entry)?.size(), 16);
+        efs.set_spi_cs2_storage(None);
+        assert!(matches!(
+            efs.read_psp_payload(&directory, &entry, &mut buf),
+            Err(Error::FlashChipNotFound)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_directory_capacity() -> Result<(), Error> {
+        use super::Directory;
+        use crate::ondisk::{
+            AddressMode, DirectoryAdditionalInfo, DirectoryHeader,
+            PspDirectoryEntry, PspDirectoryEntryType, PspDirectoryHeader,
+        };
+        use core::mem::size_of;
+        type SmallPspDirectory = Directory<
+            PspDirectoryHeader,
+            PspDirectoryEntry,
+            { size_of::<PspDirectoryHeader>() },
+            { size_of::<PspDirectoryEntry>() },
+            2,
+        >;
+        let entry = PspDirectoryEntry::new_value(
+            PspDirectoryEntryType::PspSoftFuseChain,
+            1,
+        )?;
+        let mut directory = SmallPspDirectory::create(
+            0,
+            0,
+            AddressMode::EfsRelativeOffset,
+            PspDirectoryHeader::FIRST_LEVEL_COOKIE,
+            None,
+            &[entry, entry],
+        )?;
+        assert!(matches!(
+            directory.add_entry_direct(&entry),
+            Err(Error::DirectoryRangeCheck)
+        ));
+
+        type LargePspDirectory = Directory<
+            PspDirectoryHeader,
+            PspDirectoryEntry,
+            { size_of::<PspDirectoryHeader>() },
+            { size_of::<PspDirectoryEntry>() },
+            300,
+        >;
+        let mut directory = LargePspDirectory::create(
+            0,
+            0,
+            AddressMode::EfsRelativeOffset,
+            PspDirectoryHeader::FIRST_LEVEL_COOKIE,
+            None,
+            &[],
+        )?;
+        assert_eq!(directory.max_entries(), 300);
+        directory.header.set_additional_info(
+            DirectoryAdditionalInfo::new()
+                .with_max_size_checked(1)
+                .unwrap()
+                .with_address_mode(AddressMode::EfsRelativeOffset),
+        );
+        assert_eq!(directory.max_entries(), 255);
+        for _ in 0..255 {
+            directory.add_entry_direct(&entry)?;
+        }
+        assert!(matches!(
+            directory.add_entry_direct(&entry),
+            Err(Error::DirectoryRangeCheck)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_directory_checksum() -> Result<(), Error> {
+        use crate::ProcessorGeneration;
+        use crate::ondisk::{
+            AddressMode, PspDirectoryEntry, PspDirectoryEntryType,
+            PspDirectoryHeader,
+        };
+        use crate::{DirectoryHeader, PspDirectory};
+        use flash::{ErasableRange, NorSimulator};
+        let storage = NorSimulator::new(0x10_0000, 0x1000);
+        let mut efs =
+            Efs::create(&storage, ProcessorGeneration::Genoa, 0x2_0000, None)?;
+        let beginning = storage.erasable_location(0x4_0000)?;
+        let end = beginning.advance(0x1000)?;
+        let mut directory = efs.create_psp_directory(
+            PspDirectoryHeader::FIRST_LEVEL_COOKIE,
+            beginning,
+            end,
+            None,
+            AddressMode::EfsRelativeOffset,
+            &[PspDirectoryEntry::new_value(
+                PspDirectoryEntryType::PspSoftFuseChain,
+                1,
+            )?],
+        )?;
+        let mut buf =
+            directory.save(0x1000, &ErasableRange::new(beginning, end), end)?;
+        storage.erase_and_write_blocks(beginning, &buf)?;
+        efs.set_main_psp_directory(&directory)?;
+        let expected = efs.psp_directory()?.header().checksum();
+
+        // Flip a bit in the value of the entry.
+        buf[0x18] ^= 1;
+        storage.erase_and_write_blocks(beginning, &buf)?;
+        match efs.psp_directory() {
+            Err(Error::ChecksumMismatch { expected: e, actual }) => {
+                assert_eq!(e, expected);
+                assert_ne!(actual, expected);
+            }
+            _ => panic!("expected checksum mismatch"),
+        }
+        let directory =
+            PspDirectory::load_unchecked(&storage, 0x4_0000, 0x4_0000, None)?;
+        assert_eq!(directory.header().checksum(), expected);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_directory_write_to() -> Result<(), Error> {
+        use crate::ProcessorGeneration;
+        use crate::PspDirectory;
+        use crate::ondisk::{
+            AddressMode, DirectoryHeader, PspDirectoryEntry,
+            PspDirectoryEntryType, PspDirectoryHeader,
+        };
+        use flash::{ErasableRange, NorSimulator};
+        let storage = NorSimulator::new(0x10_0000, 0x1000);
+        let mut efs =
+            Efs::create(&storage, ProcessorGeneration::Genoa, 0x2_0000, None)?;
+        let beginning = storage.erasable_location(0x4_0000)?;
+        let end = beginning.advance(0x2000)?;
+        let mut directory = efs.create_psp_directory(
+            PspDirectoryHeader::FIRST_LEVEL_COOKIE,
+            beginning,
+            end,
+            None,
+            AddressMode::EfsRelativeOffset,
+            &[PspDirectoryEntry::new_value(
+                PspDirectoryEntryType::PspSoftFuseChain,
+                1,
+            )?],
+        )?;
+        directory.write_to(&storage, &ErasableRange::new(beginning, end))?;
+        efs.set_main_psp_directory(&directory)?;
+
+        let directory = PspDirectory::load(&storage, 0x4_0000, 0x4_0000, None)?;
+        assert_eq!(directory.header().additional_info().max_size(), 2);
+        assert_eq!(directory.directory_size()?, 0x2000);
+        let entries = directory.entries().collect::<Vec<_>>();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].value()?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_directory_write_to_memory_flash() -> Result<(), Error> {
+        use crate::PspDirectory;
+        use crate::flash::MemoryFlash;
+        use crate::ondisk::{
+            AddressMode, PspDirectoryEntry, PspDirectoryEntryType,
+            PspDirectoryHeader,
+        };
+        use flash::{ErasableRange, FlashRead};
+        let storage = MemoryFlash::new([0u8; 0x4000], 0x1000);
+        let beginning = storage.erasable_location(0x1000)?;
+        let range = ErasableRange::new(beginning, beginning.advance(0x2000)?);
+        let mut directory = PspDirectory::create(
+            0x1000,
+            0,
+            AddressMode::EfsRelativeOffset,
+            PspDirectoryHeader::FIRST_LEVEL_COOKIE,
+            None,
+            &[PspDirectoryEntry::new_value(
+                PspDirectoryEntryType::PspSoftFuseChain,
+                1,
+            )?],
+        )?;
+        directory.write_to(&storage, &range)?;
+
+        let directory = PspDirectory::load(&storage, 0x1000, 0, None)?;
+        assert_eq!(directory.directory_size()?, 0x2000);
+        let entry = directory.entries().next().unwrap();
+        assert_eq!(entry.value()?, 1);
+        let mut buf = [0u8; 4];
+        storage.read_exact(0x2ffc, &mut buf)?;
+        assert_eq!(buf, [0xff; 4]);
+        storage.read_exact(0, &mut buf)?;
+        assert_eq!(buf, [0; 4]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_directory_entries_by_key() -> Result<(), Error> {
+        use crate::BhdDirectory;
+        use crate::ondisk::{
+            AddressMode, BhdDirectoryEntry, BhdDirectoryEntryType,
+            BhdDirectoryHeader, BhdDirectoryRomId, DirectoryEntry,
+            DirectoryEntryKey, KeyedDirectoryEntry, ValueOrLocation,
+        };
+        let apcb = |instance: u8, source: u32| {
+            let mut entry = BhdDirectoryEntry::new_payload(
+                AddressMode::EfsRelativeOffset,
+                BhdDirectoryEntryType::Apcb,
+                Some(0x10),
+                Some(ValueOrLocation::EfsRelativeOffset(source)),
+                None,
+            )
+            .unwrap();
+            entry.set_instance(instance);
+            entry
+        };
+        let mut directory = BhdDirectory::create(
+            0x4_0000,
+            0,
+            AddressMode::EfsRelativeOffset,
+            BhdDirectoryHeader::FIRST_LEVEL_COOKIE,
+            None,
+            &[apcb(0, 0x5_0000), apcb(1, 0x6_0000), apcb(2, 0x7_0000)],
+        )?;
+        assert!(matches!(
+            directory.add_entry(&apcb(1, 0x8_0000)),
+            Err(Error::Duplicate)
+        ));
+        let key = DirectoryEntryKey {
+            type_: BhdDirectoryEntryType::Apcb,
+            sub_program: 0,
+            instance: 1,
+            rom_id: BhdDirectoryRomId::SpiCs1,
+        };
+        let old = directory.replace_entry(&apcb(1, 0x8_0000))?;
+        assert_eq!(directory.payload_beginning(&old)?, 0x6_0000);
+        directory.entry_mut(&key)?.set_size(Some(0x20));
+        let removed = directory.remove_entry(&key)?;
+        assert_eq!(removed.size(), Some(0x20));
+        assert_eq!(directory.payload_beginning(&removed)?, 0x8_0000);
+        assert!(matches!(
+            directory.remove_entry(&key),
+            Err(Error::EntryNotFound)
+        ));
+        assert!(
+            directory
+                .entries()
+                .map(|entry| entry.key().unwrap().instance)
+                .eq([0, 2])
+        );
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_read_payload() -> Result<(), Error> {
+        use crate::PspDirectory;
+        use crate::ondisk::{
+            AddressMode, PspDirectoryEntry, PspDirectoryEntryType,
+            PspDirectoryHeader, ValueOrLocation,
+        };
+        use flash::{ErasableRange, NorSimulator};
+        let storage = NorSimulator::new(0x10_0000, 0x1000);
+        let payload = (0..0x1800).map(|i| (i / 7) as u8).collect::<Vec<u8>>();
+        storage.erase_and_write_blocks(
+            storage.erasable_location(0x5_0000)?,
+            &payload,
+        )?;
+        let entry = |type_, size, source| {
+            PspDirectoryEntry::new_payload(
+                AddressMode::EfsRelativeOffset,
+                type_,
+                Some(size),
+                Some(ValueOrLocation::EfsRelativeOffset(source)),
+            )
+            .unwrap()
+        };
+        let entries = [
+            entry(PspDirectoryEntryType::PspBootloader, 0x1800, 0x5_0000),
+            // Inside the directory
+            entry(PspDirectoryEntryType::PspTrustlets, 0x10, 0x4_0800),
+            // Beyond the end of the flash
+            entry(PspDirectoryEntryType::PspNvdata, 0x2000, 0xf_f000),
+        ];
+        let mut directory = PspDirectory::create(
+            0x4_0000,
+            0x4_0000,
+            AddressMode::EfsRelativeOffset,
+            PspDirectoryHeader::FIRST_LEVEL_COOKIE,
+            None,
+            &entries,
+        )?;
+        let beginning = storage.erasable_location(0x4_0000)?;
+        directory.write_to(
+            &storage,
+            &ErasableRange::new(beginning, beginning.advance(0x1000)?),
+        )?;
+
+        let mut buf = vec![0; 0x2000];
+        assert_eq!(
+            directory.read_payload(&storage, &entries[0], &mut buf)?,
+            0x1800
+        );
+        assert_eq!(buf[..0x1800], payload);
+        assert!(matches!(
+            directory.read_payload(&storage, &entries[0], &mut buf[..0x100]),
+            Err(Error::DirectoryPayloadRangeCheck)
+        ));
+        let mut reader = directory.payload_reader(&storage, &entries[0])?;
+        let mut chunks = Vec::new();
+        let mut chunk = [0u8; 0x500];
+        loop {
+            let size = reader.read(&mut chunk)?;
+            if size == 0 {
+                break;
+            }
+            chunks.extend_from_slice(&chunk[..size]);
+        }
+        assert_eq!(chunks, payload);
+        assert_eq!(reader.remaining(), 0);
+        for entry in &entries[1..] {
+            assert!(matches!(
+                directory.payload_reader(&storage, entry),
+                Err(Error::DirectoryPayloadRangeCheck)
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_directory_contents_elsewhere() -> Result<(), Error> {
+        use crate::PspDirectory;
+        use crate::ondisk::{
+            AddressMode, DirectoryHeader, PspDirectoryEntry,
+            PspDirectoryEntryType, PspDirectoryHeader, ValueOrLocation,
+        };
+        use flash::{ErasableRange, NorSimulator};
+        let storage = NorSimulator::new(0x10_0000, 0x1000);
+        storage.erase_and_write_blocks(
+            storage.erasable_location(0x8_2000)?,
+            &[0x42; 0x10],
+        )?;
+        let mut directory = PspDirectory::create(
+            0x4_0000,
+            0x4_0000,
+            AddressMode::DirectoryRelativeOffset,
+            PspDirectoryHeader::FIRST_LEVEL_COOKIE,
+            None,
+            &[],
+        )?;
+        directory.set_contents_beginning(Some(0x8_0000));
+        let source = directory.source_for_location(0x8_2000)?;
+        assert!(matches!(
+            source,
+            ValueOrLocation::DirectoryRelativeOffset(0x2000)
+        ));
+        directory.add_entry(&PspDirectoryEntry::new_payload(
+            AddressMode::DirectoryRelativeOffset,
+            PspDirectoryEntryType::PspBootloader,
+            Some(0x10),
+            Some(source),
+        )?)?;
+        let beginning = storage.erasable_location(0x4_0000)?;
+        directory.write_to(
+            &storage,
+            &ErasableRange::new(beginning, beginning.advance(0x1000)?),
+        )?;
+
+        let directory = PspDirectory::load(&storage, 0x4_0000, 0x4_0000, None)?;
+        assert_eq!(directory.header().additional_info().base_address(), 0x80);
+        assert_eq!(directory.contents_beginning(), 0x8_0000);
+        let entry = directory.entries().next().unwrap();
+        assert_eq!(directory.payload_beginning(&entry)?, 0x8_2000);
+        let mut buf = [0; 0x10];
+        directory.read_payload(&storage, &entry, &mut buf)?;
+        assert_eq!(buf, [0x42; 0x10]);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_other_directory_relative_offset() -> Result<(), Error> {
+        use crate::ProcessorGeneration;
+        use crate::ondisk::{
+            AddressMode, PspDirectoryEntry, PspDirectoryEntryType,
+            PspDirectoryHeader, ValueOrLocation,
+        };
+        use flash::{ErasableRange, NorSimulator};
+        let storage = NorSimulator::new(0x10_0000, 0x1000);
+        storage.erase_and_write_blocks(
+            storage.erasable_location(0x7_0000)?,
+            &[0x42; 0x10],
+        )?;
+        let mut efs =
+            Efs::create(&storage, ProcessorGeneration::Genoa, 0x2_0000, None)?;
+
+        // Second-level directory, with payloads relative to the first-level
+        // directory.
+        let beginning = storage.erasable_location(0x6_0000)?;
+        let range = ErasableRange::new(beginning, beginning.advance(0x1000)?);
+        let mut directory = efs.create_psp_directory(
+            *b"$PL2",
+            beginning,
+            range.end,
+            Some(0x4_0000),
+            AddressMode::OtherDirectoryRelativeOffset,
+            &[PspDirectoryEntry::new_payload(
+                AddressMode::OtherDirectoryRelativeOffset,
+                PspDirectoryEntryType::PspBootloader,
+                Some(0x10),
+                Some(ValueOrLocation::OtherDirectoryRelativeOffset(0x3_0000)),
+            )?],
+        )?;
+        assert_eq!(directory.other_directory_beginning(), 0x4_0000);
+        assert_eq!(
+            directory
+                .payload_beginning(&directory.entries().next().unwrap())?,
+            0x7_0000
+        );
+        directory.write_to(&storage, &range)?;
+
+        let beginning = storage.erasable_location(0x4_0000)?;
+        let range = ErasableRange::new(beginning, beginning.advance(0x1000)?);
+        let mut directory = efs.create_psp_directory(
+            PspDirectoryHeader::FIRST_LEVEL_COOKIE,
+            beginning,
+            range.end,
+            None,
+            AddressMode::DirectoryRelativeOffset,
+            &[PspDirectoryEntry::new_payload(
+                AddressMode::DirectoryRelativeOffset,
+                PspDirectoryEntryType::SecondLevelDirectory,
+                Some(0x1000),
+                Some(ValueOrLocation::EfsRelativeOffset(0x6_0000)),
+            )?],
+        )?;
+        directory.write_to(&storage, &range)?;
+        efs.set_main_psp_directory(&directory)?;
+
+        let efs = Efs::load(&storage, Some(ProcessorGeneration::Genoa), None)?;
+        let directory = efs.psp_directory()?;
+        assert_eq!(directory.other_directory_beginning(), 0x4_0000);
+        let subdirectory = efs.psp_subdirectory(&directory)?;
+        assert_eq!(
+            subdirectory.directory_address_mode(),
+            AddressMode::OtherDirectoryRelativeOffset
+        );
+        assert_eq!(subdirectory.other_directory_beginning(), 0x4_0000);
+        let entry = subdirectory.entries().next().unwrap();
+        assert_eq!(subdirectory.payload_beginning(&entry)?, 0x7_0000);
+        let mut buf = [0; 0x10];
+        subdirectory.read_payload(&storage, &entry, &mut buf)?;
+        assert_eq!(buf, [0x42; 0x10]);
+        assert!(matches!(
+            subdirectory.source_for_location(0x8_0000)?,
+            ValueOrLocation::OtherDirectoryRelativeOffset(0x4_0000)
+        ));
+        let mut used = Vec::new();
+        efs.visit_used_ranges(|beginning, size| used.push((beginning, size)))?;
+        assert!(used.contains(&(0x7_0000, 0x10)));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_create_psp_subdirectory() -> Result<(), Error> {
+        use crate::ProcessorGeneration;
+        use crate::ondisk::{AddressMode, DirectoryEntry, ValueOrLocation};
+        use flash::NorSimulator;
+        let storage = NorSimulator::new(0x10_0000, 0x1000);
+        create_genoa_efs_with_directories(
+            &storage,
+            AddressMode::DirectoryRelativeOffset,
+            &[],
+            AddressMode::EfsRelativeOffset,
+            &[],
+        )?;
+        let efs = Efs::load(&storage, Some(ProcessorGeneration::Genoa), None)?;
+        let mut directory = efs.psp_directory()?;
+        let beginning = storage.erasable_location(0x8_0000)?;
+        let subdirectory = efs.create_psp_subdirectory(
+            &mut directory,
+            beginning,
+            beginning.advance(0x1000)?,
+            None,
+            &[],
+        )?;
+        assert_eq!(subdirectory.other_directory_beginning(), 0x4_0000);
+        let entry = directory.entries().next().unwrap();
+        assert!(matches!(
+            entry.source(AddressMode::DirectoryRelativeOffset)?,
+            ValueOrLocation::DirectoryRelativeOffset(0x4_0000)
+        ));
+        assert_eq!(directory.payload_beginning(&entry)?, 0x8_0000);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_visit_used_ranges_skips_unlocatable() -> Result<(), Error> {
+        use crate::ProcessorGeneration;
+        use crate::allocators::FreeListFlashAllocator;
+        use crate::ondisk::{
+            AddressMode, PspDirectoryEntry, PspDirectoryEntryType,
+            ValueOrLocation,
+        };
+        use flash::{ErasableRange, NorSimulator};
+        let storage = NorSimulator::new(0x10_0000, 0x1000);
+        create_genoa_efs_with_directories(
+            &storage,
+            AddressMode::DirectoryRelativeOffset,
+            &[
+                // No MMIO size is known, so this cannot be located.
+                PspDirectoryEntry::new_payload(
+                    AddressMode::DirectoryRelativeOffset,
+                    PspDirectoryEntryType::PspBootloader,
+                    Some(0x10),
+                    Some(ValueOrLocation::PhysicalAddress(0xff05_0000)),
+                )?,
+                PspDirectoryEntry::new_payload(
+                    AddressMode::DirectoryRelativeOffset,
+                    PspDirectoryEntryType::PspTrustlets,
+                    Some(0x10),
+                    Some(ValueOrLocation::EfsRelativeOffset(0x7_0000)),
+                )?,
+            ],
+            AddressMode::EfsRelativeOffset,
+            &[],
+        )?;
+
+        let efs = Efs::load(&storage, Some(ProcessorGeneration::Genoa), None)?;
+        let directory = efs.psp_directory()?;
+        let entry = directory.entries().next().unwrap();
+        assert!(directory.payload_beginning(&entry).is_err());
+        let mut used = Vec::new();
+        efs.visit_used_ranges(|beginning, size| used.push((beginning, size)))?;
+        assert!(used.contains(&(0x7_0000, 0x10)));
+        let arena_beginning = storage.erasable_location(0)?;
+        let arena = ErasableRange::new(
+            arena_beginning,
+            arena_beginning.advance(0x10_0000)?,
+        );
+        FreeListFlashAllocator::from_efs(&efs, arena)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_spi_mode_all_off() {
+        let storage = Storage::new([0xff; 256], 16);
+        let mut setup = setup_efs_test(&storage);
+        assert!(setup.spi_mode_bulldozer().unwrap().is_none());
+        assert!(setup.spi_mode_zen_naples().unwrap().is_none());
+        assert!(setup.spi_mode_zen_rome().unwrap().is_none());
+        setup.set_spi_mode_bulldozer(None);
+        setup.set_spi_mode_zen_naples(None);
+        setup.set_spi_mode_zen_rome(None);
+        assert!(setup.spi_mode_bulldozer().unwrap().is_none());
+        assert!(setup.spi_mode_zen_naples().unwrap().is_none());
+        assert!(setup.spi_mode_zen_rome().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_spi_mode_bulldozer() -> Result<(), Error> {
+        let storage = Storage::new([0xff; 256], 16);
+        let mut setup = setup_efs_test(&storage);
+        let spi_mode = EfhBulldozerSpiMode {
+            read_mode: SpiReadMode::Dual112,
+            fast_speed_new: SpiFastSpeedNew::_33_33MHz,
+        };
+        setup.set_spi_mode_bulldozer(Some(spi_mode));
+        assert!(
+            setup.spi_mode_bulldozer()?.unwrap().read_mode
+                == SpiReadMode::Dual112
+        );
+        assert!(setup.spi_mode_zen_naples()?.is_none());
+        assert!(setup.spi_mode_zen_rome()?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_spi_mode_zen_naples() -> Result<(), Error> {
+        let storage = Storage::new([0xff; 256], 16);
+        let mut setup = setup_efs_test(&storage);
+        let spi_mode = EfhNaplesSpiMode {
             read_mode: SpiReadMode::Dual112,
             fast_speed_new: SpiFastSpeedNew::_33_33MHz,
             micron_mode: SpiNaplesMicronMode::DummyCycle,
@@ -1091,7 +3423,7 @@ mod tests {
 
     #[test]
     fn test_spi_mode_zen_rome() -> Result<(), Error> {
-        let storage = Storage {};
+        let storage = Storage::new([0xff; 256], 16);
         let mut setup = setup_efs_test(&storage);
         let spi_mode = EfhRomeSpiMode {
             read_mode: SpiReadMode::Dual112,
@@ -1107,4 +3439,1019 @@ mod tests {
         );
         Ok(())
     }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_create_on_nor_flash() -> Result<(), Error> {
+        use crate::AddressMode;
+        use crate::ProcessorGeneration;
+        use crate::PspDirectoryHeader;
+        use flash::NorSimulator;
+        let storage = NorSimulator::new(0x10_0000, 0x1000);
+        let mut efs =
+            Efs::create(&storage, ProcessorGeneration::Genoa, 0x2_0000, None)?;
+        let beginning = storage.erasable_location(0x4_0000)?;
+        let end = beginning.advance(0x1000)?;
+        let psp_directory = efs.create_psp_directory(
+            PspDirectoryHeader::FIRST_LEVEL_COOKIE,
+            beginning,
+            end,
+            None,
+            AddressMode::EfsRelativeOffset,
+            &[],
+        )?;
+        efs.set_main_psp_directory(&psp_directory)?;
+        assert_eq!(
+            storage.erase_count(storage.erasable_location(0x2_0000)?)?,
+            2
+        );
+        assert_eq!(storage.total_erase_count(), 2);
+        let efs = Efs::load(&storage, Some(ProcessorGeneration::Genoa), None)?;
+        assert!(
+            efs.compatible_with_processor_generation(
+                ProcessorGeneration::Genoa
+            )
+        );
+        Ok(())
+    }
+
+    /// Flash that is pending once on every read before it is ready.
+    #[cfg(feature = "std")]
+    struct YieldingFlash<'a, T>(&'a T);
+
+    #[cfg(feature = "std")]
+    impl<T: flash::FlashRead> flash::AsyncFlashRead for YieldingFlash<'_, T> {
+        async fn read_exact(
+            &self,
+            beginning: flash::Location,
+            buffer: &mut [u8],
+        ) -> Result<(), flash::Error> {
+            let mut yielded = false;
+            core::future::poll_fn(|context| {
+                if yielded {
+                    core::task::Poll::Ready(())
+                } else {
+                    yielded = true;
+                    context.waker().wake_by_ref();
+                    core::task::Poll::Pending
+                }
+            })
+            .await;
+            self.0.read_exact(beginning, buffer)
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl<T: FlashAlign> FlashAlign for YieldingFlash<'_, T> {
+        fn erasable_block_size(&self) -> usize {
+            self.0.erasable_block_size()
+        }
+    }
+
+    #[cfg(feature = "std")]
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = core::pin::pin!(future);
+        let mut context =
+            core::task::Context::from_waker(core::task::Waker::noop());
+        loop {
+            if let core::task::Poll::Ready(result) =
+                future.as_mut().poll(&mut context)
+            {
+                return result;
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_load_async() -> Result<(), Error> {
+        use crate::ProcessorGeneration;
+        use crate::ondisk::{
+            AddressMode, PspDirectoryEntry, PspDirectoryEntryType,
+            PspDirectoryHeader,
+        };
+        use flash::{ErasableRange, NorSimulator};
+        let storage = NorSimulator::new(0x10_0000, 0x1000);
+        let mut efs =
+            Efs::create(&storage, ProcessorGeneration::Genoa, 0x2_0000, None)?;
+        let beginning = storage.erasable_location(0x4_0000)?;
+        let end = beginning.advance(0x1000)?;
+        let mut psp_directory = efs.create_psp_directory(
+            PspDirectoryHeader::FIRST_LEVEL_COOKIE,
+            beginning,
+            end,
+            None,
+            AddressMode::EfsRelativeOffset,
+            &[PspDirectoryEntry::new_value(
+                PspDirectoryEntryType::PspSoftFuseChain,
+                42,
+            )?],
+        )?;
+        let buf = psp_directory.save(
+            0x1000,
+            &ErasableRange::new(beginning, end),
+            end,
+        )?;
+        storage.erase_and_write_blocks(beginning, &buf)?;
+        efs.set_main_psp_directory(&psp_directory)?;
+
+        let async_storage = YieldingFlash(&storage);
+        let efs = block_on(Efs::load_async(
+            &async_storage,
+            Some(ProcessorGeneration::Genoa),
+            None,
+        ))?;
+        let psp_directory = block_on(efs.psp_directory_async())?;
+        assert_eq!(psp_directory.beginning(), 0x4_0000);
+        let entries = psp_directory.entries().collect::<Vec<_>>();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].value()?, 42);
+        assert!(block_on(efs.bhd_directory_async(None)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_free_list_allocator_from_efs() -> Result<(), Error> {
+        use crate::ProcessorGeneration;
+        use crate::allocators::{FlashAllocate, FreeListFlashAllocator};
+        use crate::ondisk::{
+            AddressMode, BhdDirectoryEntry, BhdDirectoryEntryType,
+            PspDirectoryEntry, PspDirectoryEntryType, ValueOrLocation,
+        };
+        use flash::{ErasableRange, Location, NorSimulator};
+        let storage = NorSimulator::new(0x10_0000, 0x1000);
+        create_genoa_efs_with_directories(
+            &storage,
+            AddressMode::EfsRelativeOffset,
+            &[PspDirectoryEntry::new_payload(
+                AddressMode::EfsRelativeOffset,
+                PspDirectoryEntryType::PspBootloader,
+                Some(0x1800),
+                Some(ValueOrLocation::EfsRelativeOffset(0x5_0000)),
+            )?],
+            AddressMode::EfsRelativeOffset,
+            &[BhdDirectoryEntry::new_payload(
+                AddressMode::EfsRelativeOffset,
+                BhdDirectoryEntryType::ApcbBackup,
+                Some(0x10),
+                Some(ValueOrLocation::EfsRelativeOffset(0x8_0000)),
+                None,
+            )?],
+        )?;
+
+        let efs = Efs::load(&storage, Some(ProcessorGeneration::Genoa), None)?;
+        let arena_beginning = storage.erasable_location(0)?;
+        let arena = ErasableRange::new(
+            arena_beginning,
+            arena_beginning.advance(0x10_0000)?,
+        );
+        let mut allocator = FreeListFlashAllocator::from_efs(&efs, arena)?;
+        let free_ranges = allocator
+            .free_ranges()
+            .iter()
+            .map(|range| {
+                (Location::from(range.beginning), Location::from(range.end))
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            free_ranges,
+            [
+                (0, 0x2_0000),
+                (0x2_1000, 0x4_0000),
+                (0x4_1000, 0x5_0000),
+                (0x5_2000, 0x6_0000),
+                (0x6_1000, 0x8_0000),
+                (0x8_1000, 0x10_0000),
+            ]
+        );
+        assert_eq!(allocator.max_contiguous_capacity(), 0x7_f000);
+        let range = allocator.take_at_least(0x2_0000).unwrap();
+        assert_eq!(Location::from(range.beginning), 0);
+        let range = allocator.take_at_least(0x1_0000).unwrap();
+        assert_eq!(Location::from(range.beginning), 0x2_1000);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_compact() -> Result<(), Error> {
+        use crate::ProcessorGeneration;
+        use crate::allocators::FreeListFlashAllocator;
+        use flash::{ErasableRange, FlashRead, Location, NorSimulator};
+        let storage = NorSimulator::new(0x10_0000, 0x1000);
+        let payload = create_compactable_efs(&storage)?;
+
+        let mut efs =
+            Efs::load(&storage, Some(ProcessorGeneration::Genoa), None)?;
+        let arena_beginning = storage.erasable_location(0x4_1000)?;
+        let arena = ErasableRange::new(
+            arena_beginning,
+            storage.erasable_location(0x10_0000)?,
+        );
+        let mut allocator = FreeListFlashAllocator::from_efs(&efs, arena)?;
+        efs.compact(&mut allocator)?;
+
+        let efs = Efs::load(&storage, Some(ProcessorGeneration::Genoa), None)?;
+        let psp_directory = efs.psp_directory()?;
+        let beginnings = psp_directory
+            .entries()
+            .map(|entry| psp_directory.payload_beginning(&entry))
+            .collect::<Result<Vec<_>, _>>()?;
+        // The overlapping payloads stay.
+        assert_eq!(beginnings, [0x4_1000, 0x8_0000, 0x8_0800]);
+        let mut buf = vec![0; 0x1800];
+        storage.read_exact(0x4_1000, &mut buf)?;
+        assert_eq!(buf, payload);
+        let bhd_directory = efs.bhd_directory(None)?;
+        for entry in bhd_directory.entries() {
+            assert_eq!(bhd_directory.payload_beginning(&entry)?, 0x9_1800);
+        }
+        let mut buf = [0; 0x10];
+        storage.read_exact(0x9_1800, &mut buf)?;
+        assert_eq!(buf, [0x42; 0x10]);
+        let free_ranges = allocator
+            .free_ranges()
+            .iter()
+            .map(|range| {
+                (Location::from(range.beginning), Location::from(range.end))
+            })
+            .collect::<Vec<_>>();
+        // The PSP directory was rewritten to 0x8_2000, and its old erase
+        // block is free now.  The erase block at 0x9_1000 still has the BHD
+        // payload in it.
+        assert_eq!(psp_directory.beginning(), 0x8_2000);
+        assert_eq!(
+            free_ranges,
+            [
+                (0x4_0000, 0x4_1000),
+                (0x4_3000, 0x6_0000),
+                (0x6_1000, 0x8_0000),
+                (0x8_3000, 0x9_1000),
+                (0x9_2000, 0x10_0000)
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_compact_skips_unlocatable() -> Result<(), Error> {
+        use crate::ProcessorGeneration;
+        use crate::allocators::FreeListFlashAllocator;
+        use crate::ondisk::{
+            AddressMode, DirectoryEntry, PspDirectoryEntry,
+            PspDirectoryEntryType, ValueOrLocation,
+        };
+        use flash::{ErasableRange, NorSimulator};
+        let storage = NorSimulator::new(0x10_0000, 0x1000);
+        storage.erase_and_write_blocks(
+            storage.erasable_location(0x7_0000)?,
+            &[0x42; 0x10],
+        )?;
+        create_genoa_efs_with_directories(
+            &storage,
+            AddressMode::DirectoryRelativeOffset,
+            &[
+                // No MMIO size is known, so this cannot be located.
+                PspDirectoryEntry::new_payload(
+                    AddressMode::DirectoryRelativeOffset,
+                    PspDirectoryEntryType::PspBootloader,
+                    Some(0x10),
+                    Some(ValueOrLocation::PhysicalAddress(0xff05_0000)),
+                )?,
+                PspDirectoryEntry::new_payload(
+                    AddressMode::DirectoryRelativeOffset,
+                    PspDirectoryEntryType::PspTrustlets,
+                    Some(0x10),
+                    Some(ValueOrLocation::EfsRelativeOffset(0x7_0000)),
+                )?,
+            ],
+            AddressMode::EfsRelativeOffset,
+            &[],
+        )?;
+
+        let mut efs =
+            Efs::load(&storage, Some(ProcessorGeneration::Genoa), None)?;
+        let arena_beginning = storage.erasable_location(0x4_1000)?;
+        let arena = ErasableRange::new(
+            arena_beginning,
+            storage.erasable_location(0x10_0000)?,
+        );
+        let mut allocator = FreeListFlashAllocator::from_efs(&efs, arena)?;
+        efs.compact(&mut allocator)?;
+
+        let efs = Efs::load(&storage, Some(ProcessorGeneration::Genoa), None)?;
+        let directory = efs.psp_directory()?;
+        assert_ne!(directory.beginning(), 0x4_0000);
+        let entries = directory.entries().collect::<Vec<_>>();
+        assert!(matches!(
+            entries[0].source(AddressMode::DirectoryRelativeOffset)?,
+            ValueOrLocation::PhysicalAddress(0xff05_0000)
+        ));
+        assert_eq!(directory.payload_beginning(&entries[1])?, 0x4_1000);
+        let mut buf = [0; 0x10];
+        directory.read_payload(&storage, &entries[1], &mut buf)?;
+        assert_eq!(buf, [0x42; 0x10]);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_compact_with_power_loss() -> Result<(), Error> {
+        use crate::ProcessorGeneration;
+        use crate::allocators::FreeListFlashAllocator;
+        use crate::ondisk::PspDirectoryEntryType;
+        use flash::{ErasableRange, NorSimulator};
+        fn compact(
+            flash: &FaultInjectingFlash<'_, NorSimulator>,
+        ) -> Result<(), Error> {
+            let mut efs =
+                Efs::load(flash, Some(ProcessorGeneration::Genoa), None)?;
+            let arena_beginning = flash.erasable_location(0x4_1000)?;
+            let arena = ErasableRange::new(
+                arena_beginning,
+                flash.erasable_location(0x10_0000)?,
+            );
+            let mut allocator = FreeListFlashAllocator::from_efs(&efs, arena)?;
+            efs.compact(&mut allocator)
+        }
+        /// Checks that all the payloads of create_compactable_efs are
+        /// there.
+        fn check(storage: &NorSimulator, payload: &[u8]) -> Result<(), Error> {
+            let efs =
+                Efs::load(storage, Some(ProcessorGeneration::Genoa), None)?;
+            let psp_directory = efs.psp_directory()?;
+            assert_eq!(psp_directory.entries().count(), 3);
+            for entry in psp_directory.entries() {
+                let mut buf = vec![0; 0x1800];
+                let size =
+                    psp_directory.read_payload(storage, &entry, &mut buf)?;
+                if entry.typ_or_err()? == PspDirectoryEntryType::PspTrustlets {
+                    assert_eq!(buf, payload);
+                } else {
+                    assert_eq!(buf[..size], [0x23; 0x1000]);
+                }
+            }
+            let bhd_directory = efs.bhd_directory(None)?;
+            assert_eq!(bhd_directory.entries().count(), 2);
+            for entry in bhd_directory.entries() {
+                let mut buf = [0; 0x10];
+                bhd_directory.read_payload(storage, &entry, &mut buf)?;
+                assert_eq!(buf, [0x42; 0x10]);
+            }
+            Ok(())
+        }
+        let storage = NorSimulator::new(0x10_0000, 0x1000);
+        let payload = create_compactable_efs(&storage)?;
+        let flash = FaultInjectingFlash::new(&storage);
+        compact(&flash)?;
+        check(&storage, &payload)?;
+        let operation_count = flash.operation_count();
+        for fault in [Fault::Fail, Fault::Erase, Fault::Truncate(0x10)] {
+            for index in 0..operation_count {
+                let storage = NorSimulator::new(0x10_0000, 0x1000);
+                let payload = create_compactable_efs(&storage)?;
+                let flash =
+                    FaultInjectingFlash::with_fault(&storage, index, fault);
+                assert!(compact(&flash).is_err());
+                // Only the EFH is rewritten in place--by the very last
+                // operation.
+                if fault == Fault::Fail || index + 1 < operation_count {
+                    check(&storage, &payload)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_insert_payload() -> Result<(), Error> {
+        use crate::ProcessorGeneration;
+        use crate::allocators::{FlashAllocate, FreeListFlashAllocator};
+        use crate::ondisk::{
+            AddressMode, BhdDirectoryEntry, BhdDirectoryEntryType,
+            DirectoryEntry, PspDirectoryEntry, PspDirectoryEntryType,
+            ValueOrLocation,
+        };
+        use flash::{ErasableRange, NorSimulator};
+        let storage = NorSimulator::new(0x10_0000, 0x1000);
+        create_genoa_efs_with_directories(
+            &storage,
+            AddressMode::DirectoryRelativeOffset,
+            &[],
+            AddressMode::EfsRelativeOffset,
+            &[],
+        )?;
+
+        let mut efs =
+            Efs::load(&storage, Some(ProcessorGeneration::Genoa), None)?;
+        let arena_beginning = storage.erasable_location(0x4_1000)?;
+        let arena = ErasableRange::new(
+            arena_beginning,
+            storage.erasable_location(0x10_0000)?,
+        );
+        let mut allocator = FreeListFlashAllocator::from_efs(&efs, arena)?;
+        let bootloader = (0..0x1234).map(|i| i as u8).collect::<Vec<u8>>();
+        let mut psp_directory = efs.psp_directory()?;
+        let entry = efs.insert_psp_payload(
+            &mut psp_directory,
+            &PspDirectoryEntry::new_payload(
+                AddressMode::DirectoryRelativeOffset,
+                PspDirectoryEntryType::PspBootloader,
+                None,
+                None,
+            )?,
+            &bootloader,
+            &mut allocator,
+        )?;
+        assert!(matches!(
+            entry.source(AddressMode::DirectoryRelativeOffset)?,
+            ValueOrLocation::DirectoryRelativeOffset(0x1000)
+        ));
+        let capacity = allocator.max_contiguous_capacity();
+        assert!(matches!(
+            efs.insert_psp_payload(
+                &mut psp_directory,
+                &entry,
+                &bootloader,
+                &mut allocator,
+            ),
+            Err(Error::Duplicate)
+        ));
+        assert_eq!(allocator.max_contiguous_capacity(), capacity);
+        let mut bhd_directory = efs.bhd_directory(None)?;
+        efs.insert_bhd_payload(
+            &mut bhd_directory,
+            &BhdDirectoryEntry::new_payload(
+                AddressMode::EfsRelativeOffset,
+                BhdDirectoryEntryType::Apcb,
+                None,
+                None,
+                None,
+            )?,
+            &[0x42; 0x20],
+            &mut allocator,
+        )?;
+
+        let efs = Efs::load(&storage, Some(ProcessorGeneration::Genoa), None)?;
+        let psp_directory = efs.psp_directory()?;
+        let entry = psp_directory.entries().next().unwrap();
+        assert_eq!(entry.size(), Some(0x1234));
+        assert_eq!(psp_directory.payload_beginning(&entry)?, 0x4_1000);
+        let mut buf = vec![0; 0x1234];
+        psp_directory.read_payload(&storage, &entry, &mut buf)?;
+        assert_eq!(buf, bootloader);
+        let bhd_directory = efs.bhd_directory(None)?;
+        let entry = bhd_directory.entries().next().unwrap();
+        // APCBs are 64 KiB aligned--and the erase block the PSP directory
+        // was in before it was rewritten elsewhere is free again.
+        assert_eq!(bhd_directory.payload_beginning(&entry)?, 0x4_0000);
+        let mut buf = [0; 0x20];
+        bhd_directory.read_payload(&storage, &entry, &mut buf)?;
+        assert_eq!(buf, [0x42; 0x20]);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_insert_payload_rollback() -> Result<(), Error> {
+        use crate::ProcessorGeneration;
+        use crate::allocators::{FlashAllocate, FreeListFlashAllocator};
+        use crate::ondisk::{
+            AddressMode, PspDirectoryEntry, PspDirectoryEntryType,
+        };
+        use flash::{ErasableRange, NorSimulator};
+        let storage = NorSimulator::new(0x10_0000, 0x1000);
+        create_genoa_efs_with_directories(
+            &storage,
+            AddressMode::EfsRelativeOffset,
+            &[],
+            AddressMode::EfsRelativeOffset,
+            &[],
+        )?;
+        let entry = PspDirectoryEntry::new_payload(
+            AddressMode::EfsRelativeOffset,
+            PspDirectoryEntryType::PspBootloader,
+            None,
+            None,
+        )?;
+        for index in 0.. {
+            // Nothing is changed by the failing operation--so STORAGE stays
+            // as it was.
+            let flash =
+                FaultInjectingFlash::with_fault(&storage, index, Fault::Fail);
+            let mut efs =
+                Efs::load(&flash, Some(ProcessorGeneration::Genoa), None)?;
+            let arena_beginning = flash.erasable_location(0x4_1000)?;
+            let arena = ErasableRange::new(
+                arena_beginning,
+                flash.erasable_location(0x10_0000)?,
+            );
+            let mut allocator = FreeListFlashAllocator::from_efs(&efs, arena)?;
+            let capacity = allocator.max_contiguous_capacity();
+            let mut directory = efs.psp_directory()?;
+            let result = efs.insert_psp_payload(
+                &mut directory,
+                &entry,
+                &[0x42; 0x2000],
+                &mut allocator,
+            );
+            if !flash.faulted() {
+                result?;
+                // All the operations before failed once--including the
+                // last one, which rewrites the EFH.
+                assert_eq!(index, flash.operation_count());
+                break;
+            }
+            assert!(result.is_err());
+            assert_eq!(directory.entries().count(), 0);
+            assert_eq!(allocator.max_contiguous_capacity(), capacity);
+        }
+        let efs = Efs::load(&storage, Some(ProcessorGeneration::Genoa), None)?;
+        assert_eq!(efs.psp_directory()?.entries().count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_insert_payload_with_power_loss() -> Result<(), Error> {
+        use crate::ProcessorGeneration;
+        use crate::allocators::FreeListFlashAllocator;
+        use crate::ondisk::{
+            AddressMode, PspDirectoryEntry, PspDirectoryEntryType,
+            ValueOrLocation,
+        };
+        use flash::{ErasableRange, NorSimulator};
+        fn create(storage: &NorSimulator) -> Result<(), Error> {
+            storage.erase_and_write_blocks(
+                storage.erasable_location(0x7_0000)?,
+                &[0x23; 0x10],
+            )?;
+            create_genoa_efs_with_directories(
+                storage,
+                AddressMode::EfsRelativeOffset,
+                &[PspDirectoryEntry::new_payload(
+                    AddressMode::EfsRelativeOffset,
+                    PspDirectoryEntryType::PspTrustlets,
+                    Some(0x10),
+                    Some(ValueOrLocation::EfsRelativeOffset(0x7_0000)),
+                )?],
+                AddressMode::EfsRelativeOffset,
+                &[],
+            )
+        }
+        fn insert(
+            flash: &FaultInjectingFlash<'_, NorSimulator>,
+        ) -> Result<(), Error> {
+            let mut efs =
+                Efs::load(flash, Some(ProcessorGeneration::Genoa), None)?;
+            let arena_beginning = flash.erasable_location(0x4_1000)?;
+            let arena = ErasableRange::new(
+                arena_beginning,
+                flash.erasable_location(0x10_0000)?,
+            );
+            let mut allocator = FreeListFlashAllocator::from_efs(&efs, arena)?;
+            let mut directory = efs.psp_directory()?;
+            efs.insert_psp_payload(
+                &mut directory,
+                &PspDirectoryEntry::new_payload(
+                    AddressMode::EfsRelativeOffset,
+                    PspDirectoryEntryType::PspBootloader,
+                    None,
+                    None,
+                )?,
+                &[0x42; 0x2000],
+                &mut allocator,
+            )?;
+            Ok(())
+        }
+        let storage = NorSimulator::new(0x10_0000, 0x1000);
+        create(&storage)?;
+        let flash = FaultInjectingFlash::new(&storage);
+        insert(&flash)?;
+        let operation_count = flash.operation_count();
+        for fault in [Fault::Erase, Fault::Truncate(0x10)] {
+            // Only the EFH is rewritten in place--by the very last
+            // operation.
+            for index in 0..operation_count - 1 {
+                let storage = NorSimulator::new(0x10_0000, 0x1000);
+                create(&storage)?;
+                let flash =
+                    FaultInjectingFlash::with_fault(&storage, index, fault);
+                assert!(insert(&flash).is_err());
+                let efs = Efs::load(
+                    &storage,
+                    Some(ProcessorGeneration::Genoa),
+                    None,
+                )?;
+                let directory = efs.psp_directory()?;
+                assert_eq!(directory.beginning(), 0x4_0000);
+                assert_eq!(directory.entries().count(), 1);
+                let entry = directory.entries().next().unwrap();
+                let mut buf = [0; 0x10];
+                directory.read_payload(&storage, &entry, &mut buf)?;
+                assert_eq!(buf, [0x23; 0x10]);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_compressed_payload() -> Result<(), Error> {
+        use crate::ProcessorGeneration;
+        use crate::allocators::FreeListFlashAllocator;
+        use crate::ondisk::{
+            AddressMode, BhdDirectoryEntry, BhdDirectoryEntryType,
+            COMPRESSED_PAYLOAD_HEADER_SIZE, DirectoryEntry,
+        };
+        use flash::{ErasableRange, NorSimulator};
+        let storage = NorSimulator::new(0x10_0000, 0x1000);
+        create_genoa_efs_with_directories(
+            &storage,
+            AddressMode::EfsRelativeOffset,
+            &[],
+            AddressMode::EfsRelativeOffset,
+            &[],
+        )?;
+
+        let mut efs =
+            Efs::load(&storage, Some(ProcessorGeneration::Genoa), None)?;
+        let arena_beginning = storage.erasable_location(0x4_1000)?;
+        let arena = ErasableRange::new(
+            arena_beginning,
+            storage.erasable_location(0x10_0000)?,
+        );
+        let mut allocator = FreeListFlashAllocator::from_efs(&efs, arena)?;
+        let data = (0..0x3000).map(|i| (i / 0x100) as u8).collect::<Vec<u8>>();
+        let mut entry = BhdDirectoryEntry::new_payload(
+            AddressMode::EfsRelativeOffset,
+            BhdDirectoryEntryType::Bios,
+            None,
+            None,
+            None,
+        )?;
+        entry.set_compressed(true);
+        let mut bhd_directory = efs.bhd_directory(None)?;
+        efs.insert_bhd_payload(
+            &mut bhd_directory,
+            &entry,
+            &data,
+            &mut allocator,
+        )?;
+
+        let efs = Efs::load(&storage, Some(ProcessorGeneration::Genoa), None)?;
+        let bhd_directory = efs.bhd_directory(None)?;
+        let entry = bhd_directory.entries().next().unwrap();
+        assert!(entry.compressed());
+        assert_eq!(entry.size(), Some(0x3000));
+        let beginning = bhd_directory.payload_beginning(&entry)?;
+        let extent = bhd_directory.payload_extent(&storage, &entry)?;
+        assert!(extent > COMPRESSED_PAYLOAD_HEADER_SIZE);
+        assert!(extent < 0x1000);
+        assert_eq!(
+            bhd_directory.payload_reader(&storage, &entry)?.size(),
+            extent
+        );
+        let mut used_ranges = Vec::new();
+        efs.visit_used_ranges(|beginning, size| {
+            used_ranges.push((beginning, size))
+        })?;
+        assert!(used_ranges.contains(&(beginning, extent)));
+        let mut buf = vec![0; 0x3000];
+        assert_eq!(
+            bhd_directory.read_payload(&storage, &entry, &mut buf)?,
+            0x3000
+        );
+        assert_eq!(buf, data);
+        let mut buf = [0; 0x10];
+        assert!(matches!(
+            bhd_directory.read_payload(&storage, &entry, &mut buf),
+            Err(Error::DirectoryPayloadRangeCheck)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_visit_used_ranges_spi_cs2() -> Result<(), Error> {
+        use crate::ProcessorGeneration;
+        use crate::ondisk::{
+            AddressMode, BhdDirectoryEntry, BhdDirectoryEntryType,
+            BhdDirectoryRomId, ValueOrLocation,
+        };
+        use flash::NorSimulator;
+        let storage = NorSimulator::new(0x10_0000, 0x1000);
+        let mut entry = BhdDirectoryEntry::new_payload(
+            AddressMode::EfsRelativeOffset,
+            BhdDirectoryEntryType::Bios,
+            Some(0x3000),
+            Some(ValueOrLocation::EfsRelativeOffset(0x8_0000)),
+            None,
+        )?;
+        entry.set_compressed(true);
+        entry.set_rom_id(BhdDirectoryRomId::SpiCs2);
+        create_genoa_efs_with_directories(
+            &storage,
+            AddressMode::EfsRelativeOffset,
+            &[],
+            AddressMode::EfsRelativeOffset,
+            &[entry],
+        )?;
+
+        let efs = Efs::load(&storage, Some(ProcessorGeneration::Genoa), None)?;
+        let mut used = Vec::new();
+        efs.visit_used_ranges(|beginning, size| used.push((beginning, size)))?;
+        // The payload is on the other chip.
+        assert!(!used.iter().any(|&(beginning, _)| beginning == 0x8_0000));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_visit_used_ranges_ab() -> Result<(), Error> {
+        use crate::ProcessorGeneration;
+        use crate::allocators::FreeListFlashAllocator;
+        use crate::ondisk::{
+            AddressMode, BhdDirectoryEntry, BhdDirectoryEntryType,
+            BhdDirectoryHeader, PspDirectoryEntry, PspDirectoryEntryType,
+            PspDirectoryHeader, ValueOrLocation,
+        };
+        use flash::{ErasableRange, NorSimulator};
+        let storage = NorSimulator::new(0x10_0000, 0x1000);
+        let psp_entry = |type_, size, beginning| {
+            PspDirectoryEntry::new_payload(
+                AddressMode::EfsRelativeOffset,
+                type_,
+                Some(size),
+                Some(ValueOrLocation::EfsRelativeOffset(beginning)),
+            )
+        };
+        create_genoa_efs_with_directories(
+            &storage,
+            AddressMode::EfsRelativeOffset,
+            &[
+                psp_entry(
+                    PspDirectoryEntryType::SecondLevelAPspDirectory,
+                    0x1000,
+                    0xA_0000,
+                )?,
+                psp_entry(
+                    PspDirectoryEntryType::SecondLevelBPspDirectory,
+                    0x1000,
+                    0xB_0000,
+                )?,
+            ],
+            AddressMode::EfsRelativeOffset,
+            &[],
+        )?;
+        let mut efs =
+            Efs::load(&storage, Some(ProcessorGeneration::Genoa), None)?;
+        // Each of the A/B directories has a payload and a second-level BHD
+        // directory (0x2_0000 further) with a payload.
+        for psp_beginning in [0xA_0000, 0xB_0000] {
+            let bhd_beginning = psp_beginning + 0x2_0000;
+            let beginning = storage.erasable_location(psp_beginning)?;
+            let range =
+                ErasableRange::new(beginning, beginning.advance(0x1000)?);
+            let mut directory = efs.create_psp_directory(
+                PspDirectoryHeader::SECOND_LEVEL_COOKIE,
+                range.beginning,
+                range.end,
+                Some(0x4_0000),
+                AddressMode::EfsRelativeOffset,
+                &[
+                    psp_entry(
+                        PspDirectoryEntryType::PspBootloader,
+                        0x100,
+                        psp_beginning + 0x8000,
+                    )?,
+                    psp_entry(
+                        PspDirectoryEntryType::SecondLevelBhdDirectory,
+                        0x1000,
+                        bhd_beginning,
+                    )?,
+                ],
+            )?;
+            directory.write_to(&storage, &range)?;
+            let beginning = storage.erasable_location(bhd_beginning)?;
+            let range =
+                ErasableRange::new(beginning, beginning.advance(0x1000)?);
+            let mut directory = efs.create_bhd_directory(
+                BhdDirectoryHeader::SECOND_LEVEL_COOKIE,
+                range.beginning,
+                range.end,
+                Some(psp_beginning),
+                AddressMode::EfsRelativeOffset,
+                &[BhdDirectoryEntry::new_payload(
+                    AddressMode::EfsRelativeOffset,
+                    BhdDirectoryEntryType::Bios,
+                    Some(0x100),
+                    Some(ValueOrLocation::EfsRelativeOffset(
+                        bhd_beginning + 0x8000,
+                    )),
+                    None,
+                )?],
+            )?;
+            directory.write_to(&storage, &range)?;
+        }
+
+        let mut used = Vec::new();
+        efs.visit_used_ranges(|beginning, size| used.push((beginning, size)))?;
+        let arena_beginning = storage.erasable_location(0x4_1000)?;
+        let arena = ErasableRange::new(
+            arena_beginning,
+            storage.erasable_location(0x10_0000)?,
+        );
+        let allocator = FreeListFlashAllocator::from_efs(&efs, arena)?;
+        for beginning in [0xA_0000, 0xB_0000, 0xC_0000, 0xD_0000] {
+            let payload_beginning = beginning + 0x8000;
+            assert!(used.contains(&(payload_beginning, 0x100)));
+            for range in allocator.free_ranges() {
+                assert!(!range.overlaps(beginning, 0x1000));
+                assert!(!range.overlaps(payload_beginning, 0x100));
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates a Genoa EFS with an (empty) main PSP directory.
+    #[cfg(feature = "std")]
+    fn create_genoa_efs<T: FlashWrite>(storage: &T) -> Result<(), Error> {
+        use crate::AddressMode;
+        use crate::ProcessorGeneration;
+        use crate::PspDirectoryHeader;
+        let mut efs =
+            Efs::create(storage, ProcessorGeneration::Genoa, 0x2_0000, None)?;
+        let beginning = storage.erasable_location(0x4_0000)?;
+        let end = beginning.advance(0x1000)?;
+        let psp_directory = efs.create_psp_directory(
+            PspDirectoryHeader::FIRST_LEVEL_COOKIE,
+            beginning,
+            end,
+            None,
+            AddressMode::EfsRelativeOffset,
+            &[],
+        )?;
+        efs.set_main_psp_directory(&psp_directory)
+    }
+
+    /// Creates a Genoa EFS with a main PSP directory (at 0x4_0000) in
+    /// PSP_ADDRESS_MODE with PSP_ENTRIES and a main BHD directory (at
+    /// 0x6_0000) in BHD_ADDRESS_MODE with BHD_ENTRIES.
+    #[cfg(feature = "std")]
+    fn create_genoa_efs_with_directories<T: FlashWrite>(
+        storage: &T,
+        psp_address_mode: crate::AddressMode,
+        psp_entries: &[crate::PspDirectoryEntry],
+        bhd_address_mode: crate::AddressMode,
+        bhd_entries: &[crate::BhdDirectoryEntry],
+    ) -> Result<(), Error> {
+        use crate::ProcessorGeneration;
+        use crate::{BhdDirectoryHeader, PspDirectoryHeader};
+        use flash::ErasableRange;
+        let mut efs =
+            Efs::create(storage, ProcessorGeneration::Genoa, 0x2_0000, None)?;
+        let beginning = storage.erasable_location(0x4_0000)?;
+        let range = ErasableRange::new(beginning, beginning.advance(0x1000)?);
+        let mut directory = efs.create_psp_directory(
+            PspDirectoryHeader::FIRST_LEVEL_COOKIE,
+            beginning,
+            range.end,
+            None,
+            psp_address_mode,
+            psp_entries,
+        )?;
+        directory.write_to(storage, &range)?;
+        efs.set_main_psp_directory(&directory)?;
+        let beginning = storage.erasable_location(0x6_0000)?;
+        let range = ErasableRange::new(beginning, beginning.advance(0x1000)?);
+        let mut directory = efs.create_bhd_directory(
+            BhdDirectoryHeader::FIRST_LEVEL_COOKIE,
+            beginning,
+            range.end,
+            None,
+            bhd_address_mode,
+            bhd_entries,
+        )?;
+        directory.write_to(storage, &range)?;
+        efs.set_main_bhd_directory(&directory)
+    }
+
+    /// Creates an EFS on STORAGE with a PSP payload (which it returns) at
+    /// 0x9_0000 that can be moved, two overlapping PSP payloads at 0x8_0000
+    /// and 0x8_0800, and a BHD payload right after the first PSP payload
+    /// (in the same erase block) that both BHD entries refer to.
+    #[cfg(feature = "std")]
+    fn create_compactable_efs<T: FlashWrite>(
+        storage: &T,
+    ) -> Result<Vec<u8>, Error> {
+        use crate::ondisk::{
+            AddressMode, BhdDirectoryEntry, BhdDirectoryEntryType,
+            PspDirectoryEntry, PspDirectoryEntryType, ValueOrLocation,
+        };
+        let payload = (0..0x1800).map(|i| i as u8).collect::<Vec<u8>>();
+        let mut buf = payload.clone();
+        buf.extend_from_slice(&[0x42; 0x10]);
+        storage.erase_and_write_blocks(
+            storage.erasable_location(0x9_0000)?,
+            &buf,
+        )?;
+        storage.erase_and_write_blocks(
+            storage.erasable_location(0x8_0000)?,
+            &[0x23; 0x1800],
+        )?;
+        let psp_entries = [
+            (PspDirectoryEntryType::PspTrustlets, 0x1800, 0x9_0000),
+            (PspDirectoryEntryType::PspBootloader, 0x1000, 0x8_0000),
+            (PspDirectoryEntryType::PspOs, 0x1000, 0x8_0800),
+        ]
+        .map(|(type_, size, beginning)| {
+            PspDirectoryEntry::new_payload(
+                AddressMode::EfsRelativeOffset,
+                type_,
+                Some(size),
+                Some(ValueOrLocation::EfsRelativeOffset(beginning)),
+            )
+            .unwrap()
+        });
+        let bhd_entries =
+            [BhdDirectoryEntryType::Apcb, BhdDirectoryEntryType::ApcbBackup]
+                .map(|type_| {
+                    BhdDirectoryEntry::new_payload(
+                        AddressMode::EfsRelativeOffset,
+                        type_,
+                        Some(0x10),
+                        Some(ValueOrLocation::EfsRelativeOffset(0x9_1800)),
+                        None,
+                    )
+                    .unwrap()
+                });
+        create_genoa_efs_with_directories(
+            storage,
+            AddressMode::EfsRelativeOffset,
+            &psp_entries,
+            AddressMode::EfsRelativeOffset,
+            &bhd_entries,
+        )?;
+        Ok(payload)
+    }
+
+    /// Replays OPERATION on a fresh image with a power loss at every
+    /// possible point and with every kind of FAULTS--and then, in order to
+    /// recover, once more (without power loss) on the resulting image.
+    /// Returns, for every power loss, the index of the failed operation,
+    /// the fault and whether the image still loaded before recovery.
+    #[cfg(feature = "std")]
+    fn replay_with_power_loss(
+        operation: impl Fn(&FaultInjectingFlash<ImageStorage>) -> Result<(), Error>,
+        faults: &[Fault],
+    ) -> Vec<(usize, Fault, bool)> {
+        fn load(storage: &ImageStorage) -> bool {
+            Efs::load(storage, Some(crate::ProcessorGeneration::Genoa), None)
+                .is_ok()
+        }
+        let mut result = Vec::new();
+        for &fault in faults {
+            for index in 0.. {
+                let storage = ImageStorage::new([0xff; 0x5_0000], 0x1000);
+                let flash =
+                    FaultInjectingFlash::with_fault(&storage, index, fault);
+                let operation_result = operation(&flash);
+                if !flash.faulted() {
+                    assert!(operation_result.is_ok());
+                    assert!(load(&storage));
+                    break;
+                }
+                assert!(operation_result.is_err());
+                result.push((index, fault, load(&storage)));
+                assert!(operation(&FaultInjectingFlash::new(&storage)).is_ok());
+                assert!(load(&storage));
+            }
+        }
+        result
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_create_with_power_loss() {
+        let faults = [Fault::Fail, Fault::Erase, Fault::Truncate(16)];
+        let results =
+            replay_with_power_loss(|flash| create_genoa_efs(flash), &faults);
+        // Every operation was interrupted with every kind of fault--and
+        // replay_with_power_loss made sure that each time, the image could be
+        // recovered by running create_genoa_efs again.
+        for fault in faults {
+            assert!(results.iter().any(|&(_, f, _)| f == fault));
+        }
+        // Once the EFH has been written, a power loss that leaves the flash
+        // as it was keeps the image loadable.
+        for &(index, fault, loads) in &results {
+            if fault == Fault::Fail && index > 0 {
+                assert!(loads, "{index}");
+            }
+        }
+    }
 }
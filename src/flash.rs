@@ -4,6 +4,23 @@
 
 use core::convert::TryInto;
 
+pub use crate::adapters::CachedFlash;
+pub use crate::adapters::ProtectedRegionsFlash;
+pub use crate::adapters::VerifyingFlash;
+pub use crate::adapters::WindowFlashAdapter;
+pub use crate::adapters::{Fault, FaultInjectingFlash};
+#[cfg(feature = "std")]
+pub use crate::adapters::{
+    RecordedOperation, RecordedOperationKind, RecordingFlash,
+    decode_operations, encode_operations, minimize_operations,
+    replay_operations,
+};
+#[cfg(feature = "std")]
+pub use crate::backends::FileFlash;
+pub use crate::backends::MemoryFlash;
+#[cfg(feature = "std")]
+pub use crate::backends::NorSimulator;
+
 /// This is any Location on the Flash chip
 pub type Location = u32;
 
@@ -29,6 +46,25 @@ pub enum IoError {
     Erase { start: Location, size: usize },
     #[cfg_attr(feature = "std", error("could not flush"))]
     Flush,
+    #[cfg_attr(
+        feature = "std",
+        error(
+            "could not program 0x{size:x} B starting at 0x{start:x} B without erasing first"
+        )
+    )]
+    NotErased { start: Location, size: usize },
+    #[cfg_attr(
+        feature = "std",
+        error(
+            "could not verify 0x{size:x} B starting at 0x{start:x} B after writing"
+        )
+    )]
+    Verify { start: Location, size: usize },
+    #[cfg_attr(
+        feature = "std",
+        error("malformed operation log at offset 0x{offset:x}")
+    )]
+    Malformed { offset: usize },
 }
 
 #[derive(Debug)]
@@ -45,6 +81,15 @@ pub enum Error {
     Alignment { erasable_block_size: usize, intra_block_offset: usize },
     #[cfg_attr(feature = "std", error("requested size is unavailable"))]
     Size,
+    #[cfg_attr(feature = "std", error("operation is not supported"))]
+    Unsupported,
+    #[cfg_attr(
+        feature = "std",
+        error(
+            "0x{size:x} B starting at 0x{start:x} B overlap a protected region"
+        )
+    )]
+    Protected { start: Location, size: usize },
 }
 
 pub type Result<Q> = core::result::Result<Q, Error>;
@@ -100,7 +145,7 @@ impl From<ErasableLocation> for Location {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub struct ErasableRange {
     pub beginning: ErasableLocation, // note: same erasable_block_size assumed
     pub end: ErasableLocation,       // note: same erasable_block_size assumed
@@ -126,6 +171,14 @@ impl ErasableRange {
     pub fn capacity(&self) -> usize {
         ErasableLocation::extent(self.beginning, self.end) as usize
     }
+    /// Returns whether any of the SIZE Byte starting at BEGINNING are in
+    /// this range.
+    pub fn overlaps(&self, beginning: Location, size: usize) -> bool {
+        let end = u64::from(beginning) + size as u64;
+        size > 0
+            && u64::from(beginning) < u64::from(Location::from(self.end))
+            && end > u64::from(Location::from(self.beginning))
+    }
 }
 
 pub trait FlashRead {
@@ -198,18 +251,161 @@ pub trait FlashWrite: FlashRead + FlashAlign {
         buffer: &[u8],
     ) -> Result<()>;
 
+    /// Sizes (in Byte) of erase operations that the flash supports natively
+    /// (for example 32 KiB and 64 KiB on SPI NOR), in addition to
+    /// erasable_block_size().
+    /// Note: Each of them is assumed to be a power-of-two multiple of
+    /// erasable_block_size().
+    /// Note: If this is not empty, program has to be supported.
+    fn erase_sizes(&self) -> &[usize] {
+        &[]
+    }
+    /// Erases SIZE Byte starting at LOCATION.
+    /// Note: SIZE is either erasable_block_size() or one of erase_sizes(),
+    /// and LOCATION is aligned to SIZE.
+    fn erase(&self, location: ErasableLocation, size: usize) -> Result<()> {
+        let erasable_block_size = self.erasable_block_size();
+        if !size.is_multiple_of(erasable_block_size) {
+            return Err(Error::Size);
+        }
+        let mut location = location;
+        for _ in 0..size / erasable_block_size {
+            self.erase_block(location)?;
+            location = location.advance(erasable_block_size)?;
+        }
+        Ok(())
+    }
+    /// Programs BUFFER to LOCATION (which does not need to be aligned),
+    /// without erasing first (for example in pages of 256 Byte on SPI NOR).
+    /// The flash there is assumed to be erased already.
+    fn program(&self, location: Location, buffer: &[u8]) -> Result<()> {
+        let _ = (location, buffer);
+        Err(Error::Unsupported)
+    }
+
+    /// Note: If BUF.len() is not a multiple of erasable_block_size(), the
+    /// remainder of the last erasable block is erased anyway.
+    /// If erase_sizes() is not empty, this uses the largest erase that
+    /// fits, and then programs the data.
     // FIXME: sanity check callers
     fn erase_and_write_blocks(
         &self,
         location: ErasableLocation,
         buf: &[u8],
+    ) -> Result<()> {
+        let mut location = location;
+        let erasable_block_size = self.erasable_block_size();
+        if self.erase_sizes().is_empty() {
+            for chunk in buf.chunks(erasable_block_size) {
+                self.erase_and_write_block(location, chunk)?;
+                if chunk.len() != erasable_block_size {
+                    // TODO: Only allow on last chunk
+                    break;
+                }
+                location = location.advance(erasable_block_size)?;
+            }
+            return Ok(());
+        }
+        let mut buf = buf;
+        while !buf.is_empty() {
+            let beginning = self.location(location)?;
+            let extent = buf.len().next_multiple_of(erasable_block_size);
+            let size = self
+                .erase_sizes()
+                .iter()
+                .copied()
+                .filter(|&size| {
+                    size <= extent && (beginning as usize).is_multiple_of(size)
+                })
+                .fold(erasable_block_size, usize::max);
+            self.erase(location, size)?;
+            let (chunk, rest) = buf.split_at(size.min(buf.len()));
+            self.program(beginning, chunk)?;
+            buf = rest;
+            if !buf.is_empty() {
+                location = location.advance(size)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes BUF to LOCATION (which does not need to be aligned), while
+    /// preserving the remainder of the erasable blocks that are touched.
+    /// Note: BLOCK_BUFFER.len() == erasable_block_size(); it's used as
+    /// scratch space for the read-modify-write.
+    fn write_preserving_with_buffer(
+        &self,
+        location: Location,
+        buf: &[u8],
+        block_buffer: &mut [u8],
+    ) -> Result<()> {
+        let erasable_block_size = self.erasable_block_size();
+        assert_eq!(block_buffer.len(), erasable_block_size);
+        let mut offset = 0usize;
+        while offset < buf.len() {
+            let location = location
+                .checked_add(offset.try_into().map_err(|_| Error::Size)?)
+                .ok_or(Error::Size)?;
+            let intra_block_offset =
+                (location & self.erasable_block_mask()) as usize;
+            let size = (erasable_block_size - intra_block_offset)
+                .min(buf.len() - offset);
+            let block_location = self
+                .erasable_location(location - intra_block_offset as Location)?;
+            if size != erasable_block_size {
+                self.read_erasable_block(block_location, block_buffer)?;
+            }
+            block_buffer[intra_block_offset..intra_block_offset + size]
+                .copy_from_slice(&buf[offset..offset + size]);
+            self.erase_and_write_block(block_location, block_buffer)?;
+            offset += size;
+        }
+        Ok(())
+    }
+
+    /// Writes BUF to LOCATION (which does not need to be aligned), while
+    /// preserving the remainder of the erasable blocks that are touched.
+    #[cfg(feature = "std")]
+    fn write_preserving(&self, location: Location, buf: &[u8]) -> Result<()> {
+        let mut block_buffer = vec![0xff; self.erasable_block_size()];
+        self.write_preserving_with_buffer(location, buf, &mut block_buffer)
+    }
+}
+
+/// Like FlashRead, but for flash that is accessed asynchronously.
+#[allow(async_fn_in_trait)]
+pub trait AsyncFlashRead {
+    /// Read exactly the right amount from the location BEGINNING to fill the
+    /// entire BUFFER that was passed.
+    async fn read_exact(
+        &self,
+        beginning: Location,
+        buffer: &mut [u8],
+    ) -> Result<()>;
+}
+
+/// Like FlashWrite, but for flash that is accessed asynchronously.
+#[allow(async_fn_in_trait)]
+pub trait AsyncFlashWrite: AsyncFlashRead + FlashAlign {
+    async fn erase_block(&self, location: ErasableLocation) -> Result<()>;
+    /// Note: If BUFFER.len() < erasable_block_size(), it has to erase the
+    /// remainder anyway.
+    async fn erase_and_write_block(
+        &self,
+        location: ErasableLocation,
+        buffer: &[u8],
+    ) -> Result<()>;
+
+    async fn erase_and_write_blocks(
+        &self,
+        location: ErasableLocation,
+        buf: &[u8],
     ) -> Result<()> {
         let mut location = location;
         let erasable_block_size = self.erasable_block_size();
         for chunk in buf.chunks(erasable_block_size) {
-            self.erase_and_write_block(location, chunk)?;
+            self.erase_and_write_block(location, chunk).await?;
             if chunk.len() != erasable_block_size {
-                // TODO: Only allow on last chunk
                 break;
             }
             location = location.advance(erasable_block_size)?;
@@ -218,94 +414,49 @@ pub trait FlashWrite: FlashRead + FlashAlign {
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use core::cell::RefCell;
-    const KIB: usize = 1024; // B
-    const ERASABLE_BLOCK_SIZE: usize = 128 * KIB;
+/// This makes a FlashRead available as an AsyncFlashRead.  Its futures
+/// are always ready right away, so they can be run by run_ready.
+/// That way, we only need to implement loading once.
+pub(crate) struct BlockingFlash<'a, T>(pub(crate) &'a T);
 
-    struct FlashImage<'a> {
-        buf: RefCell<&'a mut [u8]>,
-        erasable_block_size: usize,
+impl<T: FlashRead> AsyncFlashRead for BlockingFlash<'_, T> {
+    async fn read_exact(
+        &self,
+        beginning: Location,
+        buffer: &mut [u8],
+    ) -> Result<()> {
+        self.0.read_exact(beginning, buffer)
     }
+}
 
-    impl<'a> FlashImage<'a> {
-        pub fn new(buf: &'a mut [u8]) -> Self {
-            Self {
-                buf: RefCell::new(buf),
-                erasable_block_size: ERASABLE_BLOCK_SIZE,
-            }
-        }
-        pub fn new_block_size(
-            buf: &'a mut [u8],
-            erasable_block_size: usize,
-        ) -> Self {
-            Self { buf: RefCell::new(buf), erasable_block_size }
-        }
+impl<T: FlashAlign> FlashAlign for BlockingFlash<'_, T> {
+    fn erasable_block_size(&self) -> usize {
+        self.0.erasable_block_size()
     }
+}
 
-    impl FlashRead for FlashImage<'_> {
-        fn read_exact(
-            &self,
-            location: Location,
-            buffer: &mut [u8],
-        ) -> Result<()> {
-            let len = buffer.len();
-            let buf = self.buf.borrow();
-            let block = &buf[location as usize..];
-            let block = &block[0..len];
-            buffer[..].copy_from_slice(block);
-            Ok(())
-        }
+/// Runs FUTURE, which has to be ready right away (for example because it
+/// only accesses a BlockingFlash).
+pub(crate) fn run_ready<F: Future>(future: F) -> F::Output {
+    let mut context =
+        core::task::Context::from_waker(core::task::Waker::noop());
+    match core::pin::pin!(future).poll(&mut context) {
+        core::task::Poll::Ready(result) => result,
+        core::task::Poll::Pending => unreachable!("blocking flash access"),
     }
+}
 
-    impl FlashAlign for FlashImage<'_> {
-        fn erasable_block_size(&self) -> usize {
-            self.erasable_block_size
-        }
-    }
-    impl FlashWrite for FlashImage<'_> {
-        fn read_erasable_block(
-            &self,
-            location: ErasableLocation,
-            buffer: &mut [u8],
-        ) -> Result<()> {
-            let erasable_block_size = self.erasable_block_size();
-            let location: Location = location.into();
-            let buf = self.buf.borrow();
-            let block = &buf
-                [location as usize..(location as usize + erasable_block_size)];
-            assert_eq!(buffer.len(), erasable_block_size);
-            buffer[..].copy_from_slice(block);
-            Ok(())
-        }
-        fn erase_block(&self, location: ErasableLocation) -> Result<()> {
-            let location: Location = location.into();
-            let mut buf = self.buf.borrow_mut();
-            let block = &mut buf[location as usize
-                ..(location as usize + self.erasable_block_size())];
-            block.fill(0xff);
-            Ok(())
-        }
-        fn erase_and_write_block(
-            &self,
-            location: ErasableLocation,
-            buffer: &[u8],
-        ) -> Result<()> {
-            let location: Location = location.into();
-            let mut buf = self.buf.borrow_mut();
-            let block = &mut buf[location as usize
-                ..(location as usize + self.erasable_block_size())];
-            block.copy_from_slice(&buffer[..]);
-            Ok(())
-        }
-    }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    const KIB: usize = 1024; // B
+    const ERASABLE_BLOCK_SIZE: usize = 128 * KIB;
 
     #[test]
     fn flash_image_usage() -> Result<()> {
         let mut storage = [0xFFu8; 256 * KIB];
-        let flash_image = FlashImage::new(&mut storage[..]);
+        let flash_image =
+            MemoryFlash::new(&mut storage[..], ERASABLE_BLOCK_SIZE);
         let beginning_1 =
             flash_image.erasable_location(Location::from(0u32)).unwrap();
         let erasable_block_size = ERASABLE_BLOCK_SIZE;
@@ -325,11 +476,49 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn flash_image_write_preserving() -> Result<()> {
+        let mut storage = [0u8; 64];
+        for (i, x) in storage.iter_mut().enumerate() {
+            *x = i as u8;
+        }
+        let flash_image = MemoryFlash::new(&mut storage[..], 16);
+        let mut block_buffer = [0u8; 16];
+        flash_image.write_preserving_with_buffer(
+            10,
+            &[0xaa; 20],
+            &mut block_buffer,
+        )?;
+        let mut buf = [0u8; 64];
+        flash_image.read_exact(0, &mut buf)?;
+        for (i, x) in buf.iter().enumerate() {
+            if (10..30).contains(&i) {
+                assert_eq!(*x, 0xaa);
+            } else {
+                assert_eq!(*x, i as u8);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn flash_image_write_preserving_allocating() -> Result<()> {
+        let mut storage = [0x55u8; 64];
+        let flash_image = MemoryFlash::new(&mut storage[..], 16);
+        flash_image.write_preserving(33, &[0xaa; 2])?;
+        let mut buf = [0u8; 4];
+        flash_image.read_exact(32, &mut buf)?;
+        assert_eq!(buf, [0x55, 0xaa, 0xaa, 0x55]);
+        Ok(())
+    }
+
     #[test]
     #[should_panic]
     fn flash_image_misaligned_erasure() {
         let mut storage = [0xFFu8; 256 * KIB];
-        let flash_image = FlashImage::new(&mut storage[..]);
+        let flash_image =
+            MemoryFlash::new(&mut storage[..], ERASABLE_BLOCK_SIZE);
         flash_image.erasable_location(Location::from(1u32)).unwrap();
     }
 
@@ -337,7 +526,8 @@ mod tests {
     #[should_panic(expected = "Alignment")]
     fn flash_image_misaligned_advancement() {
         let mut storage = [0xFFu8; 256 * KIB];
-        let flash_image = FlashImage::new(&mut storage[..]);
+        let flash_image =
+            MemoryFlash::new(&mut storage[..], ERASABLE_BLOCK_SIZE);
         let beginning_1 =
             flash_image.erasable_location(Location::from(0u32)).unwrap();
         beginning_1.advance(1).unwrap();
@@ -346,7 +536,8 @@ mod tests {
     #[test]
     fn flash_image_aligned_advancement() {
         let mut storage = [0xFFu8; 256 * KIB];
-        let flash_image = FlashImage::new(&mut storage[..]);
+        let flash_image =
+            MemoryFlash::new(&mut storage[..], ERASABLE_BLOCK_SIZE);
         let beginning_1 =
             flash_image.erasable_location(Location::from(0u32)).unwrap();
         beginning_1.advance_at_least(1).unwrap();
@@ -356,10 +547,10 @@ mod tests {
     #[should_panic(expected = "Alignment")]
     fn flash_image_mistaken_storage() {
         let mut storage_0 = [0xFFu8; 256 * KIB];
-        let flash_image_0 = FlashImage::new(&mut storage_0[..]);
+        let flash_image_0 =
+            MemoryFlash::new(&mut storage_0[..], ERASABLE_BLOCK_SIZE);
         let mut storage_1 = [0xFFu8; 256 * KIB];
-        let flash_image_1 =
-            FlashImage::new_block_size(&mut storage_1[..], 1 * KIB);
+        let flash_image_1 = MemoryFlash::new(&mut storage_1[..], KIB);
         let beginning_0 =
             flash_image_0.erasable_location(Location::from(0u32)).unwrap();
         flash_image_1.location(beginning_0).unwrap();
@@ -369,8 +560,9 @@ mod tests {
     #[should_panic(expected = "assertion")]
     fn flash_image_mistaken_buffer() {
         let mut storage_0 = [0xFFu8; 256 * KIB];
-        let flash_image_0 = FlashImage::new(&mut storage_0[..]);
-        let mut buffer = [0xFFu8; 1 * KIB];
+        let flash_image_0 =
+            MemoryFlash::new(&mut storage_0[..], ERASABLE_BLOCK_SIZE);
+        let mut buffer = [0xFFu8; KIB];
         let beginning_0 =
             flash_image_0.erasable_location(Location::from(0u32)).unwrap();
         flash_image_0.read_erasable_block(beginning_0, &mut buffer).unwrap();
@@ -381,7 +573,8 @@ mod tests {
     fn flash_image_too_small() {
         use crate::allocators::FlashAllocate;
         let mut storage_0 = [0xFFu8; 256 * KIB];
-        let flash_image_0 = FlashImage::new(&mut storage_0[..]);
+        let flash_image_0 =
+            MemoryFlash::new(&mut storage_0[..], ERASABLE_BLOCK_SIZE);
         let beginning =
             flash_image_0.erasable_location(Location::from(0u32)).unwrap();
         let end = beginning.advance(256 * KIB).unwrap();
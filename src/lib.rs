@@ -3,6 +3,7 @@
 mod adapters;
 pub mod allocators;
 mod amdfletcher32;
+mod backends;
 mod efs;
 pub mod flash;
 mod ondisk;
@@ -10,10 +11,17 @@ mod serializers;
 mod struct_accessors;
 mod types;
 pub use crate::efs::BhdDirectory;
+pub use crate::efs::BhdDirectoryWithCapacity;
 pub use crate::efs::ComboDirectory;
+pub use crate::efs::ComboDirectoryWithCapacity;
+pub use crate::efs::DEFAULT_MAX_DIRECTORY_ENTRIES;
+pub use crate::efs::Directory;
 pub use crate::efs::Efs;
+pub use crate::efs::PayloadReader;
 pub use crate::efs::ProcessorGeneration;
 pub use crate::efs::PspDirectory;
+pub use crate::efs::PspDirectoryWithCapacity;
+pub use crate::efs::WRITE_TO_BLOCK_BUFFER_SIZE;
 pub use crate::efs::preferred_efh_location;
 pub use crate::ondisk::ValueOrLocation;
 pub use ondisk::*;
@@ -553,7 +553,7 @@ impl Efh {
 }
 
 #[derive(
-    Debug, PartialEq, Eq, FromPrimitive, Clone, Copy, BitfieldSpecifier,
+    Debug, Default, PartialEq, Eq, FromPrimitive, Clone, Copy, BitfieldSpecifier,
 )]
 #[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
 #[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
@@ -562,17 +562,12 @@ pub enum AddressMode {
     /// Right-justified in 4 GiB address space.
     /// Only really used in families older than Rome.
     PhysicalAddress = 0,
-    EfsRelativeOffset = 1,            // x
+    #[default]
+    EfsRelativeOffset = 1, // x
     DirectoryRelativeOffset = 2,      // (x - Base)
     OtherDirectoryRelativeOffset = 3, // (x - other.Base);
 }
 
-impl Default for AddressMode {
-    fn default() -> Self {
-        Self::EfsRelativeOffset
-    }
-}
-
 pub(crate) const WEAK_ADDRESS_MODE: AddressMode =
     AddressMode::DirectoryRelativeOffset;
 
@@ -632,14 +627,16 @@ pub(crate) fn mmio_decode(
 }
 
 impl ValueOrLocation {
+    /// Entries in directories with address mode DirectoryRelativeOffset
+    /// or OtherDirectoryRelativeOffset specify their own address mode.
     fn effective_address_mode(
         directory_address_mode: AddressMode,
         entry_address_mode: AddressMode,
     ) -> AddressMode {
-        if directory_address_mode == WEAK_ADDRESS_MODE {
-            entry_address_mode
-        } else {
-            directory_address_mode
+        match directory_address_mode {
+            AddressMode::DirectoryRelativeOffset
+            | AddressMode::OtherDirectoryRelativeOffset => entry_address_mode,
+            _ => directory_address_mode,
         }
     }
     fn is_entry_address_mode_effective(
@@ -753,23 +750,18 @@ impl ValueOrLocation {
 }
 
 #[derive(
-    Debug, PartialEq, Eq, FromPrimitive, Clone, Copy, BitfieldSpecifier,
+    Debug, Default, PartialEq, Eq, FromPrimitive, Clone, Copy, BitfieldSpecifier,
 )]
 #[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
 #[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 pub enum EspiIoMode {
+    #[default]
     Auto = 0,
     ForceSingle = 1,
     ForceDual = 2,
     ForceQuad = 3,
 }
 
-impl Default for EspiIoMode {
-    fn default() -> Self {
-        Self::Auto
-    }
-}
-
 make_bitfield_serde! {
     #[bitfield(bits = 8)]
     #[repr(u8)]
@@ -870,7 +862,7 @@ impl DirectoryAdditionalInfo {
     }
     /// Given a value, tries to convert it into UNIT without loss.  If that's not possible, returns None
     pub fn try_into_unit(value: usize) -> Option<u16> {
-        if value % Self::UNIT == 0 {
+        if value.is_multiple_of(Self::UNIT) {
             let value = value / Self::UNIT;
             Some(value.try_into().ok()?)
         } else {
@@ -1155,39 +1147,33 @@ make_bitfield_serde! {
 }
 
 #[repr(u8)]
-#[derive(Debug, PartialEq, FromPrimitive, Clone, Copy, BitfieldSpecifier)]
+#[derive(
+    Debug, Default, PartialEq, FromPrimitive, Clone, Copy, BitfieldSpecifier,
+)]
 #[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
 #[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 #[bits = 2]
 #[non_exhaustive]
 pub enum PspDirectoryRomId {
+    #[default]
     SpiCs1 = 0,
     SpiCs2 = 1,
 }
 
-impl Default for PspDirectoryRomId {
-    fn default() -> Self {
-        Self::SpiCs1
-    }
-}
-
 #[repr(u8)]
-#[derive(Debug, PartialEq, FromPrimitive, Clone, Copy, BitfieldSpecifier)]
+#[derive(
+    Debug, Default, PartialEq, FromPrimitive, Clone, Copy, BitfieldSpecifier,
+)]
 #[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
 #[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 #[bits = 2]
 #[non_exhaustive]
 pub enum BhdDirectoryRomId {
+    #[default]
     SpiCs1 = 0,
     SpiCs2 = 1,
 }
 
-impl Default for BhdDirectoryRomId {
-    fn default() -> Self {
-        Self::SpiCs1
-    }
-}
-
 make_bitfield_serde! {
     #[derive(Clone, Copy)]
     #[bitfield(bits = 32)]
@@ -1248,6 +1234,31 @@ pub trait DirectoryEntry {
         value: ValueOrLocation,
     ) -> Result<()>;
     fn set_size(&mut self, value: Option<u32>);
+    /// Whether the payload is stored compressed--in which case size is the
+    /// uncompressed size.  See COMPRESSED_PAYLOAD_HEADER_SIZE.
+    fn payload_compressed(&self) -> bool {
+        false
+    }
+}
+
+/// Compressed payloads are zlib streams, with a header of this size (in
+/// Byte) in front of them.  The header is all 0, except for the size of
+/// the zlib stream at COMPRESSED_PAYLOAD_SIZE_OFFSET.
+pub const COMPRESSED_PAYLOAD_HEADER_SIZE: usize = 0x100;
+/// Offset (in Byte) in the compressed payload header of the size (LE u32,
+/// in Byte) of the zlib stream following the header.
+pub const COMPRESSED_PAYLOAD_SIZE_OFFSET: usize = 0x14;
+/// What identifies an entry within its directory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DirectoryEntryKey<Type, RomId> {
+    pub type_: Type,
+    pub sub_program: u8,
+    pub instance: u8,
+    pub rom_id: RomId,
+}
+pub trait KeyedDirectoryEntry {
+    type Key: Copy + PartialEq + core::fmt::Debug;
+    fn key(&self) -> Result<Self::Key>;
 }
 pub trait DirectoryEntrySerde: Sized {
     fn from_slice(source: &[u8]) -> Option<Self>;
@@ -1350,6 +1361,18 @@ impl PspDirectoryEntry {
     }
 }
 
+impl KeyedDirectoryEntry for PspDirectoryEntry {
+    type Key = DirectoryEntryKey<PspDirectoryEntryType, PspDirectoryRomId>;
+    fn key(&self) -> Result<Self::Key> {
+        Ok(DirectoryEntryKey {
+            type_: self.typ_or_err()?,
+            sub_program: self.sub_program(),
+            instance: self.instance(),
+            rom_id: self.rom_id_or_err()?,
+        })
+    }
+}
+
 impl DirectoryEntry for PspDirectoryEntry {
     fn source(
         &self,
@@ -1519,23 +1542,18 @@ pub enum BhdDirectoryEntryType {
     SecondLevelDirectory = 0x70, // also a BhdDirectory
 }
 
-#[derive(Copy, Clone, Debug, FromPrimitive, BitfieldSpecifier)]
+#[derive(Copy, Clone, Debug, Default, FromPrimitive, BitfieldSpecifier)]
 #[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
 #[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 #[bits = 8]
 #[non_exhaustive]
 pub enum BhdDirectoryEntryRegionType {
+    #[default]
     Normal = 0, // for X86: always
     Ta1 = 1,
     Ta2 = 2,
 }
 
-impl Default for BhdDirectoryEntryRegionType {
-    fn default() -> Self {
-        Self::Normal
-    }
-}
-
 make_bitfield_serde! {
     #[bitfield(bits = 32)]
     #[derive(Clone, Copy, Default)]
@@ -1674,6 +1692,18 @@ impl Default for BhdDirectoryEntry {
     }
 }
 
+impl KeyedDirectoryEntry for BhdDirectoryEntry {
+    type Key = DirectoryEntryKey<BhdDirectoryEntryType, BhdDirectoryRomId>;
+    fn key(&self) -> Result<Self::Key> {
+        Ok(DirectoryEntryKey {
+            type_: self.typ_or_err()?,
+            sub_program: self.sub_program(),
+            instance: self.instance(),
+            rom_id: self.rom_id_or_err()?,
+        })
+    }
+}
+
 impl DirectoryEntry for BhdDirectoryEntry {
     fn source(
         &self,
@@ -1725,6 +1755,9 @@ impl DirectoryEntry for BhdDirectoryEntry {
             }
         })
     }
+    fn payload_compressed(&self) -> bool {
+        self.compressed()
+    }
 }
 
 impl core::fmt::Debug for BhdDirectoryEntry {
@@ -1990,4 +2023,32 @@ mod tests {
             .with_spi_block_size_checked(0)
             .unwrap();
     }
+
+    #[test]
+    fn test_other_directory_relative_offset() {
+        let mode = AddressMode::OtherDirectoryRelativeOffset;
+        let raw = ValueOrLocation::OtherDirectoryRelativeOffset(0x1000)
+            .try_into_raw_location(mode)
+            .unwrap();
+        assert_eq!(raw, 0xC000_0000_0000_1000);
+        assert!(matches!(
+            ValueOrLocation::new_from_raw_location(mode, raw).unwrap(),
+            ValueOrLocation::OtherDirectoryRelativeOffset(0x1000)
+        ));
+        // Entries can override the address mode of the directory.
+        let raw = ValueOrLocation::EfsRelativeOffset(0x2000)
+            .try_into_raw_location(mode)
+            .unwrap();
+        assert_eq!(raw, 0x4000_0000_0000_2000);
+        assert!(matches!(
+            ValueOrLocation::new_from_raw_location(mode, raw).unwrap(),
+            ValueOrLocation::EfsRelativeOffset(0x2000)
+        ));
+        // ... but not the one of an EfsRelativeOffset directory.
+        assert!(
+            ValueOrLocation::OtherDirectoryRelativeOffset(0x1000)
+                .try_into_raw_location(AddressMode::EfsRelativeOffset)
+                .is_err()
+        );
+    }
 }
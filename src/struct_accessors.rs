@@ -70,6 +70,7 @@ impl<T: FromPrimitive> Getter<Result<T>> for u32 {
     Debug, PartialEq, FromBytes, IntoBytes, Immutable, KnownLayout, Clone, Copy,
 )]
 #[repr(C, packed)]
+#[allow(dead_code)]
 pub(crate) struct BU8(pub(crate) u8);
 impl Getter<Result<bool>> for BU8 {
     fn get1(self) -> Result<bool> {
@@ -94,6 +95,7 @@ impl From<bool> for BU8 {
     Debug, PartialEq, FromBytes, IntoBytes, Immutable, KnownLayout, Clone, Copy,
 )]
 #[repr(C, packed)]
+#[allow(dead_code)]
 pub(crate) struct BLU16(pub(crate) U16<LittleEndian>);
 impl Getter<Result<bool>> for BLU16 {
     fn get1(self) -> Result<bool> {
@@ -305,6 +307,7 @@ macro_rules! make_accessors {(
         // backward-compatible, that wouldn't be such a great idea.
         #[cfg_attr(feature = "serde", serde(rename = "" $StructName))]
         #[cfg_attr(feature = "serde", serde(deny_unknown_fields))]
+        #[allow(dead_code)]
         pub(crate) struct [<Serde $StructName>] {
             $(
                 $(pub $field_name: $field_ty,)?
@@ -394,6 +397,7 @@ macro_rules! make_bitfield_serde {(
         #[cfg_attr(feature = "serde", serde(deny_unknown_fields))]
         #[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
         #[cfg_attr(feature = "serde", serde(rename = "" $StructName))]
+        #[allow(dead_code)]
         pub(crate) struct [<Serde $StructName>] {
             $(
                 $(pub $field_name : <$field_ty as Specifier>::InOut,)?
@@ -37,6 +37,17 @@ pub enum Error {
     DirectoryTypeMismatch,
     #[cfg_attr(feature = "std", error("spi mode mismatch"))]
     SpiModeMismatch,
+    #[cfg_attr(feature = "std", error("flash chip not found"))]
+    FlashChipNotFound,
+    #[cfg_attr(
+        feature = "std",
+        error(
+            "checksum mismatch (expected 0x{expected:08x}, actual 0x{actual:08x})"
+        )
+    )]
+    ChecksumMismatch { expected: u32, actual: u32 },
+    #[cfg_attr(feature = "std", error("compression"))]
+    Compression,
 }
 
 pub type Result<Q> = core::result::Result<Q, Error>;
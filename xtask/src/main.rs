@@ -5,7 +5,6 @@
 //!
 //! Build driver for pico host boot loader.
 //!
-use clap;
 use duct::cmd;
 use std::env;
 use std::path::Path;
@@ -134,8 +133,8 @@ fn parse_args() -> clap::ArgMatches {
 /// Runs a cross-compiled build.
 fn build(args: BuildArgs) {
     let build_type = args.profile.build_type().unwrap_or("");
-    let locked = args.locked.then_some("--locked").unwrap_or("");
-    let verbose = args.verbose.then_some("--verbose").unwrap_or("");
+    let locked = if args.locked { "--locked" } else { "" };
+    let verbose = if args.verbose { "--verbose" } else { "" };
     let args = format!("build {locked} {verbose} {build_type}");
     cmd(cargo(), args.split_whitespace()).run().expect("build successful");
 }
@@ -143,8 +142,8 @@ fn build(args: BuildArgs) {
 /// Runs unit tests.
 fn test(args: BuildArgs) {
     let build_type = args.profile.build_type().unwrap_or("");
-    let locked = args.locked.then_some("--locked").unwrap_or("");
-    let verbose = args.verbose.then_some("--verbose").unwrap_or("");
+    let locked = if args.locked { "--locked" } else { "" };
+    let verbose = if args.verbose { "--verbose" } else { "" };
     let args = format!("test {locked} {verbose} {build_type}");
     cmd(cargo(), args.split_whitespace()).run().expect("test successful");
 }
@@ -152,8 +151,8 @@ fn test(args: BuildArgs) {
 /// Runs system tests.
 fn tests(args: BuildArgs) {
     let build_type = args.profile.build_type().unwrap_or("");
-    let locked = args.locked.then_some("--locked").unwrap_or("");
-    let verbose = args.verbose.then_some("--verbose").unwrap_or("");
+    let locked = if args.locked { "--locked" } else { "" };
+    let verbose = if args.verbose { "--verbose" } else { "" };
     let args = format!("test {locked} {build_type} {verbose}");
     cmd(cargo(), args.split_whitespace()).run().expect("test successful");
     let args = format!("test {locked} {build_type} {verbose} --features serde");
@@ -171,7 +170,7 @@ fn expand() {
 
 /// Runs the Clippy linter.
 fn clippy(with_locked: bool) {
-    let locked = with_locked.then_some("--locked").unwrap_or("");
+    let locked = if with_locked { "--locked" } else { "" };
     let args = format!("clippy {locked}");
     cmd(cargo(), args.split_whitespace()).run().expect("clippy successful");
 }